@@ -0,0 +1,79 @@
+// Stack backtrace by walking the frame pointer chain.
+//
+// With frame pointers, every frame starts with `push rbp; mov rbp, rsp`,
+// so rbp points at `[saved rbp][return address]` of the current frame.
+
+use core::fmt;
+
+use crate::serial::SerialWriter;
+use crate::x86_64;
+
+/// Print the return addresses of up to `max_frames` frames over serial.
+pub fn print(max_frames: usize) {
+    let _ = write(&mut SerialWriter, max_frames);
+}
+
+/// Write the return addresses of up to `max_frames` frames, starting from
+/// the caller. Returns the number of frames written.
+#[inline(never)]
+pub fn write(w: &mut impl fmt::Write, max_frames: usize) -> Result<usize, fmt::Error> {
+    writeln!(w, "Backtrace:")?;
+    let mut rbp = x86_64::read_rbp();
+    let mut frames = 0;
+    while frames < max_frames && rbp != 0 && rbp % 8 == 0 {
+        // Safety:
+        // rbp points at a frame record as long as the code is built
+        // with frame pointers.
+        let (saved_rbp, return_addr) = unsafe {
+            let record = rbp as *const u64;
+            (record.read(), record.add(1).read())
+        };
+        if return_addr == 0 {
+            break;
+        }
+        writeln!(w, "  #{} {:#x}", frames, return_addr)?;
+        frames += 1;
+        // The stack grows down, so callers' frames are always above.
+        // Anything else means the chain is broken.
+        if saved_rbp <= rbp {
+            break;
+        }
+        rbp = saved_rbp;
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::StrBuf;
+
+    #[inline(never)]
+    fn outer(out: &mut StrBuf<1024>) -> usize {
+        // Keep the call out of tail position, so the frame stays.
+        core::hint::black_box(middle(out))
+    }
+
+    #[inline(never)]
+    fn middle(out: &mut StrBuf<1024>) -> usize {
+        core::hint::black_box(inner(out))
+    }
+
+    #[inline(never)]
+    fn inner(out: &mut StrBuf<1024>) -> usize {
+        write(out, 8).unwrap()
+    }
+
+    #[test_case]
+    fn test_backtrace() {
+        let mut out = StrBuf::<1024>::new();
+        let frames = outer(&mut out);
+        assert!(frames >= 3);
+        let addrs = out.as_str().lines().filter(|l| l.starts_with("  #")).count();
+        assert_eq!(addrs, frames);
+        assert!(out.as_str().contains("#2 0x"));
+
+        let mut out = StrBuf::<1024>::new();
+        assert_eq!(write(&mut out, 1).unwrap(), 1);
+    }
+}
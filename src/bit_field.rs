@@ -1,5 +1,7 @@
 // A poor man's bit_field.
 
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use core::ops::Bound;
 use core::ops::RangeBounds;
 use core::ops::{Range, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive};
@@ -82,8 +84,54 @@ fn from_range<R: RangeBounds<u8>, T: BitWidth>(range: R) -> (u8, u8) {
 pub trait BitField: Sized {
     fn get_bits<R: IntoSpan>(&self, range: R) -> Self;
     fn set_bits<R: IntoSpan>(&mut self, range: R, bits: Self);
+    fn set_bits_checked<R: IntoSpan>(&mut self, range: R, bits: Self) -> Result<(), BitsOutOfRange>;
+    fn count_ones_in<R: IntoSpan>(&self, range: R) -> u32;
+    fn set_bit_indices(&self) -> BitIter;
+
+    /// Get bit pattern in range and convert it into E, e.g. an enum of
+    /// the values a field may hold.
+    /// # Panics
+    /// Panics if the range isn't valid
+    fn get_enum<E: TryFrom<Self>, R: IntoSpan>(&self, range: R) -> Result<E, E::Error> {
+        E::try_from(self.get_bits(range))
+    }
+}
+
+/// Error returned by `set_bits_checked` when the given bits don't fit in the range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitsOutOfRange;
+
+impl fmt::Display for BitsOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bits fall outside of range")
+    }
+}
+
+/// Iterator over the indices of set bits, from low to high.
+#[derive(Debug, Clone, Copy)]
+pub struct BitIter(u64);
+
+impl Iterator for BitIter {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0 == 0 {
+            return None;
+        }
+        let index = self.0.trailing_zeros() as u8;
+        // Clear the lowest set bit.
+        self.0 &= self.0 - 1;
+        Some(index)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.0.count_ones() as usize;
+        (len, Some(len))
+    }
 }
 
+impl ExactSizeIterator for BitIter {}
+
 macro_rules! impl_bit_field {
     ($ty:ty) => {
         impl BitField for $ty {
@@ -103,14 +151,39 @@ macro_rules! impl_bit_field {
             /// # Panics
             /// Panics if the range isn't valid or given bits excess the range.
             fn set_bits<R: IntoSpan>(&mut self, range: R, bits: $ty) {
+                if let Err(e) = self.set_bits_checked(range, bits) {
+                    panic!("{}", e);
+                }
+            }
+
+            /// Set self's bit pattern in range to bits, leaving self untouched
+            /// if given bits excess the range.
+            /// # Panics
+            /// Panics if the range isn't valid.
+            fn set_bits_checked<R: IntoSpan>(&mut self, range: R, bits: $ty) -> Result<(), BitsOutOfRange> {
                 let (start, end) = range.into_span::<$ty>();
                 // Get a full mask for the range span.
                 let mask: $ty = 1u64.checked_shl((end - start + 1) as u32)
                         .map(|r| r - 1)
                         .unwrap_or(u64::MAX) as $ty;
-                assert!(bits & !mask == 0, "bits fall outside of range");
+                if bits & !mask != 0 {
+                    return Err(BitsOutOfRange);
+                }
                 // Clear that range and put bits in.
                 *self = (*self & !(mask << start)) | bits << start;
+                Ok(())
+            }
+
+            /// Count the set bits in range.
+            /// # Panics
+            /// Panics if the range isn't valid
+            fn count_ones_in<R: IntoSpan>(&self, range: R) -> u32 {
+                self.get_bits(range).count_ones()
+            }
+
+            /// Iterate over the indices of set bits, from low to high.
+            fn set_bit_indices(&self) -> BitIter {
+                BitIter(*self as u64)
             }
         }
     };
@@ -123,10 +196,231 @@ impl_bit_field! {
     u8, u16, u32, u64,
 }
 
+/// `BitField` for atomics, so that a field can be updated without racing
+/// with updates of the others.
+pub trait AtomicBitField {
+    type Value: BitField;
+
+    /// Load the bit pattern in range.
+    /// # Panics
+    /// Panics if the range isn't valid.
+    fn load_bits<R: IntoSpan>(&self, range: R, ordering: Ordering) -> Self::Value;
+
+    /// Set the bit pattern in range to bits atomically, returning the
+    /// previous value. `ordering` applies to the successful update.
+    /// # Panics
+    /// Panics if the range isn't valid or given bits excess the range.
+    fn fetch_set_bits<R: IntoSpan>(&self, range: R, bits: Self::Value, ordering: Ordering)
+        -> Self::Value;
+}
+
+macro_rules! impl_atomic_bit_field {
+    ($($atomic:ty => $ty:ty,)*) => {
+        $(
+            impl AtomicBitField for $atomic {
+                type Value = $ty;
+
+                fn load_bits<R: IntoSpan>(&self, range: R, ordering: Ordering) -> $ty {
+                    self.load(ordering).get_bits(range)
+                }
+
+                fn fetch_set_bits<R: IntoSpan>(&self, range: R, bits: $ty, ordering: Ordering) -> $ty {
+                    let (start, end) = range.into_span::<$ty>();
+                    let mut current = self.load(Ordering::Relaxed);
+                    loop {
+                        let mut new = current;
+                        new.set_bits(start..=end, bits);
+                        match self.compare_exchange_weak(current, new, ordering, Ordering::Relaxed) {
+                            Ok(previous) => return previous,
+                            // Someone else got in between, retry on top of theirs.
+                            Err(actual) => current = actual,
+                        }
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_atomic_bit_field! {
+    AtomicU32 => u32,
+    AtomicU64 => u64,
+}
+
+macro_rules! impl_read_bytes {
+    ($($ty:ty => $le:ident, $be:ident;)*) => {
+        $(
+            #[doc = concat!("Read a little-endian `", stringify!($ty), "` at byte `offset`, aligned or not.")]
+            /// # Panics
+            /// Panics if bytes are too short.
+            pub fn $le(bytes: &[u8], offset: usize) -> $ty {
+                <$ty>::from_le_bytes(read_array(bytes, offset))
+            }
+
+            #[doc = concat!("Read a big-endian `", stringify!($ty), "` at byte `offset`, aligned or not.")]
+            /// # Panics
+            /// Panics if bytes are too short.
+            pub fn $be(bytes: &[u8], offset: usize) -> $ty {
+                <$ty>::from_be_bytes(read_array(bytes, offset))
+            }
+        )*
+    };
+}
+
+impl_read_bytes! {
+    u16 => read_u16_le, read_u16_be;
+    u32 => read_u32_le, read_u32_be;
+    u64 => read_u64_le, read_u64_be;
+}
+
+fn read_array<const N: usize>(bytes: &[u8], offset: usize) -> [u8; N] {
+    let end = offset.checked_add(N).filter(|&end| end <= bytes.len());
+    let end = end.expect("read past the end of bytes");
+    let mut array = [0; N];
+    array.copy_from_slice(&bytes[offset..end]);
+    array
+}
+
+/// Mask of the low `len` bits.
+const fn const_mask(len: u8) -> u64 {
+    if len == 64 {
+        u64::MAX
+    } else {
+        (1 << len) - 1
+    }
+}
+
+/// `BitField::get_bits` on u64 for consts, where trait methods can't be
+/// called yet.
+/// # Panics
+/// Panics if the range isn't valid, which fails the build in a const.
+pub const fn const_get_bits(value: u64, range: Range<u8>) -> u64 {
+    assert!(range.start < range.end && range.end <= 64, "invalid bit range");
+    (value >> range.start) & const_mask(range.end - range.start)
+}
+
+/// `BitField::set_bits` on u64 for consts, e.g.
+/// `const_set_bits(0, 40..44, 0b1001)`. Returns the new value.
+/// # Panics
+/// Panics if the range isn't valid or given bits excess the range, which
+/// fails the build in a const.
+pub const fn const_set_bits(value: u64, range: Range<u8>, bits: u64) -> u64 {
+    assert!(range.start < range.end && range.end <= 64, "invalid bit range");
+    let mask = const_mask(range.end - range.start);
+    assert!(bits & !mask == 0, "bits fall outside of range");
+    value & !(mask << range.start) | bits << range.start
+}
+
+/// Define a set of named bit flags over an integer, like the `bitflags`
+/// crate but much smaller. The struct gets the flags as associated
+/// consts, `|`, `contains` and a `Debug` listing the set flags.
+#[macro_export]
+macro_rules! bit_flags {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident: $ty:ty {
+            $(
+                $(#[$flag_attr:meta])*
+                const $flag:ident = $value:expr;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        #[repr(transparent)]
+        $vis struct $name($ty);
+
+        #[allow(dead_code)]
+        impl $name {
+            $(
+                $(#[$flag_attr])*
+                pub const $flag: Self = Self($value);
+            )*
+
+            pub const fn empty() -> Self {
+                Self(0)
+            }
+
+            pub const fn bits(self) -> $ty {
+                self.0
+            }
+
+            /// Unnamed bits are kept as is.
+            pub const fn from_bits_retain(bits: $ty) -> Self {
+                Self(bits)
+            }
+
+            /// Whether all bits of other are set in self.
+            pub const fn contains(self, other: Self) -> bool {
+                self.0 & other.0 == other.0
+            }
+
+            /// The same as `|`, but usable in consts.
+            pub const fn union(self, other: Self) -> Self {
+                Self(self.0 | other.0)
+            }
+        }
+
+        impl ::core::ops::BitOr for $name {
+            type Output = Self;
+
+            fn bitor(self, rhs: Self) -> Self {
+                self.union(rhs)
+            }
+        }
+
+        impl ::core::ops::BitOrAssign for $name {
+            fn bitor_assign(&mut self, rhs: Self) {
+                *self = self.union(rhs);
+            }
+        }
+
+        impl ::core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                f.write_str(::core::stringify!($name))?;
+                f.write_str("(")?;
+                let mut rest = self.0;
+                let mut first = true;
+                $(
+                    if Self::$flag.0 != 0 && self.contains(Self::$flag) {
+                        if !first {
+                            f.write_str(" | ")?;
+                        }
+                        f.write_str(::core::stringify!($flag))?;
+                        rest &= !Self::$flag.0;
+                        first = false;
+                    }
+                )*
+                if rest != 0 {
+                    if !first {
+                        f.write_str(" | ")?;
+                    }
+                    ::core::write!(f, "{:#x}", rest)?;
+                }
+                f.write_str(")")
+            }
+        }
+    };
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test_case]
+    fn test_const_bits() {
+        const TSS_TYPE: u64 = const_set_bits(0, 40..44, 0b1001);
+        assert_eq!(TSS_TYPE, 0x0000_0900_0000_0000);
+        assert_eq!(const_get_bits(TSS_TYPE, 40..44), 0b1001);
+        // The same as the trait methods.
+        let mut value = u64::MAX;
+        value.set_bits(8..16, 0x5a);
+        assert_eq!(const_set_bits(u64::MAX, 8..16, 0x5a), value);
+        assert_eq!(const_get_bits(value, 4..12), value.get_bits(4..12));
+        assert_eq!(const_set_bits(0, 0..64, u64::MAX), u64::MAX);
+        assert_eq!(const_get_bits(u64::MAX, 0..64), u64::MAX);
+    }
+
     #[test_case]
     fn test_bit_field_basic() {
         let mut bits: u16 = 0;
@@ -148,4 +442,101 @@ mod tests {
         assert_eq!(bits.get_bits(1), 0);
         assert_eq!(bits.get_bits(2), 1);
     }
+
+    #[test_case]
+    fn test_bit_field_set_bits_checked() {
+        let mut bits: u8 = 0b1000_0001;
+        assert_eq!(bits.set_bits_checked(2..=4, 0b1000), Err(BitsOutOfRange));
+        assert_eq!(bits, 0b1000_0001);
+        assert_eq!(bits.set_bits_checked(3, 2), Err(BitsOutOfRange));
+        assert_eq!(bits.set_bits_checked(2..=4, 0b111), Ok(()));
+        assert_eq!(bits, 0b1001_1101);
+        assert_eq!(bits.set_bits_checked(.., u8::MAX), Ok(()));
+        assert_eq!(bits, u8::MAX);
+    }
+
+    #[test_case]
+    fn test_read_bytes() {
+        let bytes = [0xff, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0xff];
+        assert_eq!(read_u16_le(&bytes, 1), 0x0201);
+        assert_eq!(read_u16_be(&bytes, 1), 0x0102);
+        assert_eq!(read_u32_le(&bytes, 3), 0x0605_0403);
+        assert_eq!(read_u32_be(&bytes, 3), 0x0304_0506);
+        assert_eq!(read_u64_le(&bytes, 1), 0x0807_0605_0403_0201);
+        assert_eq!(read_u64_be(&bytes, 1), 0x0102_0304_0506_0708);
+        assert_eq!(read_u16_le(&bytes, 8), 0xff08);
+    }
+
+    #[test_case]
+    fn test_bit_field_get_enum() {
+        /// Type of a system segment descriptor in long mode.
+        #[derive(Debug, PartialEq, Eq)]
+        enum SegmentType {
+            Ldt,
+            AvailableTss,
+            BusyTss,
+        }
+
+        impl TryFrom<u64> for SegmentType {
+            type Error = u64;
+
+            fn try_from(value: u64) -> Result<Self, Self::Error> {
+                match value {
+                    0b0010 => Ok(SegmentType::Ldt),
+                    0b1001 => Ok(SegmentType::AvailableTss),
+                    0b1011 => Ok(SegmentType::BusyTss),
+                    v => Err(v),
+                }
+            }
+        }
+
+        // The low word of a present TSS descriptor.
+        let mut low: u64 = 1 << 47 | 0x67;
+        low.set_bits(40..=43, 0b1001);
+        assert_eq!(low.get_enum(40..=43), Ok(SegmentType::AvailableTss));
+        low.set_bits(40..=43, 0b1011);
+        assert_eq!(low.get_enum(40..=43), Ok(SegmentType::BusyTss));
+        low.set_bits(40..=43, 0b1111);
+        assert_eq!(low.get_enum::<SegmentType, _>(40..=43), Err(0b1111));
+    }
+
+    #[test_case]
+    fn test_atomic_bit_field() {
+        let flags = AtomicU64::new(0);
+        // Interleaved updates of different fields keep each other's bits.
+        assert_eq!(flags.fetch_set_bits(0..4, 0xa, Ordering::SeqCst), 0);
+        assert_eq!(flags.fetch_set_bits(60.., 0x5, Ordering::SeqCst), 0xa);
+        flags.fetch_set_bits(8, 1, Ordering::SeqCst);
+        flags.fetch_set_bits(0..4, 0x3, Ordering::SeqCst);
+        assert_eq!(flags.load(Ordering::SeqCst), 0x5000_0000_0000_0103);
+        assert_eq!(flags.load_bits(60.., Ordering::SeqCst), 0x5);
+
+        let mask = AtomicU32::new(u32::MAX);
+        assert_eq!(mask.fetch_set_bits(16..24, 0, Ordering::SeqCst), u32::MAX);
+        assert_eq!(mask.load(Ordering::SeqCst), 0xff00_ffff);
+    }
+
+    #[test_case]
+    fn test_bit_field_count_ones_in() {
+        let bits: u16 = 0b1011_0110_1101_0011;
+        assert_eq!(bits.count_ones_in(0..=7), 5);
+        assert_eq!(bits.count_ones_in(8..), 5);
+        assert_eq!(bits.count_ones_in(4), 1);
+        assert_eq!(bits.count_ones_in(..), 10);
+    }
+
+    #[test_case]
+    fn test_bit_field_set_bit_indices() {
+        let mut iter = 0b1010_0001u8.set_bit_indices();
+        assert_eq!(iter.len(), 3);
+        let mut indices = [0u8; 3];
+        for slot in indices.iter_mut() {
+            *slot = iter.next().unwrap();
+        }
+        assert_eq!(indices, [0, 5, 7]);
+        assert_eq!(iter.next(), None);
+
+        assert_eq!(0u64.set_bit_indices().next(), None);
+        assert_eq!(u64::MAX.set_bit_indices().last(), Some(63));
+    }
 }
@@ -0,0 +1,74 @@
+use core::fmt;
+
+use crate::screen::ScreenConsole;
+use crate::spinlock::SpinLock;
+
+/// Where `print!` writes to.
+///
+/// Consoles are shared as `&'static dyn Console`, so implementations
+/// must do their own locking.
+pub trait Console: Sync {
+    fn write_str(&self, s: &str);
+}
+
+static OUTPUT: SpinLock<&'static dyn Console> = SpinLock::new(&ScreenConsole);
+
+/// Redirect `print!` to `console`, e.g. `&SerialConsole` for headless
+/// runs. Returns the previous console so it can be restored.
+pub fn set_output(console: &'static dyn Console) -> &'static dyn Console {
+    crate::x86_64::without_interrupts(|| core::mem::replace(&mut *OUTPUT.lock(), console))
+}
+
+/// `fmt::Write` adapter over a console.
+pub(crate) struct Writer(pub(crate) &'static dyn Console);
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    crate::x86_64::without_interrupts(|| {
+        // Don't hold the lock while writing, so the console is free to
+        // print or switch the output itself.
+        let console = *OUTPUT.lock();
+        // Only a `Display` impl can fail here. Printing as much as we can
+        // beats panicking, which may be printing itself.
+        let _ = Writer(console).write_fmt(args);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::print;
+
+    struct Capture(SpinLock<([u8; 64], usize)>);
+
+    impl Console for Capture {
+        fn write_str(&self, s: &str) {
+            let mut capture = self.0.lock();
+            let (buf, len) = &mut *capture;
+            let n = s.len().min(buf.len() - *len);
+            buf[*len..*len + n].copy_from_slice(&s.as_bytes()[..n]);
+            *len += n;
+        }
+    }
+
+    #[test_case]
+    fn test_redirect_output() {
+        static CAPTURE: Capture = Capture(SpinLock::new(([0; 64], 0)));
+
+        let prev = set_output(&CAPTURE);
+        print!("hello {}\n", 42);
+        set_output(prev);
+        print!("not captured");
+
+        let capture = CAPTURE.0.lock();
+        assert_eq!(&capture.0[..capture.1], b"hello 42\n");
+    }
+}
@@ -1,22 +1,35 @@
+use crate::bit_field::const_set_bits;
 use crate::lazy_static;
+use crate::memory::KernelStack;
+use crate::x86_64;
 use crate::x86_64::{
-    lgdt, load_tss, DescriptorTablePointer, PrivilegeLevel, SegmentSelector, VirtAddr, CS,
+    lgdt, load_tss, DescriptorTablePointer, PrivilegeLevel, SegmentSelector, VirtAddr, CS, DS, SS,
 };
+use core::fmt;
 use core::mem::size_of;
 
+/// Number of interrupt stack table slots in the TSS, so IST indices are
+/// in 0..7.
+pub const IST_COUNT: usize = 7;
+
+// Faults that may be caused by a bad stack get a stack of their own, so
+// that they don't fault again while pushing the interrupt frame.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 2;
+
+const DOUBLE_FAULT_STACK_PAGES: usize = 5;
+const FAULT_STACK_PAGES: usize = 3;
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            // Notice the property of x86 stack, i.e. grows downward
-            stack_start + STACK_SIZE
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            KernelStack::new(DOUBLE_FAULT_STACK_PAGES).top();
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+            KernelStack::new(FAULT_STACK_PAGES).top();
+        tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] =
+            KernelStack::new(FAULT_STACK_PAGES).top();
         tss
     };
 
@@ -25,21 +38,35 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_segment());
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
         let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors{code_selector, tss_selector})
+        (gdt, Selectors{code_selector, data_selector, tss_selector})
     };
 }
 
+/// Whether our GDT is the one in use.
+pub fn is_loaded() -> bool {
+    // Copy out of the packed struct.
+    let base = { x86_64::sgdt().base };
+    base == VirtAddr::from_ptr(GDT.0.table.as_ptr())
+}
+
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+/// Load the GDT and the TSS. It's fine to call it more than once.
 pub fn init() {
-    GDT.0.load();
-    // Safety: code and tss selector are valid.
+    // Safety: It's the kernel's table, which the IDT gates refer to.
+    unsafe { GDT.0.load_and_set_segments(GDT.1.code_selector, GDT.1.data_selector) };
+    // Loading the TSS marks it busy, and `ltr` faults on a busy TSS.
+    if x86_64::read_tr() == GDT.1.tss_selector {
+        return;
+    }
+    // Safety: tss selector is valid.
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
@@ -52,7 +79,7 @@ pub struct TaskStateSegment {
     pub privilege_stack_table: [VirtAddr; 3],
     reserved_2: u64,
     /// The full 64-bit canonical forms of the interrupt stack table (IST) pointers.
-    pub interrupt_stack_table: [VirtAddr; 7],
+    pub interrupt_stack_table: [VirtAddr; IST_COUNT],
     reserved_3: u64,
     reserved_4: u16,
     /// The 16-bit offset to the I/O permission bit map from the 64-bit TSS base.
@@ -67,10 +94,10 @@ impl TaskStateSegment {
     /// As we always set the TSS segment limit to
     /// `size_of::<TaskStateSegment>() - 1`, this means that `iomap_base` is
     /// initialized to `size_of::<TaskStateSegment>()`.
-    fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             privilege_stack_table: [VirtAddr::zero(); 3],
-            interrupt_stack_table: [VirtAddr::zero(); 7],
+            interrupt_stack_table: [VirtAddr::zero(); IST_COUNT],
             iomap_base: size_of::<TaskStateSegment>() as u16,
             reserved_1: 0,
             reserved_2: 0,
@@ -80,7 +107,7 @@ impl TaskStateSegment {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct GlobalDescriptorTable {
     table: [u64; 8],
     len: usize,
@@ -116,13 +143,7 @@ impl GlobalDescriptorTable {
             }
         };
         let rpl = match entry {
-            Descriptor::UserSegment(value) => {
-                if value & DescriptorFlags::DPL_RING_3 != 0 {
-                    PrivilegeLevel::Ring3
-                } else {
-                    PrivilegeLevel::Ring0
-                }
-            }
+            Descriptor::UserSegment(value) => DescriptorFlags::from_bits_retain(value).dpl(),
             Descriptor::SystemSegment(_, _) => PrivilegeLevel::Ring0,
         };
 
@@ -137,6 +158,11 @@ impl GlobalDescriptorTable {
         index
     }
 
+    /// Print the used entries over serial.
+    pub fn dump(&self) {
+        crate::serial_println!("{:#?}", self);
+    }
+
     fn pointer(&self) -> DescriptorTablePointer {
         DescriptorTablePointer {
             base: VirtAddr::from_ptr(self.table.as_ptr()),
@@ -149,11 +175,122 @@ impl GlobalDescriptorTable {
         }
     }
 
+    /// Load the table into GDTR.
+    ///
+    /// The CPU keeps using the table at this address until the next `lgdt`,
+    /// so it must outlive every use, hence `&'static self`. Since the type
+    /// is `Clone`, a clone on the stack is an easy way to get this wrong,
+    /// e.g. `gdt.clone().load()`; the bound rejects it at compile time.
     pub fn load(&'static self) {
         // Safety:
         // * valid & 'static
         unsafe { lgdt(&self.pointer()) }
     }
+
+    /// Load the table, then switch CS to `code` and the data segment
+    /// registers to `data`, e.g. to move over from another GDT. Until the
+    /// segment registers are reloaded, they still hold what they cached
+    /// from the old table.
+    ///
+    /// Safety:
+    /// * Until another table is loaded, no interrupt may come in unless
+    ///   the selectors of the IDT gates point to 64-bit code segments in
+    ///   this table. The kernel's gates use the kernel's code selector.
+    ///
+    /// # Panics
+    /// Panics if `code` isn't a ring 0 64-bit code segment in the table, or
+    /// `data` isn't a ring 0 data segment.
+    pub unsafe fn load_and_set_segments(
+        &'static self,
+        code: SegmentSelector,
+        data: SegmentSelector,
+    ) {
+        let code_flags = self.segment_flags(code);
+        assert!(
+            code_flags.contains(DescriptorFlags::EXECUTABLE | DescriptorFlags::LONG_MODE),
+            "{:?} isn't a 64-bit code segment",
+            code
+        );
+        let data_flags = self.segment_flags(data);
+        assert!(
+            !data_flags.contains(DescriptorFlags::EXECUTABLE),
+            "{:?} isn't a data segment",
+            data
+        );
+        self.load();
+        // Safety: Both are present ring 0 segments of the right kind in the
+        // loaded table.
+        unsafe {
+            CS::set_reg(code);
+            DS::set_reg(data);
+            SS::set_reg(data);
+        }
+    }
+
+    /// Flags of the present ring 0 user segment the selector points to.
+    /// # Panics
+    /// Panics if it points to something else.
+    fn segment_flags(&self, selector: SegmentSelector) -> DescriptorFlags {
+        let index = selector.index() as usize;
+        assert!(index != 0 && index < self.len, "{:?} out of the GDT", selector);
+        let flags = DescriptorFlags::from_bits_retain(self.table[index]);
+        assert!(
+            flags.contains(DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT)
+                && flags.dpl() == PrivilegeLevel::Ring0
+                && selector.rpl() == PrivilegeLevel::Ring0,
+            "{:?} isn't a present ring 0 segment",
+            selector
+        );
+        flags
+    }
+}
+
+impl fmt::Debug for GlobalDescriptorTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use crate::bit_field::BitField;
+
+        let mut list = f.debug_list();
+        let mut index = 1;
+        while index < self.len {
+            let low = self.table[index];
+            let mut base = low.get_bits(16..40) | low.get_bits(56..64) << 24;
+            let limit = low.get_bits(0..16) | low.get_bits(48..52) << 16;
+            // Everything but base and limit.
+            let mut flags = low;
+            flags.set_bits(0..40, 0);
+            flags.set_bits(48..52, 0);
+            flags.set_bits(56..64, 0);
+            let flags = DescriptorFlags::from_bits_retain(flags);
+
+            let system = !flags.contains(DescriptorFlags::USER_SEGMENT);
+            if system {
+                // The high half of a system segment holds the upper base.
+                base.set_bits(32..64, self.table[index + 1].get_bits(0..32));
+            }
+            list.entry(&DecodedEntry { index, system, base, limit, flags });
+            index += if system { 2 } else { 1 };
+        }
+        list.finish()
+    }
+}
+
+struct DecodedEntry {
+    index: usize,
+    system: bool,
+    base: u64,
+    limit: u64,
+    flags: DescriptorFlags,
+}
+
+impl fmt::Debug for DecodedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct(if self.system { "SystemSegment" } else { "UserSegment" })
+            .field("index", &self.index)
+            .field("base", &format_args!("{:#x}", self.base))
+            .field("limit", &format_args!("{:#x}", self.limit))
+            .field("flags", &self.flags)
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -162,58 +299,85 @@ pub enum Descriptor {
     SystemSegment(u64, u64),
 }
 
-// TODO: refactor to bitflags after I write my own version.
-struct DescriptorFlags;
+// Checked at compile time, so the flags can't drift from the known values.
+const _: () = assert!(DescriptorFlags::KERNEL_CODE64.bits() == 0x00af9b000000ffff);
+const _: () = assert!(DescriptorFlags::KERNEL_DATA.bits() == 0x00cf93000000ffff);
+
+crate::bit_flags! {
+    pub struct DescriptorFlags: u64 {
+        // Flags ignored in 64-bit mode are omitted.
+
+        // TODO: What does below x86_64 comment mean?
+        // * _Setting_ this bit in software prevents GDT writes on first use.
+        const ACCESSED = 1 << 40;
+
+        // --- It's said to be ignored in 64-bit
+        const WRITABLE = 1 << 41;
+        const GRANULARITY = 1 << 55;
+        const DEFAULT_SIZE = 1 << 54;
+        const LIMIT_0_15 = const_set_bits(0, 0..16, 0xffff);
+        const LIMIT_16_19 = const_set_bits(0, 48..52, 0xf);
+        // ---
+
+        const EXECUTABLE = 1 << 43;
+        const USER_SEGMENT = 1 << 44;
+        const DPL_RING_3 = const_set_bits(0, 45..47, 3);
+        const PRESENT = 1 << 47;
+        const LONG_MODE = 1 << 53;
+    }
+}
 
 impl DescriptorFlags {
-    // Flags ignored in 64-bit mode are omitted.
+    pub const COMMON: Self = Self::USER_SEGMENT
+        .union(Self::PRESENT)
+        .union(Self::WRITABLE)
+        .union(Self::ACCESSED)
+        .union(Self::LIMIT_0_15)
+        .union(Self::LIMIT_16_19)
+        .union(Self::GRANULARITY);
 
-    // TODO: What does below x86_64 comment mean?
-    // * _Setting_ this bit in software prevents GDT writes on first use.
-    pub const ACCESSED: u64 = 1 << 40;
+    pub const KERNEL_CODE64: Self = Self::COMMON.union(Self::LONG_MODE).union(Self::EXECUTABLE);
 
-    // --- It's said to be ignored in 64-bit
-    pub const WRITABLE: u64 = 1 << 41;
-    pub const GRANULARITY: u64 = 1 << 55;
-    pub const LIMIT_0_15: u64 = 0xffff;
-    pub const LIMIT_16_19: u64 = 0xf << 48;
-    // ---
+    /// Flat data segment, the same as x86_64 crate's.
+    pub const KERNEL_DATA: Self = Self::COMMON.union(Self::DEFAULT_SIZE);
 
-    pub const EXECUTABLE: u64 = 1 << 43;
-    pub const USER_SEGMENT: u64 = 1 << 44;
-    pub const DPL_RING_3: u64 = 3 << 45;
-    pub const PRESENT: u64 = 1 << 47;
-    pub const LONG_MODE: u64 = 1 << 53;
+    /// Type of an available 64-bit TSS in a system segment.
+    const AVAILABLE_TSS: u64 = const_set_bits(0, 40..44, 0b1001);
 
-    pub const COMMON: u64 = Self::USER_SEGMENT
-        | Self::PRESENT
-        | Self::WRITABLE
-        | Self::ACCESSED
-        | Self::LIMIT_0_15
-        | Self::LIMIT_16_19
-        | Self::GRANULARITY;
+    /// The descriptor privilege level.
+    pub fn dpl(self) -> PrivilegeLevel {
+        use crate::bit_field::BitField;
 
-    pub const KERNEL_CODE64: u64 = Self::COMMON | Self::LONG_MODE | Self::EXECUTABLE;
+        match self.0.get_bits(45..47) {
+            0 => PrivilegeLevel::Ring0,
+            1 => PrivilegeLevel::Ring1,
+            2 => PrivilegeLevel::Ring2,
+            _ => PrivilegeLevel::Ring3,
+        }
+    }
 }
 
 impl Descriptor {
-    fn kernel_segment() -> Self {
-        Descriptor::UserSegment(DescriptorFlags::KERNEL_CODE64)
+    pub fn kernel_segment() -> Self {
+        Descriptor::UserSegment(DescriptorFlags::KERNEL_CODE64.bits())
+    }
+
+    pub fn kernel_data_segment() -> Self {
+        Descriptor::UserSegment(DescriptorFlags::KERNEL_DATA.bits())
     }
 
-    fn tss_segment(tss: &'static TaskStateSegment) -> Self {
+    pub fn tss_segment(tss: &'static TaskStateSegment) -> Self {
         use crate::bit_field::BitField;
 
         let ptr = tss as *const _ as u64;
-        let mut low = DescriptorFlags::PRESENT;
+        let mut low = DescriptorFlags::PRESENT.bits();
         // base
         low.set_bits(16..40, ptr.get_bits(0..24));
         low.set_bits(56..64, ptr.get_bits(24..32));
         // TODO: what does those below comments mean?
         // limit (the `-1` in needed since the bound is inclusive)
         low.set_bits(0..16, (size_of::<TaskStateSegment>() - 1) as u64);
-        // type (0b1001 = available 64-bit tss)
-        low.set_bits(40..44, 0b1001);
+        low |= DescriptorFlags::AVAILABLE_TSS;
 
         let mut high = 0;
         high.set_bits(0..32, ptr.get_bits(32..64));
@@ -226,8 +390,111 @@ impl Descriptor {
 mod tests {
     use super::*;
 
+    #[test_case]
+    fn test_load_static() {
+        // Only a `'static` table gets here; a local one is rejected by
+        // the signature of `load`.
+        init();
+        assert!(is_loaded());
+        // Loading it again keeps the same table in use.
+        GDT.0.load();
+        assert!(is_loaded());
+    }
+
+    #[test_case]
+    fn test_init_twice() {
+        init();
+        init();
+        assert_eq!(x86_64::read_tr(), GDT.1.tss_selector);
+    }
+
     #[test_case]
     fn test_flags() {
-        assert_eq!(DescriptorFlags::KERNEL_CODE64, 0x00af9b000000ffffu64);
+        assert_eq!(DescriptorFlags::KERNEL_CODE64.bits(), 0x00af9b000000ffffu64);
+        assert_eq!(DescriptorFlags::KERNEL_DATA.bits(), 0x00cf93000000ffffu64);
+        assert!(!DescriptorFlags::KERNEL_DATA.contains(DescriptorFlags::EXECUTABLE));
+    }
+
+    #[test_case]
+    fn test_data_selector() {
+        init();
+        assert_eq!(DS::get_reg(), GDT.1.data_selector);
+        assert_eq!(SS::get_reg(), GDT.1.data_selector);
+        assert_eq!(CS::get_reg(), GDT.1.code_selector);
+    }
+
+    #[test_case]
+    fn test_dump() {
+        use crate::test_util::StrBuf;
+        use core::fmt::Write;
+
+        let mut gdt = GlobalDescriptorTable::new();
+        gdt.add_entry(Descriptor::kernel_segment());
+        gdt.add_entry(Descriptor::tss_segment(&TSS));
+
+        let mut out = StrBuf::<512>::new();
+        write!(out, "{:?}", gdt).unwrap();
+        let mut base = StrBuf::<32>::new();
+        write!(base, "base: {:#x}", &*TSS as *const TaskStateSegment as u64).unwrap();
+        assert!(out.as_str().contains(base.as_str()), "{}", out.as_str());
+        assert!(out.as_str().contains("UserSegment { index: 1, base: 0x0, limit: 0xfffff"));
+        assert!(out.as_str().contains("SystemSegment { index: 2"));
+        // The limit covers the whole TSS, no more.
+        let mut limit = StrBuf::<32>::new();
+        write!(limit, "limit: {:#x}", size_of::<TaskStateSegment>() - 1).unwrap();
+        assert!(out.as_str().contains(limit.as_str()));
+        gdt.dump();
+    }
+
+    #[test_case]
+    fn test_flags_ops() {
+        use crate::test_util::StrBuf;
+        use core::fmt::Write;
+
+        let flags =
+            DescriptorFlags::COMMON | DescriptorFlags::LONG_MODE | DescriptorFlags::EXECUTABLE;
+        assert_eq!(flags, DescriptorFlags::KERNEL_CODE64);
+        assert!(flags.contains(DescriptorFlags::PRESENT | DescriptorFlags::LONG_MODE));
+        assert!(!flags.contains(DescriptorFlags::DPL_RING_3));
+        assert_eq!(flags.dpl(), PrivilegeLevel::Ring0);
+        assert_eq!(DescriptorFlags::DPL_RING_3.dpl(), PrivilegeLevel::Ring3);
+
+        let mut out = StrBuf::<128>::new();
+        write!(
+            out,
+            "{:?}",
+            DescriptorFlags::PRESENT | DescriptorFlags::from_bits_retain(1 << 42)
+        )
+        .unwrap();
+        assert_eq!(out.as_str(), "DescriptorFlags(PRESENT | 0x40000000000)");
+    }
+
+    lazy_static! {
+        // Data before code, so the indices differ from the kernel's.
+        static ref TEST_GDT: (GlobalDescriptorTable, SegmentSelector, SegmentSelector) = {
+            let mut gdt = GlobalDescriptorTable::new();
+            let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+            let code_selector = gdt.add_entry(Descriptor::kernel_segment());
+            (gdt, code_selector, data_selector)
+        };
+    }
+
+    #[test_case]
+    fn test_load_and_set_segments() {
+        let (code, data) = (TEST_GDT.1, TEST_GDT.2);
+        // Safety: The IDT refers to the kernel's code segment, so no
+        // interrupt may come in while the test table is loaded.
+        let (cs, ds, ss) = x86_64::without_interrupts(|| unsafe {
+            TEST_GDT.0.load_and_set_segments(code, data);
+            let regs = (CS::get_reg(), DS::get_reg(), SS::get_reg());
+            // Back to the kernel's, the TSS stays loaded.
+            GDT.0.load_and_set_segments(GDT.1.code_selector, GDT.1.data_selector);
+            regs
+        });
+        assert_eq!((cs, ds, ss), (code, data, data));
+        assert_eq!(cs.index(), 2);
+        assert_eq!(ds.index(), 1);
+        assert!(is_loaded());
+        assert_eq!(CS::get_reg(), GDT.1.code_selector);
     }
 }
@@ -0,0 +1,88 @@
+// VGA DAC palette, needed for anything beyond the default colors in
+// graphics modes like 13h.
+// See https://wiki.osdev.org/VGA_Hardware#VGA_Registers
+
+use crate::port::{Port, PortRead, PortWrite};
+use crate::x86_64::without_interrupts;
+
+/// Selects the entry the following reads of DAC_DATA come from.
+const DAC_READ_INDEX: u16 = 0x3c7;
+/// Selects the entry the following writes to DAC_DATA go to.
+const DAC_WRITE_INDEX: u16 = 0x3c8;
+/// Red, green and blue in turn. The index moves to the next entry after blue.
+const DAC_DATA: u16 = 0x3c9;
+
+/// Each component is only 6 bits wide.
+const COMPONENT_MASK: u8 = 0x3f;
+
+pub const PALETTE_SIZE: usize = 256;
+
+/// Set palette entry `index` to the color. Components are 6-bit, so only
+/// the low 6 bits of r, g and b are used.
+pub fn set_palette_entry(index: u8, r: u8, g: u8, b: u8) {
+    let mut write_index: Port<u8> = Port::new(DAC_WRITE_INDEX);
+    let mut data: Port<u8> = Port::new(DAC_DATA);
+    // The index and the component sequence are shared state of the DAC,
+    // so don't let an interrupt handler touch it halfway.
+    without_interrupts(|| {
+        // Safety: These are the standard VGA DAC ports.
+        unsafe {
+            write_index.write(index);
+            data.write(r & COMPONENT_MASK);
+            data.write(g & COMPONENT_MASK);
+            data.write(b & COMPONENT_MASK);
+        }
+    });
+}
+
+/// Load the whole palette, starting from entry 0.
+pub fn set_palette(palette: &[(u8, u8, u8); PALETTE_SIZE]) {
+    let mut write_index: Port<u8> = Port::new(DAC_WRITE_INDEX);
+    let mut data: Port<u8> = Port::new(DAC_DATA);
+    without_interrupts(|| {
+        // Safety: These are the standard VGA DAC ports.
+        unsafe {
+            write_index.write(0);
+            // The DAC moves to the next entry by itself.
+            for &(r, g, b) in palette.iter() {
+                data.write(r & COMPONENT_MASK);
+                data.write(g & COMPONENT_MASK);
+                data.write(b & COMPONENT_MASK);
+            }
+        }
+    });
+}
+
+/// Read palette entry `index` as 6-bit (r, g, b).
+pub fn palette_entry(index: u8) -> (u8, u8, u8) {
+    let mut read_index: Port<u8> = Port::new(DAC_READ_INDEX);
+    let mut data: Port<u8> = Port::new(DAC_DATA);
+    without_interrupts(|| {
+        // Safety: These are the standard VGA DAC ports.
+        unsafe {
+            read_index.write(index);
+            let r: u8 = data.read();
+            let g: u8 = data.read();
+            let b: u8 = data.read();
+            (r, g, b)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_set_palette_entry() {
+        const INDEX: u8 = 200;
+
+        let (r, g, b) = palette_entry(INDEX);
+        set_palette_entry(INDEX, 0x3f, 0x15, 0x2a);
+        assert_eq!(palette_entry(INDEX), (0x3f, 0x15, 0x2a));
+        // Only 6 bits are kept.
+        set_palette_entry(INDEX, 0xff, 0x40, 0x01);
+        assert_eq!(palette_entry(INDEX), (0x3f, 0x00, 0x01));
+        set_palette_entry(INDEX, r, g, b);
+    }
+}
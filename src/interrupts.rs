@@ -1,18 +1,24 @@
+pub mod debug;
 pub mod idt;
+pub mod nmi;
+pub mod probe;
 
 pub use crate::pic::ChainedPics;
 
 use core::fmt;
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 use crate::spinlock::SpinLock;
 use crate::x86_64::{self, VirtAddr};
 use crate::lazy_static;
 use crate::print;
-use crate::println;
 use crate::port::{ Port, PortRead };
-use crate::serial_print;
+use crate::console::{Console, Writer};
 use crate::serial_println;
-use idt::InterruptDescriptorTable;
+pub use idt::InterruptDescriptorTable;
+pub use probe::install_probe_handler;
+pub use crate::x86_64::{enable_and_hlt, enable_interrupt as enable};
+use nmi::raw_nmi_handler;
 
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -306,6 +312,19 @@ pub struct InterruptStackFrame {
     value: InterruptStackFrameValue,
 }
 
+impl InterruptStackFrame {
+    /// The frame the handler returns to, e.g. to set the trap flag. Write
+    /// it with `write_volatile`, since the compiler can't tell `iretq`
+    /// reads it.
+    ///
+    /// Safety:
+    /// * Whatever is written must be valid to return to, otherwise the
+    ///   interrupted code goes astray.
+    pub unsafe fn as_mut(&mut self) -> &mut InterruptStackFrameValue {
+        &mut self.value
+    }
+}
+
 impl core::ops::Deref for InterruptStackFrame {
     type Target = InterruptStackFrameValue;
 
@@ -324,126 +343,565 @@ pub struct InterruptStackFrameValue {
     pub stack_segment: u64,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// A compact one-line format, for paths that may fire often.
+/// Use `{:#?}` for a full dump.
+impl fmt::Display for InterruptStackFrameValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rip={:#x} cs={:#x} rflags={:#x} rsp={:#x} ss={:#x}",
+            self.instruction_pointer,
+            self.code_segment,
+            self.cpu_flags,
+            self.stack_pointer,
+            self.stack_segment,
+        )
+    }
+}
+
+/// CPU exceptions and their vector numbers.
+/// See https://wiki.osdev.org/Exceptions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    BreakPoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    CoprocessorSegmentOverrun = 9,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    VmmCommunicationException = 29,
+    SecurityException = 30,
+}
+
+impl Exception {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exception::DivideError => "Divide Error",
+            Exception::Debug => "Debug",
+            Exception::NonMaskableInterrupt => "Non-maskable Interrupt",
+            Exception::BreakPoint => "Breakpoint",
+            Exception::Overflow => "Overflow",
+            Exception::BoundRangeExceeded => "Bound Range Exceeded",
+            Exception::InvalidOpcode => "Invalid Opcode",
+            Exception::DeviceNotAvailable => "Device Not Available",
+            Exception::DoubleFault => "Double Fault",
+            Exception::CoprocessorSegmentOverrun => "Coprocessor Segment Overrun",
+            Exception::InvalidTss => "Invalid TSS",
+            Exception::SegmentNotPresent => "Segment Not Present",
+            Exception::StackSegmentFault => "Stack-Segment Fault",
+            Exception::GeneralProtectionFault => "General Protection Fault",
+            Exception::PageFault => "Page Fault",
+            Exception::X87FloatingPoint => "x87 Floating-Point Exception",
+            Exception::AlignmentCheck => "Alignment Check",
+            Exception::MachineCheck => "Machine Check",
+            Exception::SimdFloatingPoint => "SIMD Floating-Point Exception",
+            Exception::Virtualization => "Virtualization Exception",
+            Exception::VmmCommunicationException => "VMM Communication Exception",
+            Exception::SecurityException => "Security Exception",
+        }
+    }
+}
+
+impl TryFrom<u8> for Exception {
+    type Error = u8;
+
+    /// Returns the vector back if it's reserved or not an exception.
+    fn try_from(vector: u8) -> Result<Self, Self::Error> {
+        let exception = match vector {
+            0 => Exception::DivideError,
+            1 => Exception::Debug,
+            2 => Exception::NonMaskableInterrupt,
+            3 => Exception::BreakPoint,
+            4 => Exception::Overflow,
+            5 => Exception::BoundRangeExceeded,
+            6 => Exception::InvalidOpcode,
+            7 => Exception::DeviceNotAvailable,
+            8 => Exception::DoubleFault,
+            9 => Exception::CoprocessorSegmentOverrun,
+            10 => Exception::InvalidTss,
+            11 => Exception::SegmentNotPresent,
+            12 => Exception::StackSegmentFault,
+            13 => Exception::GeneralProtectionFault,
+            14 => Exception::PageFault,
+            16 => Exception::X87FloatingPoint,
+            17 => Exception::AlignmentCheck,
+            18 => Exception::MachineCheck,
+            19 => Exception::SimdFloatingPoint,
+            20 => Exception::Virtualization,
+            29 => Exception::VmmCommunicationException,
+            30 => Exception::SecurityException,
+            v => return Err(v),
+        };
+        Ok(exception)
+    }
+}
+
+/// Name of the exception at `vector`, or None if the vector is reserved
+/// or not an exception.
+pub fn exception_name(vector: u8) -> Option<&'static str> {
+    Exception::try_from(vector).ok().map(|e| e.name())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Serial2 = PIC_1_OFFSET + 3,
+    Serial1 = PIC_1_OFFSET + 4,
+    Rtc = PIC_2_OFFSET,
+    Mouse = PIC_2_OFFSET + 4,
+}
+
+impl InterruptIndex {
+    pub const ALL: [Self; 6] = [
+        Self::Timer,
+        Self::Keyboard,
+        Self::Serial2,
+        Self::Serial1,
+        Self::Rtc,
+        Self::Mouse,
+    ];
+
+    /// The vector in the IDT.
+    pub const fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// The IRQ line on the chained PICs.
+    pub const fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+impl TryFrom<u8> for InterruptIndex {
+    type Error = u8;
+
+    /// Returns the vector back if no IRQ we know of is delivered on it.
+    fn try_from(vector: u8) -> Result<Self, Self::Error> {
+        Self::ALL
+            .into_iter()
+            .find(|index| index.as_u8() == vector)
+            .ok_or(vector)
+    }
+}
+
+/// Let the PICs deliver the interrupt.
+pub fn enable_irq(index: InterruptIndex) {
+    // Handlers lock PICS to notify end of interrupt.
+    x86_64::without_interrupts(|| {
+        // Safety: PICS is set up at the standard ports.
+        unsafe { PICS.lock().unmask(index.irq()) }
+    });
+}
+
+/// Stop the PICs from delivering the interrupt.
+pub fn disable_irq(index: InterruptIndex) {
+    x86_64::without_interrupts(|| {
+        // Safety: PICS is set up at the standard ports.
+        unsafe { PICS.lock().mask(index.irq()) }
+    });
 }
 
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        // Both handler and raw handler should work.
-        idt.divide_error.set_raw_handler(raw_handler!(raw_divide_by_zero_handler));
-        idt.breakpoint.set_handler(breakpoint_handler);
-        idt.invalid_opcode.set_raw_handler(raw_handler!(raw_invalid_opcode_handler));
-        // Safety:
-        // * The stack index points to a valid stack in GDT.
-        // * It's not used by other interrupt handler.
-        unsafe {
-            idt.double_fault
-                .set_raw_handler(raw_handler_with_error_code!(raw_double_fault_handler -> !))
-                .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+    static ref IDT: InterruptDescriptorTable = build_idt();
+}
+
+/// The kernel's IDT with all of its handlers. This is the only place
+/// handlers are wired up; `init` loads the one built here.
+pub fn build_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    // Both handler and raw handler should work.
+    idt.divide_error.set_raw_handler(raw_handler!(raw_divide_by_zero_handler));
+    idt.non_maskable_interrupt.set_raw_handler(raw_handler!(raw_nmi_handler));
+    idt.breakpoint.set_handler(breakpoint_handler);
+    idt.debug.set_handler(debug::debug_handler);
+    idt.invalid_opcode.set_raw_handler(raw_handler!(raw_invalid_opcode_handler));
+    // Safety:
+    // * The stack index points to a valid stack in GDT.
+    // * It's not used by other interrupt handler.
+    unsafe {
+        idt.double_fault
+            .set_raw_handler(raw_handler_with_error_code!(raw_double_fault_handler -> !))
+            .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    // Safety:
+    // * The stack indices point to valid stacks in GDT, each used by one
+    //   handler only.
+    // A fault in either handler starts over at the top of its stack and
+    // overwrites the frame of the running one. That's fine since these
+    // never return. The probe's handlers on the same stacks do, see
+    // `probe` for why they can't fault.
+    unsafe {
         idt.general_protection_fault
-            .set_raw_handler(raw_handler_with_error_code!(raw_general_protection_fault_handler));
+            .set_raw_handler(raw_handler_with_error_code!(raw_general_protection_fault_handler))
+            .set_stack_index(crate::gdt::GENERAL_PROTECTION_FAULT_IST_INDEX);
         idt.page_fault
-            .set_raw_handler(raw_page_fault_handler!(raw_page_fault_handler));
+            .set_raw_handler(raw_page_fault_handler!(raw_page_fault_handler))
+            .set_stack_index(crate::gdt::PAGE_FAULT_IST_INDEX);
+    }
 
-        idt[InterruptIndex::Timer as usize]
-            .set_raw_handler(raw_handler!(raw_timer_handler));
-        idt[InterruptIndex::Keyboard as usize]
-            .set_raw_handler(raw_handler!(raw_keyboard_handler));
-        idt
-    };
+    set_irq_handlers(&mut idt);
+    if default_handler().is_some() {
+        set_default_handlers(&mut idt);
+    }
+    idt
 }
 
 pub fn init() {
     IDT.load();
 }
 
-extern "C" fn raw_keyboard_handler(_stack_frame: &InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+/// Whether our IDT is the one in use.
+pub fn is_loaded() -> bool {
+    // Copy out of the packed struct.
+    let base = { x86_64::sidt().base };
+    base == VirtAddr::from_ptr(&*IDT)
+}
 
-    lazy_static! {
-        static ref KEYBOARD: SpinLock<Keyboard<layouts::Us104Key, ScancodeSet1>> = 
-            SpinLock::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
-    }
+/// Load idt for the duration of f, then put back whatever IDT was loaded
+/// before, e.g. to catch an exception in a test without breaking the
+/// tests after it. Interrupts are off meanwhile, since idt may have no
+/// gates for the IRQs.
+pub fn with_idt<R>(idt: &'static InterruptDescriptorTable, f: impl FnOnce() -> R) -> R {
+    x86_64::without_interrupts(|| {
+        let previous = x86_64::sidt();
+        idt.load();
+        let result = f();
+        // Safety: It was loaded before, so it's still valid.
+        unsafe { x86_64::lidt(&previous) };
+        result
+    })
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+/// Rust handler for a PIC IRQ line. End of interrupt is sent for it.
+pub type IrqHandler = fn();
 
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(ch) => print!("{ch}"),
-                DecodedKey::RawKey(key) => print!("{key:?}"),
-            }
+/// Number of IRQ lines on the chained PICs.
+pub const IRQ_LINES: usize = 16;
+
+static IRQ_HANDLERS: SpinLock<[Option<IrqHandler>; IRQ_LINES]> = SpinLock::new({
+    let mut handlers: [Option<IrqHandler>; IRQ_LINES] = [None; IRQ_LINES];
+    handlers[InterruptIndex::Timer.irq() as usize] = Some(timer_handler);
+    handlers[InterruptIndex::Keyboard.irq() as usize] = Some(keyboard_handler);
+    handlers
+});
+
+/// Call `handler` on every interrupt of the IRQ line, in place of the
+/// previous one, which is returned.
+/// # Panics
+/// Panics if line isn't below `IRQ_LINES`.
+pub fn register_irq_handler(line: u8, handler: IrqHandler) -> Option<IrqHandler> {
+    // The dispatcher takes the lock in interrupt context.
+    x86_64::without_interrupts(|| IRQ_HANDLERS.lock()[line as usize].replace(handler))
+}
+
+/// Remove the handler of the IRQ line, returning it. Interrupts on the
+/// line are only acknowledged afterward.
+/// # Panics
+/// Panics if line isn't below `IRQ_LINES`.
+pub fn unregister_irq_handler(line: u8) -> Option<IrqHandler> {
+    x86_64::without_interrupts(|| IRQ_HANDLERS.lock()[line as usize].take())
+}
+
+fn dispatch_irq(line: u8) {
+    let interrupt_id = PIC_1_OFFSET + line;
+    {
+        let mut pics = PICS.lock();
+        if pics.is_spurious(interrupt_id) {
+            unsafe { pics.notify_spurious_interrupt(interrupt_id) };
+            return;
         }
     }
-
+    // Don't hold the lock while the handler runs, so it's free to
+    // register handlers itself.
+    let handler = IRQ_HANDLERS.lock()[line as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
     unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
+        PICS.lock().notify_end_of_interrupt(interrupt_id);
     }
 }
 
-extern "C" fn raw_timer_handler(_stack_frame: &InterruptStackFrame) {
-    print!(".");
-    serial_print!(".");
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer as u8);
+/// Define a raw handler for each IRQ line that goes through
+/// `dispatch_irq`, and a function to put them into an IDT.
+macro_rules! irq_handlers {
+    ($($line:literal => $name:ident,)*) => {
+        $(
+            extern "C" fn $name(_stack_frame: &InterruptStackFrame) {
+                dispatch_irq($line);
+            }
+        )*
+
+        fn set_irq_handlers(idt: &mut InterruptDescriptorTable) {
+            $(
+                idt[(PIC_1_OFFSET + $line) as usize].set_raw_handler(raw_handler!($name));
+            )*
+        }
+    };
+}
+
+irq_handlers! {
+    0 => raw_irq0_handler,
+    1 => raw_irq1_handler,
+    2 => raw_irq2_handler,
+    3 => raw_irq3_handler,
+    4 => raw_irq4_handler,
+    5 => raw_irq5_handler,
+    6 => raw_irq6_handler,
+    7 => raw_irq7_handler,
+    8 => raw_irq8_handler,
+    9 => raw_irq9_handler,
+    10 => raw_irq10_handler,
+    11 => raw_irq11_handler,
+    12 => raw_irq12_handler,
+    13 => raw_irq13_handler,
+    14 => raw_irq14_handler,
+    15 => raw_irq15_handler,
+}
+
+/// Rust handler for an interrupt that arrives at an otherwise unset
+/// vector, which is passed in.
+pub type DefaultHandler = fn(u8, &InterruptStackFrame);
+
+static DEFAULT_HANDLER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Call `handler` for interrupts on vectors that have no handler, instead
+/// of letting them triple fault. It takes effect on IDTs built afterward,
+/// so call it before `init`. `report_unhandled` is the usual choice.
+///
+/// It's off by default, since a handler that returns or halts would hide
+/// a fault that a test is expected to catch.
+pub fn set_default_handler(handler: Option<DefaultHandler>) {
+    let ptr = handler.map_or(core::ptr::null_mut(), |handler| handler as *mut ());
+    DEFAULT_HANDLER.store(ptr, Ordering::Release);
+}
+
+fn default_handler() -> Option<DefaultHandler> {
+    let ptr = DEFAULT_HANDLER.load(Ordering::Acquire);
+    if ptr.is_null() {
+        None
+    } else {
+        // Safety: Only `DefaultHandler`s are stored in DEFAULT_HANDLER.
+        Some(unsafe { core::mem::transmute::<*mut (), DefaultHandler>(ptr) })
     }
 }
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error: ErrorCode) {
+/// Print the vector over serial and halt.
+pub fn report_unhandled(vector: u8, stack_frame: &InterruptStackFrame) {
     serial_println!(
-        "EXCEPTION: double fault with error code `{:#x}` at {:#x}\n{:#?}",
-        error,
+        "unhandled interrupt {} at {:#x}\n{:#?}",
+        vector,
         stack_frame.instruction_pointer,
         stack_frame
     );
     x86_64::hlt_loop();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("Haoye! It's a breakpoint!");
-    serial_println!(
-        "At {:#x}\nStackFrame:\n{:#?}",
-        stack_frame.instruction_pointer,
-        stack_frame
-    );
+fn dispatch_unhandled(vector: u8, stack_frame: &InterruptStackFrame) {
+    match default_handler() {
+        Some(handler) => handler(vector, stack_frame),
+        // Cleared after the IDT was built.
+        None => report_unhandled(vector, stack_frame),
+    }
 }
 
-extern "C" fn raw_breakpoint_handler(stack_frame: &InterruptStackFrame) {
+/// Whether the CPU pushes an error code for the vector.
+fn has_error_code(vector: u8) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+/// Define a raw handler for every vector that goes through
+/// `dispatch_unhandled`, and put it into each unset entry of the IDT.
+macro_rules! default_handlers {
+    ($idt:ident; $($hi:literal)*) => {
+        $(
+            default_handlers!(@ROW $idt, $hi);
+        )*
+    };
+    (@ROW $idt:ident, $hi:literal) => {
+        default_handlers!(@ONE $idt, $hi, 0);
+        default_handlers!(@ONE $idt, $hi, 1);
+        default_handlers!(@ONE $idt, $hi, 2);
+        default_handlers!(@ONE $idt, $hi, 3);
+        default_handlers!(@ONE $idt, $hi, 4);
+        default_handlers!(@ONE $idt, $hi, 5);
+        default_handlers!(@ONE $idt, $hi, 6);
+        default_handlers!(@ONE $idt, $hi, 7);
+        default_handlers!(@ONE $idt, $hi, 8);
+        default_handlers!(@ONE $idt, $hi, 9);
+        default_handlers!(@ONE $idt, $hi, 10);
+        default_handlers!(@ONE $idt, $hi, 11);
+        default_handlers!(@ONE $idt, $hi, 12);
+        default_handlers!(@ONE $idt, $hi, 13);
+        default_handlers!(@ONE $idt, $hi, 14);
+        default_handlers!(@ONE $idt, $hi, 15);
+    };
+    (@ONE $idt:ident, $hi:literal, $lo:literal) => {{
+        const VECTOR: u8 = $hi * 16 + $lo;
+        extern "C" fn unhandled(stack_frame: &InterruptStackFrame) {
+            dispatch_unhandled(VECTOR, stack_frame);
+        }
+        extern "C" fn unhandled_with_error_code(stack_frame: &InterruptStackFrame, _: ErrorCode) {
+            dispatch_unhandled(VECTOR, stack_frame);
+        }
+        // Safety: The error code is taken where the CPU pushes one.
+        unsafe {
+            if has_error_code(VECTOR) {
+                let handler = raw_handler_with_error_code!(unhandled_with_error_code);
+                $idt.set_missing_raw_handler(VECTOR, handler);
+            } else {
+                $idt.set_missing_raw_handler(VECTOR, raw_handler!(unhandled));
+            }
+        }
+    }};
+}
+
+fn set_default_handlers(idt: &mut InterruptDescriptorTable) {
+    default_handlers!(idt; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+}
+
+fn keyboard_handler() {
+    use crate::keyboard::{self, DecodedKey};
+
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+
+    if let Some(key) = keyboard::handle_scancode(scancode) {
+        match key {
+            DecodedKey::Unicode(ch) => print!("{ch}"),
+            DecodedKey::RawKey(key) => print!("{key:?}"),
+        }
+    }
+}
+
+fn timer_handler() {
+    crate::pit::tick();
+    crate::screen::tick_cursor();
+    crate::keyboard::tick_repeat();
+    heartbeat();
+}
+
+/// Print a dot on each tick to show the timer is alive. Skipped where
+/// the lock is held, which may be by the code this handler interrupted.
+fn heartbeat() {
+    use core::fmt::Write;
+
+    if let Some(mut screen) = crate::screen::SCREEN.try_lock() {
+        screen.puts(".");
+    }
+    if !crate::serial::is_present() {
+        return;
+    }
+    if let Some(mut serial) = crate::serial::SERIAL1.try_lock() {
+        let _ = serial.write_str(".");
+    }
+}
+
+extern "x86-interrupt" fn breakpoint_handler(mut stack_frame: InterruptStackFrame) {
     serial_println!("Haoye! It's a breakpoint!");
-    serial_println!(
-        "At {:#x}\nStackFrame:\n{:#?}",
-        stack_frame.instruction_pointer,
-        stack_frame
-    );
+    serial_println!("StackFrame: {}", *stack_frame);
+    // Start stepping from here if asked to.
+    debug::apply(&mut stack_frame);
 }
 
 extern "C" fn raw_divide_by_zero_handler(stack_frame: &InterruptStackFrame) {
-    serial_println!("EXCEPTION: divide-by-zero");
+    serial_println!("EXCEPTION: {}", Exception::DivideError.name());
     serial_println!("{:#?}", stack_frame);
     x86_64::hlt_loop();
 }
 
 extern "C" fn raw_invalid_opcode_handler(stack_frame: &InterruptStackFrame) {
     serial_println!(
-        "EXCEPTION: invalid opcode at {:#x}\n{:#?}",
+        "EXCEPTION: {} at {:#x}\n{:#?}",
+        Exception::InvalidOpcode.name(),
         stack_frame.instruction_pointer,
         stack_frame
     );
     x86_64::hlt_loop();
 }
 
+static DOUBLE_FAULTED: AtomicBool = AtomicBool::new(false);
+
+/// Sent over serial first thing on double fault.
+pub const DOUBLE_FAULT_MARKER: u8 = b'!';
+
+/// Whether the double fault handler has run, e.g. for a panic handler to
+/// tell what it's been called from.
+pub fn double_faulted() -> bool {
+    DOUBLE_FAULTED.load(Ordering::Relaxed)
+}
+
+/// Max number of bytes to dump from the interrupted stack.
+const STACK_DUMP_WINDOW: u64 = 256;
+
+lazy_static! {
+    static ref STACK_DUMP_OUTPUT: SpinLock<Option<&'static dyn Console>> = SpinLock::new(None);
+}
+
+/// Hexdump the interrupted stack to `output` on double fault, e.g.
+/// `Some(&SerialConsole)`. This shows what was on the stack when it
+/// overflowed, since the handler itself runs on the IST stack. It's off
+/// by default.
+pub fn set_double_fault_stack_dump(output: Option<&'static dyn Console>) {
+    x86_64::without_interrupts(|| *STACK_DUMP_OUTPUT.lock() = output);
+}
+
+/// Hexdump the stack from `rsp` upward, but never past the end of the page
+/// `rsp` points into, since the next one might be unmapped. The page
+/// itself is looked up first: after a stack overflow or with a corrupted
+/// stack pointer, `rsp` isn't mapped either.
+fn dump_stack(w: &mut impl fmt::Write, rsp: VirtAddr) -> fmt::Result {
+    writeln!(w, "Interrupted stack at {:#x}:", rsp)?;
+    let canonical = ((rsp.0 << 16) as i64 >> 16) as u64 == rsp.0;
+    if !canonical || crate::memory::translate(rsp).is_none() {
+        return writeln!(w, "  not mapped");
+    }
+    let page_end = (rsp.0 | 0xfff) + 1;
+    let end = page_end.min(rsp.0.saturating_add(STACK_DUMP_WINDOW));
+    let mut addr = rsp.0 & !0x7;
+    while addr + 8 <= end {
+        // Safety: The address is aligned and within the mapped page.
+        let value = unsafe { core::ptr::read_volatile(addr as *const u64) };
+        writeln!(w, "  {:#018x}: {:016x}", addr, value)?;
+        addr += 8;
+    }
+    Ok(())
+}
+
 extern "C" fn raw_double_fault_handler(stack_frame: &InterruptStackFrame, error: ErrorCode) -> ! {
+    // Before anything that takes much stack. If the rest overflows the IST
+    // stack, it's a triple fault and a reset without a word, so leave at
+    // least a byte behind.
+    crate::serial::emergency_write(&[DOUBLE_FAULT_MARKER]);
+    DOUBLE_FAULTED.store(true, Ordering::Relaxed);
+    // Don't wait on the lock, the fault may have come in while it's held.
+    let output = STACK_DUMP_OUTPUT.try_lock().and_then(|output| *output);
+    if let Some(output) = output {
+        let _ = dump_stack(&mut Writer(output), stack_frame.stack_pointer);
+    }
     panic!(
-        "EXCEPTION: double fault with error code `{:#x}` at {:#x}\n{:#?}",
-        error, stack_frame.instruction_pointer, stack_frame
+        "EXCEPTION: {} with error code `{:#x}` at {:#x}\n{:#?}",
+        Exception::DoubleFault.name(),
+        error,
+        stack_frame.instruction_pointer,
+        stack_frame
     );
 }
 
@@ -452,7 +910,8 @@ extern "C" fn raw_general_protection_fault_handler(
     error: ErrorCode,
 ) {
     serial_println!(
-        "EXCEPTION: general protection fault with error code `{:#x}` at {:#x}\n{:#?}",
+        "EXCEPTION: {} with error code `{:#x}` at {:#x}\n{:#?}",
+        Exception::GeneralProtectionFault.name(),
         error,
         stack_frame.instruction_pointer,
         stack_frame
@@ -462,7 +921,8 @@ extern "C" fn raw_general_protection_fault_handler(
 
 extern "C" fn raw_page_fault_handler(stack_frame: &InterruptStackFrame, error: PageFaultErrorCode) {
     serial_println!(
-        "EXCEPTION: page fault with error code `{:#x}` at {:#x}\n{:#?}",
+        "EXCEPTION: {} with error code `{:#x}` at {:#x}\n{:#?}",
+        Exception::PageFault.name(),
         error,
         stack_frame.instruction_pointer,
         stack_frame
@@ -474,9 +934,182 @@ extern "C" fn raw_page_fault_handler(stack_frame: &InterruptStackFrame, error: P
 mod tests {
     use super::*;
     use crate::gdt;
+    use crate::serial_print;
     use crate::serial_println;
     use crate::x86_64;
 
+    #[test_case]
+    fn test_exception_name() {
+        assert!(Exception::PageFault.name().contains("Page"));
+        assert_eq!(exception_name(14), Some(Exception::PageFault.name()));
+        assert_eq!(exception_name(8), Some("Double Fault"));
+        for vector in 0..32 {
+            let expected = matches!(vector, 15 | 21..=28 | 31);
+            assert_eq!(exception_name(vector).is_none(), expected);
+        }
+        assert_eq!(exception_name(32), None);
+        assert_eq!(Exception::try_from(13), Ok(Exception::GeneralProtectionFault));
+    }
+
+    #[test_case]
+    fn test_dump_stack() {
+        use crate::test_util::StrBuf;
+
+        let stack: [u64; 4] = [0x1111, 0x2222, 0x3333, 0x4444];
+        let rsp = VirtAddr::from_ptr(stack.as_ptr());
+        let mut out = StrBuf::<1024>::new();
+        dump_stack(&mut out, rsp).unwrap();
+
+        let mut lines = out.as_str().lines();
+        assert!(lines.next().unwrap().starts_with("Interrupted stack at"));
+        let first = lines.next().unwrap();
+        assert!(first.trim_start().starts_with("0x"));
+        assert!(first.ends_with("0000000000001111"));
+        // Never crosses the page boundary.
+        let page_end = (rsp.0 | 0xfff) + 1;
+        let expected = (page_end.min(rsp.0 + STACK_DUMP_WINDOW) - rsp.0) / 8;
+        assert_eq!(lines.count() as u64 + 1, expected);
+
+        // Nothing is read where the stack pointer is bad.
+        for rsp in [0xdead_beef_0000, 0x8000_0000_0000] {
+            out.clear();
+            dump_stack(&mut out, VirtAddr(rsp)).unwrap();
+            let mut lines = out.as_str().lines();
+            assert!(lines.next().unwrap().starts_with("Interrupted stack at"));
+            assert_eq!(lines.next(), Some("  not mapped"));
+            assert_eq!(lines.next(), None);
+        }
+    }
+
+    #[test_case]
+    fn test_stack_frame_display() {
+        use crate::test_util::StrBuf;
+        use core::fmt::Write;
+
+        let frame = InterruptStackFrameValue {
+            instruction_pointer: VirtAddr(0x20_1234),
+            code_segment: 0x8,
+            cpu_flags: 0x202,
+            stack_pointer: VirtAddr(0x4444_4444_0ff8),
+            stack_segment: 0,
+        };
+        let mut out = StrBuf::<128>::new();
+        write!(out, "{}", frame).unwrap();
+        assert_eq!(
+            out.as_str(),
+            "rip=0x201234 cs=0x8 rflags=0x202 rsp=0x444444440ff8 ss=0x0"
+        );
+    }
+
+    #[test_case]
+    fn test_interrupt_index_round_trip() {
+        for index in InterruptIndex::ALL {
+            assert_eq!(InterruptIndex::try_from(index.as_u8()), Ok(index));
+            assert_eq!(index.as_u8(), PIC_1_OFFSET + index.irq());
+        }
+        assert_eq!(InterruptIndex::try_from(PIC_1_OFFSET + 2), Err(PIC_1_OFFSET + 2));
+        assert_eq!(InterruptIndex::try_from(0), Err(0));
+    }
+
+    #[test_case]
+    fn test_enable_irq() {
+        // Don't hold PICS where an IRQ could come in and wait for it.
+        let is_masked = |irq| x86_64::without_interrupts(|| PICS.lock().is_masked(irq));
+        enable_irq(InterruptIndex::Keyboard);
+        assert!(!is_masked(1));
+
+        let was_masked = is_masked(InterruptIndex::Mouse.irq());
+        disable_irq(InterruptIndex::Mouse);
+        assert!(is_masked(12));
+        enable_irq(InterruptIndex::Mouse);
+        assert!(!is_masked(12));
+        // The cascade line is needed for the secondary PIC.
+        assert!(!is_masked(2));
+        if was_masked {
+            disable_irq(InterruptIndex::Mouse);
+        }
+    }
+
+    #[test_case]
+    fn test_pic_save_restore_masks() {
+        crate::init();
+        crate::x86_64::without_interrupts(|| {
+            let mut pics = PICS.lock();
+            let saved = pics.save_masks();
+            unsafe { pics.disable() };
+            assert_eq!(pics.save_masks(), [u8::MAX; 2]);
+            unsafe { pics.restore_masks(saved) };
+            assert_eq!(pics.save_masks(), saved);
+        });
+    }
+
+    #[test_case]
+    fn test_pic_registers() {
+        crate::init();
+        enable_irq(InterruptIndex::Timer);
+        crate::x86_64::without_interrupts(|| {
+            // Nothing is in service outside of handlers.
+            assert_eq!(PICS.lock().read_isr(), 0);
+            // With interrupts disabled, the next tick stays pending.
+            let mut irr = 0;
+            for _ in 0..10_000_000 {
+                irr = PICS.lock().read_irr();
+                if irr & 1 != 0 {
+                    break;
+                }
+            }
+            assert_ne!(irr & 1, 0, "timer never raised");
+            assert_eq!(PICS.lock().read_isr(), 0);
+            // Reading them leaves the masks alone.
+            assert!(!PICS.lock().is_masked(InterruptIndex::Timer.irq()));
+        });
+    }
+
+    #[test_case]
+    fn test_pic_spurious() {
+        crate::init();
+        crate::x86_64::without_interrupts(|| {
+            let mut pics = PICS.lock();
+            // Outside of handlers nothing is in service, so IRQ 7 and 15
+            // would be spurious.
+            assert!(pics.is_spurious(PIC_1_OFFSET + 7));
+            assert!(pics.is_spurious(PIC_2_OFFSET + 7));
+            assert!(!pics.is_spurious(PIC_1_OFFSET));
+            assert!(!pics.is_spurious(PIC_2_OFFSET + 6));
+        });
+    }
+
+    #[test_case]
+    fn test_nmi_handler_installed() {
+        // Don't raise a real NMI, just make sure one would be handled.
+        let entry = &IDT.non_maskable_interrupt;
+        assert!(entry.options().is_present());
+        assert_ne!(entry.handler_addr(), VirtAddr::zero());
+        assert!(!IDT.overflow.options().is_present());
+    }
+
+    #[test_case]
+    fn test_irq_dispatch() {
+        use core::sync::atomic::AtomicUsize;
+
+        static HITS: AtomicUsize = AtomicUsize::new(0);
+        fn handler() {
+            HITS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        crate::init();
+        // Line 5 is free, it's LPT2 on old machines.
+        assert!(register_irq_handler(5, handler).is_none());
+        // PIC_1_OFFSET + 5
+        unsafe { core::arch::asm!("int 37") };
+        assert_eq!(HITS.load(Ordering::SeqCst), 1);
+
+        let removed = unregister_irq_handler(5).map(|f| f as usize);
+        assert_eq!(removed, Some(handler as IrqHandler as usize));
+        unsafe { core::arch::asm!("int 37") };
+        assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    }
+
     #[test_case]
     fn test_breakpoint_handler() {
         init();
@@ -520,6 +1153,79 @@ mod tests {
     //     serial_println!("No haoye!");
     // }
 
+    #[test_case]
+    fn test_unified_idt() {
+        crate::init();
+        assert!(is_loaded());
+        // Returns only if the breakpoint is handled.
+        x86_64::int3();
+        // The timer keeps ticking through the same IDT.
+        let start = crate::pit::ticks();
+        while crate::pit::ticks() < start + 2 {
+            x86_64::hlt();
+        }
+    }
+
+    #[test_case]
+    fn test_default_handler() {
+        use core::sync::atomic::AtomicU8;
+
+        static VECTOR: AtomicU8 = AtomicU8::new(0);
+        fn record(vector: u8, _stack_frame: &InterruptStackFrame) {
+            VECTOR.store(vector, Ordering::SeqCst);
+        }
+
+        // Get IDT built without the default handlers first.
+        crate::init();
+        set_default_handler(Some(record));
+        lazy_static! {
+            static ref TEST_IDT: InterruptDescriptorTable = build_idt();
+        }
+        with_idt(&TEST_IDT, || {
+            // Nothing is assigned to vector 0x50.
+            unsafe { core::arch::asm!("int 0x50") };
+        });
+        set_default_handler(None);
+        assert_eq!(VECTOR.load(Ordering::SeqCst), 0x50);
+        // Assigned vectors keep their own handlers.
+        assert_eq!(
+            TEST_IDT.breakpoint.handler_addr(),
+            IDT.breakpoint.handler_addr()
+        );
+        assert!(TEST_IDT.overflow.options().is_present());
+        assert!(!IDT.overflow.options().is_present());
+        // Vectors with an error code get one, too.
+        assert!(TEST_IDT.alignment_check.options().is_present());
+        assert!(TEST_IDT.security_exception.options().is_present());
+    }
+
+    #[test_case]
+    fn test_with_idt() {
+        static HIT: AtomicBool = AtomicBool::new(false);
+        extern "x86-interrupt" fn handler(_stack_frame: InterruptStackFrame) {
+            HIT.store(true, Ordering::SeqCst);
+        }
+        lazy_static! {
+            static ref TEST_IDT: InterruptDescriptorTable = {
+                let mut idt = InterruptDescriptorTable::new();
+                idt.breakpoint.set_handler(handler);
+                idt
+            };
+        }
+
+        crate::init();
+        let answer = with_idt(&TEST_IDT, || {
+            // The timer has no gate in the test IDT.
+            assert!(!x86_64::is_interrupt_enabled());
+            x86_64::int3();
+            42
+        });
+        assert_eq!(answer, 42);
+        assert!(HIT.load(Ordering::SeqCst));
+        assert!(is_loaded());
+        assert!(x86_64::is_interrupt_enabled());
+    }
+
     #[test_case]
     fn test_timer_handler() {
         crate::init();
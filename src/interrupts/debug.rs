@@ -0,0 +1,112 @@
+// Single-stepping with the trap flag, for an in-kernel debugger.
+//
+// With RFLAGS.TF set, the CPU raises a debug exception (#DB) after each
+// instruction. The CPU clears TF on entry to a handler and `iretq` puts
+// back the interrupted flags, so stepping is turned on and off by editing
+// the flags in the frame a handler returns to.
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use super::InterruptStackFrame;
+use crate::serial_println;
+
+/// The trap flag in RFLAGS.
+const TRAP_FLAG: u64 = 1 << 8;
+
+/// Called on every step with the frame of the instruction about to run.
+pub type StepHook = fn(&InterruptStackFrame);
+
+static STEPPING: AtomicBool = AtomicBool::new(false);
+static HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Start or stop stepping. It takes effect when the breakpoint or debug
+/// handler returns, so `int3` right after turning it on starts stepping
+/// at the next instruction. It's off by default.
+pub fn single_step(on: bool) {
+    STEPPING.store(on, Ordering::SeqCst);
+}
+
+pub fn is_stepping() -> bool {
+    STEPPING.load(Ordering::SeqCst)
+}
+
+/// Call `hook` on every step, instead of printing the instruction
+/// pointer over serial. It runs in the debug exception handler.
+pub fn set_step_hook(hook: Option<StepHook>) {
+    let ptr = hook.map_or(core::ptr::null_mut(), |hook| hook as *mut ());
+    HOOK.store(ptr, Ordering::Release);
+}
+
+fn hook() -> Option<StepHook> {
+    let ptr = HOOK.load(Ordering::Acquire);
+    if ptr.is_null() {
+        None
+    } else {
+        // Safety: Only `StepHook`s are stored in HOOK.
+        Some(unsafe { core::mem::transmute::<*mut (), StepHook>(ptr) })
+    }
+}
+
+/// Set or clear the trap flag in the frame as `single_step` asked.
+pub(super) fn apply(stack_frame: &mut InterruptStackFrame) {
+    // Safety: Only the trap flag changes, which doesn't affect memory
+    // safety of the interrupted code.
+    let frame = unsafe { stack_frame.as_mut() };
+    let mut flags = frame.cpu_flags;
+    if is_stepping() {
+        flags |= TRAP_FLAG;
+    } else {
+        flags &= !TRAP_FLAG;
+    }
+    // Volatile, since the compiler doesn't know iretq reads it.
+    unsafe { core::ptr::write_volatile(&mut frame.cpu_flags, flags) };
+}
+
+pub(super) extern "x86-interrupt" fn debug_handler(mut stack_frame: InterruptStackFrame) {
+    match hook() {
+        Some(hook) => hook(&stack_frame),
+        None => serial_println!("STEP: {:#x}", stack_frame.instruction_pointer),
+    }
+    apply(&mut stack_frame);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_64::{self, VirtAddr};
+    use core::sync::atomic::AtomicU64;
+
+    static STEPS: AtomicU64 = AtomicU64::new(0);
+    static LAST_RIP: AtomicU64 = AtomicU64::new(0);
+
+    fn record(stack_frame: &InterruptStackFrame) {
+        STEPS.fetch_add(1, Ordering::SeqCst);
+        LAST_RIP.store(stack_frame.instruction_pointer.0, Ordering::SeqCst);
+        // Just one step.
+        single_step(false);
+    }
+
+    #[test_case]
+    fn test_single_step() {
+        crate::init();
+        set_step_hook(Some(record));
+        let after_nop: u64;
+        single_step(true);
+        unsafe {
+            core::arch::asm!(
+                "int3",
+                "nop",
+                "2:",
+                "lea {}, [rip + 2b]",
+                out(reg) after_nop,
+            );
+        }
+        set_step_hook(None);
+
+        assert!(!is_stepping());
+        assert_eq!(STEPS.load(Ordering::SeqCst), 1);
+        // The trap comes after the nop, the first instruction after int3.
+        assert_eq!(VirtAddr(LAST_RIP.load(Ordering::SeqCst)), VirtAddr(after_nop));
+        assert_eq!(x86_64::read_rflags() & TRAP_FLAG, 0);
+    }
+}
@@ -1,20 +1,13 @@
+use core::fmt;
 use core::marker::PhantomData;
 use core::ops::{ Index, IndexMut };
 use crate::bit_field::BitField;
-use crate::gdt;
-use crate::lazy_static;
-use crate::raw_handler;
-use crate::raw_page_fault_handler;
-use crate::raw_handler_with_error_code;
-use crate::print;
-use crate::serial_print;
-use crate::serial_println;
 use crate::x86_64::{
     lidt, DescriptorTablePointer,
     SegmentSelector, VirtAddr, CS,
 };
 use super::{
-    ErrorCode, PageFaultErrorCode, InterruptStackFrame,
+    Exception, RawHandler,
     HandlerFunc, HandlerFuncWithErrorCode, PageFaultHandlerFunc, 
     DivergingHandlerFunc, DivergingHandlerFuncWithErrorCode,
     RawHandlerFunc, RawHandlerFuncWithErrorCode, RawPageFaultHandlerFunc,
@@ -89,6 +82,90 @@ impl InterruptDescriptorTable {
         }
     }
 
+    /// Set the handler of an exception without error code.
+    /// # Panics
+    /// Panics if the exception pushes an error code or must not return,
+    /// see `set_double_fault_handler` for the latter.
+    pub fn set_handler(&mut self, exception: Exception, handler: HandlerFunc) -> &mut EntryOptions {
+        self[exception as usize].set_handler(handler)
+    }
+
+    /// Set the raw handler of an exception without error code.
+    /// # Panics
+    /// Panics if the exception pushes an error code or must not return,
+    /// see `set_double_fault_handler` for the latter.
+    pub fn set_raw_handler(
+        &mut self,
+        exception: Exception,
+        handler: RawHandler<RawHandlerFunc>,
+    ) -> &mut EntryOptions {
+        self[exception as usize].set_raw_handler(handler)
+    }
+
+    /// Set the raw handler of an exception with error code.
+    /// # Panics
+    /// Panics if the exception doesn't push an error code or must not
+    /// return, see `set_double_fault_handler` for the latter.
+    pub fn set_raw_handler_with_error_code(
+        &mut self,
+        exception: Exception,
+        handler: RawHandler<RawHandlerFuncWithErrorCode>,
+    ) -> &mut EntryOptions {
+        self.entry_with_error_code(exception).set_raw_handler(handler)
+    }
+
+    pub fn set_page_fault_handler(&mut self, handler: PageFaultHandlerFunc) -> &mut EntryOptions {
+        self.page_fault.set_handler(handler)
+    }
+
+    /// Set the double fault handler. It has a setter of its own, since it
+    /// must not return, which the `Exception` based setters can't check.
+    pub fn set_double_fault_handler(
+        &mut self,
+        handler: DivergingHandlerFuncWithErrorCode,
+    ) -> &mut EntryOptions {
+        self.double_fault.set_handler(handler)
+    }
+
+    fn entry_with_error_code(&mut self, exception: Exception) -> &mut Entry<HandlerFuncWithErrorCode> {
+        match exception {
+            Exception::InvalidTss => &mut self.invalid_tss,
+            Exception::SegmentNotPresent => &mut self.segment_not_present,
+            Exception::StackSegmentFault => &mut self.stack_segment_fault,
+            Exception::GeneralProtectionFault => &mut self.general_protection_fault,
+            Exception::AlignmentCheck => &mut self.alignment_check,
+            Exception::VmmCommunicationException => &mut self.vmm_communication_exception,
+            Exception::SecurityException => &mut self.security_exception,
+            e @ Exception::DoubleFault => panic!(
+                "{:?} is an diverging exception (must not return), see `set_double_fault_handler`",
+                e
+            ),
+            e @ Exception::PageFault => panic!("{:?} has a page fault error code", e),
+            e => panic!("{:?} is an exception without error code", e),
+        }
+    }
+
+    /// Set the raw handler of any vector, if it has none yet, whatever
+    /// the entry's handler type. For defaults that cover every vector,
+    /// reserved ones included.
+    ///
+    /// Safety:
+    /// * handler must take an error code exactly if the vector pushes one.
+    pub(crate) unsafe fn set_missing_raw_handler<F: HandlerFn>(
+        &mut self,
+        vector: u8,
+        handler: RawHandler<F>,
+    ) {
+        // Safety: The table is the 256 entries in order, and they have the
+        // same layout whatever their handler type.
+        let entries = unsafe { &mut *(self as *mut Self as *mut [Entry<HandlerFunc>; 256]) };
+        let entry = &mut entries[vector as usize];
+        if !entry.options().is_present() {
+            // Safety: Guaranteed by the caller.
+            unsafe { entry.set_handler_addr(VirtAddr(handler.handler as u64)) };
+        }
+    }
+
     pub fn load(&'static self) {
         let ptr = DescriptorTablePointer {
             limit: (core::mem::size_of::<Self>() - 1) as u16,
@@ -190,6 +267,34 @@ impl<F: HandlerFn> Entry<F> {
         &mut self.options
     }
 
+    pub fn options(&self) -> EntryOptions {
+        self.options
+    }
+
+    /// Run the handler in the code segment of `selector` instead of the
+    /// current CS, which setting a handler picks by default. Set the
+    /// handler first, since it resets the segment.
+    ///
+    /// Safety:
+    /// * selector must point to a valid code segment in the GDT.
+    pub unsafe fn set_segment(&mut self, selector: SegmentSelector) -> &mut Self {
+        self.gdt_selector = selector;
+        self
+    }
+
+    pub fn segment(&self) -> SegmentSelector {
+        self.gdt_selector
+    }
+
+    /// Address of the installed handler, or zero if none.
+    pub fn handler_addr(&self) -> VirtAddr {
+        VirtAddr(
+            self.pointer_low as u64
+                | (self.pointer_middle as u64) << 16
+                | (self.pointer_high as u64) << 32,
+        )
+    }
+
     fn missing() -> Self {
         Entry {
             pointer_low: 0,
@@ -244,7 +349,7 @@ impl_set_handler!{
     RawPageFaultHandlerFunc,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct EntryOptions(u16);
 
@@ -270,12 +375,143 @@ impl EntryOptions {
         self
     }
 
+    /// Switch to the stack of `interrupt_stack_table[index]` in the TSS,
+    /// so index is in 0..7, see `gdt::IST_COUNT`.
+    ///
     /// Safety:
     /// * stack index is a valid and not used by other interrupts.
+    ///
+    /// # Panics
+    /// Panics if index is 7 or above, since the TSS has only 7 slots.
     pub unsafe fn set_stack_index(&mut self, index: u16) -> &mut Self {
+        assert!(index < 7, "IST index {} out of range 0..7", index);
         // The hardware IST index starts at 1, but our software IST index
         // starts at 0. Therefore we need to add 1 here.
         self.0.set_bits(0..=2, index + 1);
         self
     }
+
+    pub fn is_present(&self) -> bool {
+        self.0.get_bits(15) == 1
+    }
+
+    pub fn interrupts_disabled(&self) -> bool {
+        self.0.get_bits(8) == 0
+    }
+
+    pub fn privilege_level(&self) -> u16 {
+        self.0.get_bits(13..=14)
+    }
+
+    /// The software IST index, or None if no stack switch.
+    pub fn stack_index(&self) -> Option<u16> {
+        self.0.get_bits(0..=2).checked_sub(1)
+    }
+}
+
+impl fmt::Debug for EntryOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EntryOptions")
+            .field("present", &self.is_present())
+            .field("interrupts_disabled", &self.interrupts_disabled())
+            .field("privilege_level", &self.privilege_level())
+            .field("stack_index", &self.stack_index())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::InterruptStackFrame;
+    use crate::lazy_static;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static BREAKPOINT_HIT: AtomicBool = AtomicBool::new(false);
+
+    extern "x86-interrupt" fn test_breakpoint_handler(_stack_frame: InterruptStackFrame) {
+        BREAKPOINT_HIT.store(true, Ordering::SeqCst);
+    }
+
+    lazy_static! {
+        static ref TEST_IDT: InterruptDescriptorTable = {
+            let mut idt = InterruptDescriptorTable::new();
+            idt.set_handler(Exception::BreakPoint, test_breakpoint_handler);
+            idt
+        };
+    }
+
+    #[test_case]
+    fn test_set_handler_by_exception() {
+        super::super::with_idt(&TEST_IDT, crate::x86_64::int3);
+        assert!(BREAKPOINT_HIT.load(Ordering::SeqCst));
+    }
+
+    extern "x86-interrupt" fn test_double_fault_handler(
+        _stack_frame: InterruptStackFrame,
+        _error_code: crate::interrupts::ErrorCode,
+    ) -> ! {
+        panic!("unexpected double fault");
+    }
+
+    #[test_case]
+    fn test_set_double_fault_handler() {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.set_double_fault_handler(test_double_fault_handler);
+        let handler = test_double_fault_handler
+            as extern "x86-interrupt" fn(InterruptStackFrame, crate::interrupts::ErrorCode) -> !;
+        assert_eq!(idt.double_fault.handler_addr(), VirtAddr(handler as u64));
+        assert!(idt.double_fault.options().is_present());
+    }
+
+    #[test_case]
+    fn test_set_segment() {
+        use crate::x86_64::PrivilegeLevel;
+
+        let mut idt = InterruptDescriptorTable::new();
+        idt.breakpoint.set_handler(test_breakpoint_handler);
+        assert_eq!(idt.breakpoint.gdt_selector, CS::get_reg());
+
+        let selector = SegmentSelector::new(3, PrivilegeLevel::Ring0);
+        unsafe {
+            idt.breakpoint.set_segment(selector);
+        }
+        assert_eq!(idt.breakpoint.gdt_selector, selector);
+        assert_eq!(idt.breakpoint.segment(), selector);
+        assert!(idt.breakpoint.options().is_present());
+    }
+
+    #[test_case]
+    fn test_entry_options_getters() {
+        let mut options = EntryOptions::minimal();
+        assert!(!options.is_present());
+        assert!(options.interrupts_disabled());
+        assert_eq!(options.stack_index(), None);
+
+        options.set_present(true).set_privilege_level(3);
+        unsafe {
+            options.set_stack_index(2);
+        }
+        assert!(options.is_present());
+        assert_eq!(options.privilege_level(), 3);
+        assert_eq!(options.stack_index(), Some(2));
+
+        options.disable_interrupts(false);
+        assert!(!options.interrupts_disabled());
+    }
+
+    #[test_case]
+    fn test_set_stack_index_bits() {
+        let mut options = EntryOptions::minimal();
+        unsafe {
+            options.set_stack_index(0);
+        }
+        assert_eq!(options.0.get_bits(0..=2), 1);
+        unsafe {
+            options.set_stack_index(6);
+        }
+        assert_eq!(options.0.get_bits(0..=2), 7);
+        // Nothing else is touched.
+        assert_eq!(options.0 & !0b111, EntryOptions::minimal().0);
+    }
 }
@@ -0,0 +1,163 @@
+// Non-maskable interrupts, e.g. from a hardware watchdog or a memory
+// parity error.
+// See https://wiki.osdev.org/Non_Maskable_Interrupt
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use super::InterruptStackFrame;
+use crate::port::{Port, PortRead, PortWrite};
+use crate::serial::{self, SERIAL1};
+
+/// System control port B.
+const SYSTEM_CONTROL_B: u16 = 0x61;
+/// Read only. Set on a memory parity error.
+const PARITY_ERROR: u8 = 1 << 7;
+/// Read only. Set on an I/O channel check, e.g. from an expansion card.
+const IO_CHANNEL_CHECK: u8 = 1 << 6;
+/// Setting these clears and disables the matching source, clearing them
+/// enables it again.
+const PARITY_CHECK_DISABLE: u8 = 1 << 2;
+const IO_CHANNEL_CHECK_DISABLE: u8 = 1 << 3;
+/// The writable bits. The low two drive the speaker, so keep them.
+const WRITABLE_BITS: u8 = 0x0f;
+
+/// What raised the NMI, as reported by system control port B. Both may be
+/// false, e.g. for a watchdog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NmiReason {
+    pub parity_error: bool,
+    pub io_channel_check: bool,
+}
+
+impl NmiReason {
+    pub fn from_status(status: u8) -> Self {
+        Self {
+            parity_error: status & PARITY_ERROR != 0,
+            io_channel_check: status & IO_CHANNEL_CHECK != 0,
+        }
+    }
+}
+
+pub type NmiHook = fn(NmiReason);
+
+static HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+static IN_NMI: AtomicBool = AtomicBool::new(false);
+
+/// Call `hook` on every NMI, after it's been logged. It runs in NMI
+/// context, so it must not take any lock that might be held.
+pub fn set_hook(hook: Option<NmiHook>) {
+    let ptr = hook.map_or(core::ptr::null_mut(), |hook| hook as *mut ());
+    HOOK.store(ptr, Ordering::Release);
+}
+
+fn hook() -> Option<NmiHook> {
+    let ptr = HOOK.load(Ordering::Acquire);
+    if ptr.is_null() {
+        None
+    } else {
+        // Safety: Only `NmiHook`s are stored in HOOK.
+        Some(unsafe { core::mem::transmute::<*mut (), NmiHook>(ptr) })
+    }
+}
+
+/// Read the reason and acknowledge it, so the same source can fire again.
+fn take_reason() -> NmiReason {
+    let mut port: Port<u8> = Port::new(SYSTEM_CONTROL_B);
+    // Safety: It's the standard system control port.
+    unsafe {
+        let status: u8 = port.read();
+        let reason = NmiReason::from_status(status);
+        let mask = match reason {
+            NmiReason {
+                parity_error: true,
+                io_channel_check: true,
+            } => PARITY_CHECK_DISABLE | IO_CHANNEL_CHECK_DISABLE,
+            NmiReason {
+                parity_error: true, ..
+            } => PARITY_CHECK_DISABLE,
+            NmiReason {
+                io_channel_check: true,
+                ..
+            } => IO_CHANNEL_CHECK_DISABLE,
+            _ => 0,
+        };
+        if mask != 0 {
+            let bits = status & WRITABLE_BITS;
+            port.write(bits | mask);
+            port.write(bits & !mask);
+        }
+        reason
+    }
+}
+
+pub(super) extern "C" fn raw_nmi_handler(stack_frame: &InterruptStackFrame) {
+    // The CPU holds off further NMIs until the next iret, but a fault in
+    // here ends with an iret too and lets the next one in. Don't go around
+    // again in that case.
+    if IN_NMI.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    let reason = take_reason();
+    // The NMI may have hit while the serial port is locked, so never wait
+    // for it.
+    if serial::is_present() {
+        if let Some(mut serial) = SERIAL1.try_lock() {
+            let _ = writeln!(
+                serial,
+                "NMI: {:?} at {:#x}",
+                reason, stack_frame.instruction_pointer
+            );
+        }
+    }
+    if let Some(hook) = hook() {
+        hook(reason);
+    }
+
+    IN_NMI.store(false, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_nmi_reason() {
+        assert_eq!(
+            NmiReason::from_status(0x80),
+            NmiReason {
+                parity_error: true,
+                io_channel_check: false
+            }
+        );
+        assert_eq!(
+            NmiReason::from_status(0x43),
+            NmiReason {
+                parity_error: false,
+                io_channel_check: true
+            }
+        );
+        assert_eq!(
+            NmiReason::from_status(0x0f),
+            NmiReason {
+                parity_error: false,
+                io_channel_check: false
+            }
+        );
+    }
+
+    #[test_case]
+    fn test_nmi_hook() {
+        fn hook(_: NmiReason) {}
+
+        assert!(super::hook().is_none());
+        set_hook(Some(hook));
+        assert_eq!(
+            super::hook().map(|f| f as usize),
+            Some(hook as NmiHook as usize)
+        );
+        set_hook(None);
+        assert!(super::hook().is_none());
+    }
+}
@@ -0,0 +1,144 @@
+// Reading memory that may not be there, e.g. to find out whether an MMIO
+// region is present, without bringing the kernel down.
+//
+// The read is a single instruction of known length. If it faults, the page
+// fault or #GP handler sees it's the probe, notes the failure and returns
+// past it instead of reporting the fault.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::{ErrorCode, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use crate::gdt::{GENERAL_PROTECTION_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX};
+use crate::lazy_static;
+use crate::x86_64::{self, without_interrupts, VirtAddr};
+
+/// Length of the probe instruction, `mov al, byte ptr [rdi]`.
+const PROBE_LEN: u64 = 2;
+
+/// Whether the last probe faulted.
+static FAULTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref PROBE_IDT: InterruptDescriptorTable = {
+        let mut idt = super::build_idt();
+        // Safety: The same stacks as the kernel's handlers, see `build_idt`.
+        // Unlike those, these return, so a nested fault would overwrite
+        // the frame they return with. They must not fault: `recover` only
+        // compares and writes the frame, and anything but the probe goes
+        // to the kernel's handler, which never returns.
+        unsafe {
+            idt.general_protection_fault
+                .set_handler(general_protection_fault_handler)
+                .set_stack_index(GENERAL_PROTECTION_FAULT_IST_INDEX);
+            idt.page_fault
+                .set_handler(page_fault_handler)
+                .set_stack_index(PAGE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+/// Read the byte at addr. The first instruction may fault, so it must be
+/// `PROBE_LEN` bytes long.
+///
+/// Safety:
+/// * The probe handler must be installed.
+#[naked]
+unsafe extern "C" fn read_byte(addr: u64) -> u8 {
+    unsafe {
+        ::core::arch::asm!(
+            "mov al, byte ptr [rdi]",
+            "ret",
+            options(noreturn)
+        )
+    }
+}
+
+/// Skip the probe instruction if it's the one that faulted.
+fn recover(stack_frame: &mut InterruptStackFrame) -> bool {
+    let probe = read_byte as unsafe extern "C" fn(u64) -> u8 as u64;
+    if stack_frame.instruction_pointer != VirtAddr(probe) {
+        return false;
+    }
+    FAULTED.store(true, Ordering::Relaxed);
+    // Safety: The probe returns normally from the next instruction.
+    let frame = unsafe { stack_frame.as_mut() };
+    // Volatile, since the compiler doesn't know iretq reads it.
+    let resume = VirtAddr(probe + PROBE_LEN);
+    unsafe { core::ptr::write_volatile(&mut frame.instruction_pointer, resume) };
+    true
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error: PageFaultErrorCode,
+) {
+    if !recover(&mut stack_frame) {
+        super::raw_page_fault_handler(&stack_frame, error);
+    }
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error: ErrorCode,
+) {
+    if !recover(&mut stack_frame) {
+        super::raw_general_protection_fault_handler(&stack_frame, error);
+    }
+}
+
+/// Load the kernel's IDT, but with page fault and #GP handlers that let
+/// `probe_read` recover. Other faults are reported as usual. It lasts
+/// until `interrupts::init` loads the plain IDT again.
+pub fn install_probe_handler() {
+    PROBE_IDT.load();
+}
+
+pub fn is_probe_handler_installed() -> bool {
+    // Copy out of the packed struct.
+    let base = { x86_64::sidt().base };
+    base == VirtAddr::from_ptr(&*PROBE_IDT)
+}
+
+/// Read the byte at `addr`, or None if that faults, e.g. if it isn't
+/// mapped. Mind that reading some device registers has side effects.
+/// # Panics
+/// Panics if the probe handler isn't installed, see
+/// `install_probe_handler`.
+pub fn probe_read(addr: VirtAddr) -> Option<u8> {
+    assert!(is_probe_handler_installed(), "probe handler isn't installed");
+    without_interrupts(|| {
+        FAULTED.store(false, Ordering::Relaxed);
+        // Safety: The handler is installed.
+        let byte = unsafe { read_byte(addr.0) };
+        if FAULTED.load(Ordering::Relaxed) {
+            None
+        } else {
+            Some(byte)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_probe_read() {
+        static VALUE: u8 = 0x5a;
+
+        // For the IST stacks.
+        crate::init();
+        install_probe_handler();
+        assert_eq!(probe_read(VirtAddr::from_ptr(&VALUE)), Some(0x5a));
+        // Unmapped, so it's a page fault.
+        assert_eq!(probe_read(VirtAddr(0xdead_beef_0000)), None);
+        // Not canonical, so it's a #GP.
+        assert_eq!(probe_read(VirtAddr(0x8000_0000_0000)), None);
+        assert_eq!(probe_read(VirtAddr::from_ptr(&VALUE)), Some(0x5a));
+
+        // Back to the plain IDT.
+        super::super::IDT.load();
+        assert!(!is_probe_handler_installed());
+    }
+}
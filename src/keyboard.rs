@@ -0,0 +1,619 @@
+// PS/2 keyboard decoding on top of pc_keyboard.
+
+use pc_keyboard::{
+    layouts, HandleControl, KeyEvent, KeyState, Keyboard, ScancodeSet1, ScancodeSet2,
+};
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::lazy_static;
+use crate::port::{Port, PortRead, PortWrite};
+use crate::spinlock::SpinLock;
+use crate::x86_64;
+
+pub use pc_keyboard::{DecodedKey, KeyCode};
+
+/// The scancode set the keyboard bytes are decoded with.
+///
+/// The PS/2 controller translates set 2 to set 1 by default, which is why
+/// we start with set 1. Only pick set 2 if the translation is turned off in
+/// the controller (or the device sends set 2 anyway, like some emulated
+/// ones), otherwise every key decodes as garbage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+/// pc_keyboard picks the set by a type parameter, so we need one variant
+/// for each set to switch at runtime.
+enum AnyKeyboard {
+    Set1(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Set2(Keyboard<layouts::Us104Key, ScancodeSet2>),
+}
+
+impl AnyKeyboard {
+    fn new(set: ScancodeSet) -> Self {
+        match set {
+            ScancodeSet::Set1 => AnyKeyboard::Set1(Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet1,
+                HandleControl::Ignore,
+            )),
+            ScancodeSet::Set2 => AnyKeyboard::Set2(Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet2,
+                HandleControl::Ignore,
+            )),
+        }
+    }
+
+    fn scancode_set(&self) -> ScancodeSet {
+        match self {
+            AnyKeyboard::Set1(_) => ScancodeSet::Set1,
+            AnyKeyboard::Set2(_) => ScancodeSet::Set2,
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        let event = match self {
+            AnyKeyboard::Set1(keyboard) => keyboard.add_byte(byte),
+            AnyKeyboard::Set2(keyboard) => keyboard.add_byte(byte),
+        };
+        event.ok().flatten()
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            AnyKeyboard::Set1(keyboard) => keyboard.process_keyevent(event),
+            AnyKeyboard::Set2(keyboard) => keyboard.process_keyevent(event),
+        }
+    }
+}
+
+/// State of the modifier and lock keys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    /// Track the key event. Returns true if a lock key toggles.
+    fn update(&mut self, event: &KeyEvent) -> bool {
+        let down = event.state == KeyState::Down;
+        match event.code {
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => self.shift = down,
+            KeyCode::ControlLeft | KeyCode::ControlRight => self.ctrl = down,
+            KeyCode::AltLeft | KeyCode::AltRight => self.alt = down,
+            // Toggle on every press, the same as pc_keyboard does for
+            // caps lock, so that the LEDs agree with the decoded keys.
+            KeyCode::CapsLock if down => {
+                self.caps_lock = !self.caps_lock;
+                return true;
+            }
+            KeyCode::NumpadLock if down => {
+                self.num_lock = !self.num_lock;
+                return true;
+            }
+            KeyCode::ScrollLock if down => {
+                self.scroll_lock = !self.scroll_lock;
+                return true;
+            }
+            _ => (),
+        }
+        false
+    }
+}
+
+struct KeyboardState {
+    decoder: AnyKeyboard,
+    modifiers: Modifiers,
+}
+
+impl KeyboardState {
+    fn new(set: ScancodeSet) -> Self {
+        Self {
+            decoder: AnyKeyboard::new(set),
+            modifiers: Modifiers::default(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref KEYBOARD: SpinLock<KeyboardState> =
+        SpinLock::new(KeyboardState::new(ScancodeSet::Set1));
+}
+
+/// Number of keys buffered before new ones get dropped.
+const QUEUE_CAPACITY: usize = 64;
+
+/// Keys decoded by the interrupt handler and waiting to be read.
+struct KeyQueue {
+    keys: [DecodedKey; QUEUE_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl KeyQueue {
+    const fn new() -> Self {
+        Self {
+            keys: [DecodedKey::Unicode('\0'); QUEUE_CAPACITY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns false if the queue is full and the key is dropped.
+    fn push(&mut self, key: DecodedKey) -> bool {
+        if self.len == QUEUE_CAPACITY {
+            return false;
+        }
+        self.keys[(self.head + self.len) % QUEUE_CAPACITY] = key;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<DecodedKey> {
+        if self.len == 0 {
+            return None;
+        }
+        let key = self.keys[self.head];
+        self.head = (self.head + 1) % QUEUE_CAPACITY;
+        self.len -= 1;
+        Some(key)
+    }
+}
+
+static QUEUE: SpinLock<KeyQueue> = SpinLock::new(KeyQueue::new());
+
+/// Repeat a held key in software, counted in timer ticks.
+struct Typematic {
+    /// Ticks from the press to the first repeat.
+    delay: u64,
+    /// Ticks between repeats. Zero turns repeating off.
+    interval: u64,
+    /// The key being held, and when to repeat it next.
+    held: Option<(KeyCode, DecodedKey, u64)>,
+}
+
+impl Typematic {
+    const fn new() -> Self {
+        Self {
+            delay: 0,
+            interval: 0,
+            held: None,
+        }
+    }
+
+    /// Start repeating `key` at `now`. Returns false if it's the key held
+    /// already, i.e. the keyboard's own repeat, which we replace.
+    fn press(&mut self, code: KeyCode, key: DecodedKey, now: u64) -> bool {
+        if self.interval == 0 {
+            return true;
+        }
+        if matches!(self.held, Some((held, _, _)) if held == code) {
+            return false;
+        }
+        self.held = Some((code, key, now + self.delay));
+        true
+    }
+
+    fn release(&mut self, code: KeyCode) {
+        if matches!(self.held, Some((held, _, _)) if held == code) {
+            self.held = None;
+        }
+    }
+
+    /// Push the held key to `queue` for every repeat due by `now`.
+    fn tick(&mut self, now: u64, queue: &mut KeyQueue) {
+        let Some((code, key, mut next)) = self.held else {
+            return;
+        };
+        while next <= now {
+            // Drop the key if nobody reads them.
+            queue.push(key);
+            next += self.interval;
+        }
+        self.held = Some((code, key, next));
+    }
+}
+
+static TYPEMATIC: SpinLock<Typematic> = SpinLock::new({
+    let mut typematic = Typematic::new();
+    // The same as a keyboard after reset, 500ms and ~10Hz.
+    typematic.delay = crate::pit::ms_to_ticks(500);
+    typematic.interval = crate::pit::ms_to_ticks(100);
+    typematic
+});
+
+/// Repeat a held key after `delay_ms`, `rate_hz` times a second, with a
+/// resolution of one timer tick. A rate of 0 turns repeating off and
+/// leaves it to the keyboard.
+pub fn set_repeat(delay_ms: u64, rate_hz: u64) {
+    let interval = match rate_hz {
+        0 => 0,
+        rate => crate::pit::ms_to_ticks(1000 / rate).max(1),
+    };
+    x86_64::without_interrupts(|| {
+        TYPEMATIC.with(|typematic| {
+            typematic.delay = crate::pit::ms_to_ticks(delay_ms);
+            typematic.interval = interval;
+            typematic.held = None;
+        })
+    });
+}
+
+/// Called by the timer interrupt handler.
+pub(crate) fn tick_repeat() {
+    let now = crate::pit::ticks();
+    TYPEMATIC.with(|typematic| QUEUE.with(|queue| typematic.tick(now, queue)));
+}
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+/// Set in the status register when there's a byte to read.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Set in the status register when the controller isn't ready for a byte.
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CMD_SET_LEDS: u8 = 0xed;
+const RESPONSE_ACK: u8 = 0xfa;
+const RESPONSE_RESEND: u8 = 0xfe;
+const MAX_RESENDS: usize = 3;
+/// Number of status polls before we give up on the controller.
+const POLL_LIMIT: usize = 100_000;
+
+/// Byte-level access to the keyboard behind the PS/2 controller. It allows
+/// the command protocol to be tested with a stub.
+pub trait Ps2Controller {
+    /// Send a byte to the keyboard. Returns false if the controller isn't
+    /// ready in time.
+    fn write(&mut self, byte: u8) -> bool;
+    /// Receive a byte from the keyboard, or None if nothing comes in time.
+    fn read(&mut self) -> Option<u8>;
+}
+
+/// The real PS/2 controller at the standard ports.
+pub struct Ps2Port;
+
+impl Ps2Port {
+    fn wait_status(&mut self, mask: u8, set: bool) -> bool {
+        let mut status: Port<u8> = Port::new(STATUS_PORT);
+        (0..POLL_LIMIT).any(|_| {
+            // Safety: This is the status register of the PS/2 controller.
+            let value: u8 = unsafe { status.read() };
+            (value & mask != 0) == set
+        })
+    }
+}
+
+impl Ps2Controller for Ps2Port {
+    fn write(&mut self, byte: u8) -> bool {
+        if !self.wait_status(STATUS_INPUT_FULL, false) {
+            return false;
+        }
+        // Safety: The controller is ready for the byte.
+        unsafe { Port::new(DATA_PORT).write(byte) };
+        true
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        if !self.wait_status(STATUS_OUTPUT_FULL, true) {
+            return None;
+        }
+        // Safety: There's a byte from the keyboard.
+        Some(unsafe { Port::new(DATA_PORT).read() })
+    }
+}
+
+/// The keyboard didn't acknowledge a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoAck;
+
+/// Send a byte and wait for the ACK, resending if the keyboard asks to.
+fn send<C: Ps2Controller>(controller: &mut C, byte: u8) -> Result<(), NoAck> {
+    for _ in 0..MAX_RESENDS {
+        if !controller.write(byte) {
+            return Err(NoAck);
+        }
+        match controller.read() {
+            Some(RESPONSE_ACK) => return Ok(()),
+            Some(RESPONSE_RESEND) => continue,
+            _ => return Err(NoAck),
+        }
+    }
+    Err(NoAck)
+}
+
+/// Turn the keyboard LEDs on or off through `controller`.
+pub fn set_leds_with<C: Ps2Controller>(
+    controller: &mut C,
+    caps: bool,
+    num: bool,
+    scroll: bool,
+) -> Result<(), NoAck> {
+    let leds = (caps as u8) << 2 | (num as u8) << 1 | scroll as u8;
+    send(controller, CMD_SET_LEDS)?;
+    send(controller, leds)
+}
+
+/// Turn the keyboard LEDs on or off. It polls for the ACKs, so it's
+/// meant to be called with interrupts disabled, otherwise the keyboard
+/// handler may take them.
+pub fn set_leds(caps: bool, num: bool, scroll: bool) -> Result<(), NoAck> {
+    set_leds_with(&mut Ps2Port, caps, num, scroll)
+}
+
+pub fn modifiers() -> Modifiers {
+    x86_64::without_interrupts(|| KEYBOARD.lock().modifiers)
+}
+
+/// Decode the following scancodes with `set`. The decoding state,
+/// including the modifiers, starts over.
+pub fn set_scancode_set(set: ScancodeSet) {
+    // The keyboard handler also takes the lock.
+    x86_64::without_interrupts(|| {
+        *KEYBOARD.lock() = KeyboardState::new(set);
+    });
+}
+
+pub fn scancode_set() -> ScancodeSet {
+    x86_64::without_interrupts(|| KEYBOARD.lock().decoder.scancode_set())
+}
+
+/// Take the next key from the queue without blocking.
+pub fn poll() -> Option<DecodedKey> {
+    // The keyboard handler pushes to the queue.
+    x86_64::without_interrupts(|| QUEUE.lock().pop())
+}
+
+/// Wait for the next key.
+/// # Panics
+/// Panics if interrupts are disabled, since no key would ever come.
+pub fn read() -> DecodedKey {
+    assert!(
+        x86_64::is_interrupt_enabled(),
+        "read with interrupts disabled"
+    );
+    loop {
+        // Poll with interrupts off, so the key can't arrive after the
+        // poll but before the halt.
+        x86_64::disable_interrupt();
+        if let Some(key) = QUEUE.lock().pop() {
+            x86_64::enable_interrupt();
+            return key;
+        }
+        x86_64::enable_and_hlt();
+    }
+}
+
+/// Called on every key press with the modifiers held at the time, e.g.
+/// to catch shortcuts.
+pub type KeyHook = fn(KeyCode, Modifiers);
+
+static KEY_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Call `hook` on every key press, including modifier keys. It runs in
+/// the keyboard interrupt handler.
+pub fn set_key_hook(hook: Option<KeyHook>) {
+    let ptr = hook.map_or(core::ptr::null_mut(), |hook| hook as *mut ());
+    KEY_HOOK.store(ptr, Ordering::Release);
+}
+
+fn key_hook() -> Option<KeyHook> {
+    let ptr = KEY_HOOK.load(Ordering::Acquire);
+    if ptr.is_null() {
+        None
+    } else {
+        // Safety: Only `KeyHook`s are stored in KEY_HOOK.
+        Some(unsafe { core::mem::transmute::<*mut (), KeyHook>(ptr) })
+    }
+}
+
+/// Feed a byte read from the keyboard. Returns the key if the byte
+/// completes a key press, and queues it for `poll` and `read`.
+pub fn handle_scancode(scancode: u8) -> Option<DecodedKey> {
+    // Responses to our commands, not keys.
+    if scancode == RESPONSE_ACK || scancode == RESPONSE_RESEND {
+        return None;
+    }
+    let (event, modifiers, key) = x86_64::without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+        let event = keyboard.decoder.add_byte(scancode)?;
+        if keyboard.modifiers.update(&event) {
+            let m = keyboard.modifiers;
+            // Nothing to do if the keyboard doesn't respond. The LEDs
+            // are only cosmetic.
+            let _ = set_leds(m.caps_lock, m.num_lock, m.scroll_lock);
+        }
+        let modifiers = keyboard.modifiers;
+        let mut key = keyboard.decoder.process_keyevent(event.clone());
+        let mut typematic = TYPEMATIC.lock();
+        match (key, event.state) {
+            (Some(decoded), KeyState::Down) => {
+                if !typematic.press(event.code, decoded, crate::pit::ticks()) {
+                    key = None;
+                }
+            }
+            _ => typematic.release(event.code),
+        }
+        if let Some(key) = key {
+            // Drop the key if nobody reads them.
+            QUEUE.lock().push(key);
+        }
+        Some((event, modifiers, key))
+    })?;
+    // Without the locks, so the hook may use the keyboard.
+    if event.state == KeyState::Down {
+        if let Some(hook) = key_hook() {
+            hook(event.code, modifiers);
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_scancode_set2() {
+        let original = scancode_set();
+
+        set_scancode_set(ScancodeSet::Set2);
+        assert_eq!(scancode_set(), ScancodeSet::Set2);
+        // Make code of `A` in set 2, then its break code.
+        assert_eq!(handle_scancode(0x1c), Some(DecodedKey::Unicode('a')));
+        assert_eq!(handle_scancode(0xf0), None);
+        assert_eq!(handle_scancode(0x1c), None);
+
+        set_scancode_set(ScancodeSet::Set1);
+        // `A` is 0x1e in set 1.
+        assert_eq!(handle_scancode(0x1e), Some(DecodedKey::Unicode('a')));
+        assert_eq!(handle_scancode(0x9e), None);
+
+        set_scancode_set(original);
+    }
+
+    /// Replies to each byte with the next response, and records what's sent.
+    struct StubController {
+        sent: [u8; 8],
+        len: usize,
+        responses: &'static [u8],
+    }
+
+    impl StubController {
+        fn new(responses: &'static [u8]) -> Self {
+            Self {
+                sent: [0; 8],
+                len: 0,
+                responses,
+            }
+        }
+    }
+
+    impl Ps2Controller for StubController {
+        fn write(&mut self, byte: u8) -> bool {
+            self.sent[self.len] = byte;
+            self.len += 1;
+            true
+        }
+
+        fn read(&mut self) -> Option<u8> {
+            let (&first, rest) = self.responses.split_first()?;
+            self.responses = rest;
+            Some(first)
+        }
+    }
+
+    #[test_case]
+    fn test_set_leds() {
+        let mut stub = StubController::new(&[RESPONSE_ACK, RESPONSE_ACK]);
+        assert_eq!(set_leds_with(&mut stub, true, true, false), Ok(()));
+        assert_eq!(stub.sent[..stub.len], [CMD_SET_LEDS, 0b110]);
+
+        // Resend is honored.
+        let mut stub = StubController::new(&[RESPONSE_RESEND, RESPONSE_ACK, RESPONSE_ACK]);
+        assert_eq!(set_leds_with(&mut stub, false, false, true), Ok(()));
+        assert_eq!(stub.sent[..stub.len], [CMD_SET_LEDS, CMD_SET_LEDS, 0b001]);
+
+        // No response at all doesn't hang.
+        let mut stub = StubController::new(&[]);
+        assert_eq!(set_leds_with(&mut stub, true, false, false), Err(NoAck));
+        assert_eq!(stub.len, 1);
+    }
+
+    #[test_case]
+    fn test_lock_key_toggles() {
+        let caps_lock = modifiers().caps_lock;
+        // Caps lock press and release in set 1.
+        handle_scancode(0x3a);
+        handle_scancode(0xba);
+        assert_eq!(modifiers().caps_lock, !caps_lock);
+        handle_scancode(0x3a);
+        handle_scancode(0xba);
+        assert_eq!(modifiers().caps_lock, caps_lock);
+    }
+
+    #[test_case]
+    fn test_poll() {
+        while poll().is_some() {}
+        assert_eq!(poll(), None);
+
+        // `A` and `B` press and release in set 1.
+        for scancode in [0x1e, 0x9e, 0x30, 0xb0] {
+            handle_scancode(scancode);
+        }
+        let mut keys = [None; 3];
+        for key in keys.iter_mut() {
+            *key = poll();
+        }
+        assert_eq!(
+            keys,
+            [
+                Some(DecodedKey::Unicode('a')),
+                Some(DecodedKey::Unicode('b')),
+                None
+            ]
+        );
+    }
+
+    #[test_case]
+    fn test_key_queue_full() {
+        let mut queue = KeyQueue::new();
+        for _ in 0..QUEUE_CAPACITY {
+            assert!(queue.push(DecodedKey::Unicode('x')));
+        }
+        assert!(!queue.push(DecodedKey::Unicode('y')));
+        assert_eq!(queue.pop(), Some(DecodedKey::Unicode('x')));
+        assert!(queue.push(DecodedKey::Unicode('z')));
+        let mut last = None;
+        while let Some(key) = queue.pop() {
+            last = Some(key);
+        }
+        assert_eq!(last, Some(DecodedKey::Unicode('z')));
+    }
+
+    #[test_case]
+    fn test_typematic() {
+        let key = DecodedKey::Unicode('a');
+        let mut queue = KeyQueue::new();
+        let mut typematic = Typematic::new();
+        typematic.delay = 5;
+        typematic.interval = 2;
+
+        assert!(typematic.press(KeyCode::A, key, 10));
+        // The keyboard's own repeat is swallowed.
+        assert!(!typematic.press(KeyCode::A, key, 11));
+        for now in 10..15 {
+            typematic.tick(now, &mut queue);
+        }
+        assert_eq!(queue.pop(), None);
+        // Repeats at 15, 17 and 19.
+        for now in 15..20 {
+            typematic.tick(now, &mut queue);
+        }
+        // Releasing another key leaves it held.
+        typematic.release(KeyCode::B);
+        typematic.release(KeyCode::A);
+        typematic.tick(30, &mut queue);
+        let mut count = 0;
+        while let Some(repeated) = queue.pop() {
+            assert_eq!(repeated, key);
+            count += 1;
+        }
+        assert_eq!(count, 3);
+
+        // Off, the keyboard's repeat goes through.
+        typematic.interval = 0;
+        assert!(typematic.press(KeyCode::A, key, 40));
+        assert!(typematic.press(KeyCode::A, key, 41));
+        typematic.tick(100, &mut queue);
+        assert_eq!(queue.pop(), None);
+    }
+}
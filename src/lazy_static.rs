@@ -2,22 +2,17 @@ use core::cell::UnsafeCell;
 use core::marker::Sync;
 use core::mem::MaybeUninit;
 use core::ops::Deref;
-use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicBool;
 use core::sync::atomic::Ordering;
 
-struct InitStage;
-
-impl InitStage {
-    #![allow(non_upper_case_globals)]
-
-    const Uninit: u8 = 0;
-    const Initing: u8 = 1;
-    const Inited: u8 = 2;
-}
+use crate::spinlock::SpinLock;
+use crate::x86_64::without_interrupts;
 
 pub struct LazyStatic<T: 'static, F: FnOnce() -> T> {
-    init_state: AtomicU8,
-    init_fn: UnsafeCell<Option<F>>,
+    is_inited: AtomicBool,
+    // The lock serializes initialization. The init fn is taken out on the
+    // first and only run.
+    init_fn: SpinLock<Option<F>>,
 
     value: UnsafeCell<MaybeUninit<T>>,
 }
@@ -25,24 +20,38 @@ pub struct LazyStatic<T: 'static, F: FnOnce() -> T> {
 impl<T: 'static, F: FnOnce() -> T> LazyStatic<T, F> {
     pub const fn new(init_fn: F) -> Self {
         Self {
-            init_state: AtomicU8::new(InitStage::Uninit),
-            init_fn: UnsafeCell::new(Some(init_fn)),
+            is_inited: AtomicBool::new(false),
+            init_fn: SpinLock::new(Some(init_fn)),
             value: UnsafeCell::new(MaybeUninit::uninit()),
         }
     }
+
+    /// Whether the value has been initialized.
+    pub fn initialized(&self) -> bool {
+        self.is_inited.load(Ordering::Acquire)
+    }
+
+    /// Initialize it now if it isn't yet, instead of at the first use,
+    /// which might be in an interrupt handler.
+    pub fn force(&self) -> &T {
+        self
+    }
 }
 
+// A `static` is never dropped, so this only runs for a `LazyStatic` that
+// isn't one, e.g. in tests.
 impl<T: 'static, F: FnOnce() -> T> Drop for LazyStatic<T, F> {
     fn drop(&mut self) {
-        match *self.init_state.get_mut() {
-            InitStage::Uninit | InitStage::Initing => (),
+        // No atomic ordering is needed: `&mut self` means no one else can
+        // access it anymore, and whatever handed it over to us has
+        // synchronized with the initialization already.
+        if *self.is_inited.get_mut() {
             // Safety:
             // - We have unique access to self.value on drop, and
             // that value has been inited.
-            InitStage::Inited => unsafe {
+            unsafe {
                 (*self.value.get()).assume_init_drop();
-            },
-            _ => unreachable!(),
+            }
         }
     }
 }
@@ -51,38 +60,28 @@ impl<T: 'static, F: FnOnce() -> T> Deref for LazyStatic<T, F> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        // TODO:
-        // This may cause a race condition when the interrupt handler gets in
-        // during the Initing stage.
-        // We need to block that temporarily.
-        loop {
-            match self.init_state.compare_exchange(
-                InitStage::Uninit,
-                InitStage::Initing,
-                Ordering::Acquire,
-                Ordering::Acquire,
-            ) {
-                // Safety:
-                // - We have unique access to both self.value and self.init_fn.
-                // - This is the only time that self.init_fn's ownership got taken.
-                Ok(_) => unsafe {
-                    let init_fn = (*self.init_fn.get()).take().unwrap();
-                    (*self.value.get()).write((init_fn)());
-                    self.init_state.store(InitStage::Inited, Ordering::Release);
-                    return (*self.value.get()).assume_init_ref();
-                },
-                Err(InitStage::Initing) => {
-                    core::hint::spin_loop();
+        if !self.is_inited.load(Ordering::Acquire) {
+            // Block interrupts while holding the lock, otherwise a handler
+            // using the same static would spin forever on the lock held by
+            // the code it interrupted.
+            without_interrupts(|| {
+                let mut init_fn = self.init_fn.lock();
+                // Someone else may have inited it before we got the lock.
+                if let Some(init_fn) = init_fn.take() {
+                    // Safety:
+                    // - We hold the lock, and no refs to self.value has been
+                    // handed out before it's inited.
+                    unsafe {
+                        (*self.value.get()).write((init_fn)());
+                    }
+                    self.is_inited.store(true, Ordering::Release);
                 }
-                // Safety:
-                // - There won't be any ohter mutable refs to self.value, and
-                // - The value has been initialized.
-                Err(InitStage::Inited) => unsafe {
-                    return (*self.value.get()).assume_init_ref();
-                },
-                _ => unreachable!(),
-            }
+            });
         }
+        // Safety:
+        // - There won't be any ohter mutable refs to self.value, and
+        // - The value has been initialized.
+        unsafe { (*self.value.get()).assume_init_ref() }
     }
 }
 
@@ -104,3 +103,75 @@ macro_rules! lazy_static {
         $crate::lazy_static!{ $($rest)* }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::LazyStatic;
+    use crate::interrupts::idt::InterruptDescriptorTable;
+    use crate::interrupts::{Exception, InterruptStackFrame};
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    static INIT_COUNT: AtomicU32 = AtomicU32::new(0);
+
+    lazy_static! {
+        static ref VALUE: u32 = {
+            INIT_COUNT.fetch_add(1, Ordering::SeqCst);
+            42
+        };
+
+        static ref FORCED: [u8; 4] = [1, 2, 3, 4];
+
+        static ref TEST_IDT: InterruptDescriptorTable = {
+            let mut idt = InterruptDescriptorTable::new();
+            idt.set_handler(Exception::BreakPoint, breakpoint_handler);
+            idt
+        };
+    }
+
+    extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
+        assert_eq!(*VALUE, 42);
+    }
+
+    #[test_case]
+    fn test_init_once() {
+        // The first access happens in an interrupt handler.
+        crate::interrupts::with_idt(&TEST_IDT, crate::x86_64::int3);
+
+        assert_eq!(*VALUE, 42);
+        assert_eq!(*VALUE, 42);
+        assert_eq!(INIT_COUNT.load(Ordering::SeqCst), 1);
+    }
+
+    struct DropCounter<'a>(&'a AtomicU32);
+
+    impl Drop for DropCounter<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test_case]
+    fn test_drop() {
+        static DROPS: AtomicU32 = AtomicU32::new(0);
+
+        {
+            let lazy = LazyStatic::new(|| DropCounter(&DROPS));
+            let _ = &*lazy;
+            let _ = &*lazy;
+            assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+        }
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+
+        // Nothing to drop if it was never initialized.
+        drop(LazyStatic::new(|| DropCounter(&DROPS)));
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn test_force() {
+        assert!(!FORCED.initialized());
+        assert_eq!(FORCED.force()[3], 4);
+        assert!(FORCED.initialized());
+        assert_eq!(FORCED.iter().sum::<u8>(), 10);
+    }
+}
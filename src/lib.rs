@@ -7,58 +7,134 @@
 #![feature(abi_x86_interrupt)]
 #![feature(naked_functions)]
 #![feature(asm_sym)]
+#![feature(alloc_error_handler)]
 
+extern crate alloc;
+
+pub mod backtrace;
+pub mod console;
 pub mod gdt;
+pub mod graphics;
 pub mod interrupts;
+pub mod keyboard;
 pub mod lazy_static;
+pub mod log;
+pub mod memory;
+pub mod mmio;
+pub mod pci;
+pub mod percpu;
 pub mod port;
 pub mod pic;
+pub mod pit;
 pub mod screen;
 pub mod serial;
+pub mod speaker;
 pub mod spinlock;
+pub mod test_util;
 pub mod x86_64;
 
 // TODO: how to make it pub only to should-panic tests?
 pub mod bit_field;
 
+/// Bring up the CPU tables and interrupts. The order matters:
+/// 1. GDT and TSS, since the IDT's double fault entry switches to an IST
+///    stack that only exists in the TSS.
+/// 2. IDT, so that everything that can arrive has a handler.
+/// 3. PIC remap, otherwise IRQs land on the exception vectors.
+/// 4. Per-CPU data, before any handler may look at it.
+/// 5. Lazy statics that handlers print with, so that they aren't first
+///    initialized in an interrupt.
+/// 6. Enable interrupts.
+///
+/// It's fine to call it more than once.
 pub fn init() {
+    // Interrupts may be on from an earlier call, and nothing may arrive
+    // while the PIC is being remapped.
+    x86_64::disable_interrupt();
     gdt::init();
+    debug_assert!(gdt::is_loaded(), "IDT needs the IST stacks from the GDT");
     interrupts::init();
     unsafe {
         interrupts::PICS.lock().initialize();
     }
+    debug_assert!(interrupts::is_loaded(), "enable interrupts without an IDT");
+    percpu::init();
+    screen::SCREEN.force();
+    serial::SERIAL1.force();
     x86_64::enable_interrupt();
 }
 
 pub trait Testable {
+    fn name(&self) -> &'static str;
     fn run(&self);
 }
 
 impl<F: Fn()> Testable for F {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<F>()
+    }
+
     fn run(&self) {
-        serial_print!("{} ...\t", core::any::type_name::<F>());
+        serial_print!("{} ...\t", self.name());
         self();
         serial_print!("[OK]");
     }
 }
 
+/// Only tests whose name contains it are run, e.g. build with
+/// `FYOS_TEST_FILTER=screen cargo test`. Empty runs them all.
+const TEST_FILTER: &str = match option_env!("FYOS_TEST_FILTER") {
+    Some(filter) => filter,
+    None => "",
+};
+
+/// The tests whose name contains `filter`.
+fn select_tests<'a>(
+    tests: &'a [&'a dyn Testable],
+    filter: &'a str,
+) -> impl Iterator<Item = &'a dyn Testable> + 'a {
+    tests.iter().copied().filter(move |test| test.name().contains(filter))
+}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
-    if !tests.is_empty() {
-        serial_println!("Running {} tests...", tests.len());
+    let selected = select_tests(tests, TEST_FILTER).count();
+    if selected != 0 {
+        serial_println!("Running {} tests...", selected);
     } else {
         serial_println!("No test to run.");
     }
+    if selected < tests.len() {
+        let skipped = tests.len() - selected;
+        serial_println!("Skipped {} tests not matching {:?}.", skipped, TEST_FILTER);
+    }
 
-    for &test in tests.iter() {
+    for test in select_tests(tests, TEST_FILTER) {
         test.run();
         serial_println!();
     }
     exit_qemu(QemuExitCode::Success);
 }
 
+/// Take back the output locks from whoever held them when it panicked, so
+/// that the panic handler can print.
+///
+/// Safety:
+/// * Nothing that ran before the panic runs again. See
+///   `SpinLock::force_unlock`.
+pub unsafe fn reclaim_output() {
+    // Safety: Guaranteed by the caller.
+    unsafe {
+        screen::SCREEN.force_unlock();
+        serial::SERIAL1.force_unlock();
+    }
+}
+
 pub fn test_panic_handler(info: &core::panic::PanicInfo) -> ! {
+    // Safety: The failed test never resumes.
+    unsafe { reclaim_output() };
     serial_println!("[Failed]");
     serial_println!("{}", info);
+    backtrace::print(16);
     exit_qemu(QemuExitCode::Failed);
 }
 
@@ -69,8 +145,10 @@ pub enum QemuExitCode {
     Failed = 0x11,
 }
 
-/// Tell QEMU we are about to exit.
+/// Tell QEMU we are about to exit. Serial output is flushed first, so none
+/// of it is lost.
 pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    serial::flush();
     // Safety:
     // Write exit code to QEMU's isa-debug-exit device.
     unsafe {
@@ -95,3 +173,34 @@ pub extern "C" fn _start() -> ! {
 fn panic(info: &core::panic::PanicInfo) -> ! {
     test_panic_handler(info);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen_a() {}
+    fn screen_b() {}
+    fn serial_a() {}
+
+    #[test_case]
+    fn test_select_tests() {
+        let tests: [&dyn Testable; 3] = [&screen_a, &screen_b, &serial_a];
+        let names = |filter| {
+            let mut names = ["", "", ""];
+            for (name, test) in names.iter_mut().zip(select_tests(&tests, filter)) {
+                *name = test.name();
+            }
+            names
+        };
+
+        let (screen_a, screen_b, serial_a) = (
+            "fyos::tests::screen_a",
+            "fyos::tests::screen_b",
+            "fyos::tests::serial_a",
+        );
+        assert_eq!(names(""), [screen_a, screen_b, serial_a]);
+        assert_eq!(names("screen"), [screen_a, screen_b, ""]);
+        assert_eq!(names("_a"), [screen_a, serial_a, ""]);
+        assert_eq!(names("keyboard"), ["", "", ""]);
+    }
+}
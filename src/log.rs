@@ -0,0 +1,125 @@
+// Leveled log lines over serial, optionally stamped with the time since
+// the timer was enabled, e.g.
+//
+//     [ 12.303] WARN: keyboard queue full
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::pit;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        };
+        f.write_str(name)
+    }
+}
+
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+/// Prefix log lines with `[seconds.millis]` since the timer was enabled.
+/// It's off by default.
+pub fn set_timestamps(on: bool) {
+    TIMESTAMPS.store(on, Ordering::Relaxed);
+}
+
+pub fn timestamps() -> bool {
+    TIMESTAMPS.load(Ordering::Relaxed)
+}
+
+/// A log line, stamped at `ticks`.
+struct Record<'a> {
+    level: Level,
+    ticks: u64,
+    args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if timestamps() {
+            let ms = pit::ticks_to_ms(self.ticks);
+            write!(f, "[{:3}.{:03}] ", ms / 1000, ms % 1000)?;
+        }
+        writeln!(f, "{}: {}", self.level, self.args)
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    let record = Record {
+        level,
+        ticks: pit::ticks(),
+        args,
+    };
+    crate::serial_print!("{}", record);
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::log::_log($level, ::core::format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log!($crate::log::Level::Info, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log!($crate::log::Level::Warn, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log!($crate::log::Level::Error, $($arg)*)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::StrBuf;
+    use core::fmt::Write;
+
+    fn format(ticks: u64) -> StrBuf<64> {
+        let mut out = StrBuf::new();
+        let record = Record {
+            level: Level::Warn,
+            ticks,
+            args: format_args!("queue {}", "full"),
+        };
+        write!(out, "{}", record).unwrap();
+        out
+    }
+
+    #[test_case]
+    fn test_timestamps() {
+        assert!(!timestamps());
+        assert_eq!(format(224).as_str(), "WARN: queue full\n");
+
+        set_timestamps(true);
+        assert_eq!(format(224).as_str(), "[ 12.303] WARN: queue full\n");
+        assert_eq!(format(1).as_str(), "[  0.054] WARN: queue full\n");
+        assert_eq!(format(20000).as_str(), "[1098.508] WARN: queue full\n");
+        crate::info!("logged at tick {}", pit::ticks());
+        set_timestamps(false);
+    }
+}
@@ -1,19 +1,31 @@
 #![no_std]
 #![no_main]
 #![deny(unsafe_op_in_unsafe_fn)]
+// kmain and what it uses are skipped under test.
+#![cfg_attr(test, allow(dead_code))]
 #![feature(custom_test_frameworks)]
 #![feature(naked_functions)]
 #![test_runner(fyos::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+#[cfg(test)]
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use fyos::init;
+use fyos::keyboard::{self, KeyCode, Modifiers};
 use fyos::println;
+use fyos::screen::vt;
 use fyos::x86_64;
 
 static HELLO: &str = "Hello World!";
 static MORNING: &str = "Morning! Nice day for fishing ain't it?";
 
-fn main() {
+/// The kernel proper, after `init` has set up the CPU.
+///
+/// Press Ctrl+Shift+Q to quit QEMU with `QemuExitCode::Success`, so that
+/// demo runs can be scripted. Alt+F1..F4 switch virtual consoles.
+fn kmain() {
+    keyboard::set_key_hook(Some(on_key));
     println!("{}\n", HELLO);
     for i in 1.. {
         println!("{} - {}", MORNING, i);
@@ -21,15 +33,46 @@ fn main() {
     naked_fn_exmaple();
 }
 
+fn is_exit_shortcut(code: KeyCode, modifiers: Modifiers) -> bool {
+    code == KeyCode::Q && modifiers.ctrl && modifiers.shift
+}
+
+fn on_key(code: KeyCode, modifiers: Modifiers) {
+    if is_exit_shortcut(code, modifiers) {
+        exit();
+    }
+    if let Some(n) = vt::switch_key(code, modifiers) {
+        // It's dropped if the screen is busy, just press again.
+        vt::try_switch(n);
+    }
+}
+
+/// Set instead of exiting under test.
+#[cfg(test)]
+static EXIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(not(test))]
+fn exit() {
+    fyos::exit_qemu(fyos::QemuExitCode::Success);
+}
+
+#[cfg(test)]
+fn exit() {
+    EXIT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
+    // Left off under test, where a halt would hang the run.
+    #[cfg(not(test))]
+    fyos::interrupts::set_default_handler(Some(fyos::interrupts::report_unhandled));
     init();
 
     #[cfg(test)]
     test_main();
 
     #[cfg(not(test))]
-    main();
+    kmain();
 
     x86_64::hlt_loop();
 }
@@ -37,7 +80,10 @@ pub extern "C" fn _start() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    // Safety: We never return from here.
+    unsafe { fyos::reclaim_output() };
     println!("{}", info);
+    fyos::backtrace::print(16);
     x86_64::hlt_loop();
 }
 
@@ -53,3 +99,22 @@ extern "C" fn naked_fn_exmaple() -> ! {
         core::arch::asm!("mov eax, 0x42", options(noreturn));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_exit_shortcut() {
+        keyboard::set_key_hook(Some(on_key));
+        // Set 1 make codes of Q, Ctrl, Shift and Q, then the break codes.
+        keyboard::handle_scancode(0x10);
+        keyboard::handle_scancode(0x90);
+        assert!(!EXIT_REQUESTED.load(Ordering::SeqCst));
+        for scancode in [0x1d, 0x2a, 0x10, 0x90, 0xaa, 0x9d] {
+            keyboard::handle_scancode(scancode);
+        }
+        keyboard::set_key_hook(None);
+        assert!(EXIT_REQUESTED.load(Ordering::SeqCst));
+    }
+}
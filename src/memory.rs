@@ -0,0 +1,10 @@
+pub mod heap;
+pub mod paging;
+pub mod slab;
+pub mod stack;
+
+pub use crate::interrupts::probe::probe_read;
+pub use heap::{dump_heap_stats, heap_stats, HeapStats};
+pub use paging::{map, map_identity, translate, MapError, PageTableFlags};
+pub use slab::Slab;
+pub use stack::KernelStack;
@@ -0,0 +1,309 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem::{align_of, size_of};
+use core::ptr;
+
+use crate::serial_println;
+use crate::spinlock::SpinLock;
+use crate::x86_64::without_interrupts;
+
+pub const HEAP_SIZE: usize = 100 * 1024;
+
+// Only its address is used.
+#[allow(dead_code)]
+#[repr(align(4096))]
+struct HeapSpace([u8; HEAP_SIZE]);
+
+static mut HEAP_SPACE: HeapSpace = HeapSpace([0; HEAP_SIZE]);
+
+#[global_allocator]
+static HEAP: Heap = Heap(SpinLock::new(HeapInner::new()));
+
+/// Header written at the start of each free region.
+struct FreeRegion {
+    size: usize,
+    next: *mut FreeRegion,
+}
+
+impl FreeRegion {
+    fn start(&self) -> usize {
+        self as *const _ as usize
+    }
+
+    fn end(&self) -> usize {
+        self.start() + self.size
+    }
+}
+
+/// Every free region must be able to hold its header.
+const MIN_REGION: usize = size_of::<FreeRegion>();
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// A first-fit allocator keeping free regions in a list sorted by address,
+/// so that adjacent regions can be merged on free.
+struct FreeList {
+    // Dummy head whose size is always 0.
+    head: FreeRegion,
+}
+
+impl FreeList {
+    const fn new() -> Self {
+        Self {
+            head: FreeRegion {
+                size: 0,
+                next: ptr::null_mut(),
+            },
+        }
+    }
+
+    /// Round the layout up so that every allocation can be turned back into
+    /// a free region on dealloc.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let align = layout.align().max(align_of::<FreeRegion>());
+        let size = align_up(layout.size().max(MIN_REGION), align_of::<FreeRegion>());
+        (size, align)
+    }
+
+    /// Safety:
+    /// * The region must be unused and valid for writes.
+    /// * addr is aligned for FreeRegion and size is at least MIN_REGION.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        debug_assert!(addr % align_of::<FreeRegion>() == 0 && size >= MIN_REGION);
+        let head: *mut FreeRegion = &mut self.head;
+        let mut prev = head;
+        // Safety: All regions in the list are valid free regions.
+        unsafe {
+            while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+                prev = (*prev).next;
+            }
+            let next = (*prev).next;
+            let region = addr as *mut FreeRegion;
+            region.write(FreeRegion { size, next });
+            (*prev).next = region;
+
+            if !next.is_null() && (*region).end() == next as usize {
+                (*region).size += (*next).size;
+                (*region).next = (*next).next;
+            }
+            if prev != head && (*prev).end() == addr {
+                (*prev).size += (*region).size;
+                (*prev).next = (*region).next;
+            }
+        }
+    }
+
+    /// Find where an allocation would start in the region. Gaps left in
+    /// front or behind it must be big enough to become free regions.
+    fn fit(region: &FreeRegion, size: usize, align: usize) -> Option<usize> {
+        let mut start = align_up(region.start(), align);
+        if start != region.start() && start - region.start() < MIN_REGION {
+            start = align_up(region.start() + MIN_REGION, align);
+        }
+        let end = start.checked_add(size)?;
+        if end > region.end() {
+            return None;
+        }
+        let excess = region.end() - end;
+        if excess > 0 && excess < MIN_REGION {
+            return None;
+        }
+        Some(start)
+    }
+
+    fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut FreeRegion = &mut self.head;
+        // Safety: All regions in the list are valid free regions.
+        unsafe {
+            while !(*prev).next.is_null() {
+                let region = (*prev).next;
+                if let Some(start) = Self::fit(&*region, size, align) {
+                    let (region_start, region_end) = ((*region).start(), (*region).end());
+                    (*prev).next = (*region).next;
+                    let end = start + size;
+                    if start > region_start {
+                        self.add_free_region(region_start, start - region_start);
+                    }
+                    if region_end > end {
+                        self.add_free_region(end, region_end - end);
+                    }
+                    return start as *mut u8;
+                }
+                prev = region;
+            }
+        }
+        ptr::null_mut()
+    }
+}
+
+// Safety:
+// The free list owns the regions it points to, so moving it to another
+// thread is fine.
+unsafe impl Send for FreeList {}
+
+/// A snapshot of the heap usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub total: usize,
+    /// Bytes taken by live allocations, including rounding.
+    pub used: usize,
+    pub free: usize,
+    /// Number of live allocations.
+    pub allocations: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "heap: {} / {} bytes used, {} free, {} allocations",
+            self.used, self.total, self.free, self.allocations
+        )
+    }
+}
+
+struct HeapInner {
+    free_list: FreeList,
+    is_initialized: bool,
+    used: usize,
+    allocations: usize,
+}
+
+impl HeapInner {
+    const fn new() -> Self {
+        Self {
+            free_list: FreeList::new(),
+            is_initialized: false,
+            used: 0,
+            allocations: 0,
+        }
+    }
+
+    fn init_once(&mut self) {
+        if !self.is_initialized {
+            // Safety: The heap space is only ever handed out by us.
+            unsafe {
+                let start = ptr::addr_of_mut!(HEAP_SPACE) as usize;
+                self.free_list.add_free_region(start, HEAP_SIZE);
+            }
+            self.is_initialized = true;
+        }
+    }
+
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            total: HEAP_SIZE,
+            used: self.used,
+            free: HEAP_SIZE - self.used,
+            allocations: self.allocations,
+        }
+    }
+}
+
+pub struct Heap(SpinLock<HeapInner>);
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = FreeList::size_align(layout);
+        // An interrupt handler that allocates must not spin on the lock
+        // held by the code it interrupted.
+        without_interrupts(|| {
+            let mut heap = self.0.lock();
+            heap.init_once();
+            let ptr = heap.free_list.alloc(size, align);
+            if !ptr.is_null() {
+                heap.used += size;
+                heap.allocations += 1;
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = FreeList::size_align(layout);
+        without_interrupts(|| {
+            let mut heap = self.0.lock();
+            // Safety: The block was allocated by us with the same layout.
+            unsafe {
+                heap.free_list.add_free_region(ptr as usize, size);
+            }
+            heap.used -= size;
+            heap.allocations -= 1;
+        })
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    without_interrupts(|| HEAP.0.lock().stats())
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    serial_println!(
+        "ALLOC ERROR: failed to allocate {} bytes aligned to {}",
+        layout.size(),
+        layout.align()
+    );
+    serial_println!("{}", heap_stats());
+    crate::x86_64::hlt_loop();
+}
+
+/// Print the heap usage over serial.
+pub fn dump_heap_stats() {
+    serial_println!("{}", heap_stats());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn test_heap_stats() {
+        let before = heap_stats();
+        assert_eq!(before.total, HEAP_SIZE);
+        assert_eq!(before.used + before.free, before.total);
+
+        let a: Vec<u64> = (0..100).collect();
+        let b: Vec<u8> = Vec::with_capacity(1000);
+        let during = heap_stats();
+        assert!(during.used >= before.used + 800 + 1000);
+        assert_eq!(during.allocations, before.allocations + 2);
+        assert_eq!(a.iter().sum::<u64>(), 4950);
+
+        drop(a);
+        drop(b);
+        assert_eq!(heap_stats(), before);
+    }
+
+    #[test_case]
+    fn test_heap_alignment() {
+        use alloc::alloc::{alloc, dealloc};
+        use alloc::boxed::Box;
+
+        for align in [8, 16, 64, 512, 4096] {
+            let layout = Layout::from_size_align(100, align).unwrap();
+            let ptr = unsafe { alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            unsafe { dealloc(ptr, layout) };
+        }
+
+        #[repr(align(64))]
+        struct CacheLine([u8; 64]);
+        let line = Box::new(CacheLine([0; 64]));
+        assert_eq!(&*line as *const _ as usize % 64, 0);
+        assert_eq!(line.0[63], 0);
+    }
+
+    #[test_case]
+    fn test_heap_reuse() {
+        // Freed regions are merged back, so this can't run out of memory.
+        for _ in 0..100 {
+            let v: Vec<u8> = Vec::with_capacity(HEAP_SIZE / 2);
+            assert_eq!(v.capacity(), HEAP_SIZE / 2);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+// 4-level page tables, and mapping pages into the ones in use.
+//
+// The page tables are reached through the bootloader's mapping of all
+// physical memory at `PHYSICAL_MEMORY_OFFSET`, see `map_physical_memory`
+// and `physical-memory-offset` in Cargo.toml. The two must agree.
+
+use core::cell::UnsafeCell;
+
+use crate::bit_field::BitField;
+use crate::spinlock::SpinLock;
+use crate::x86_64::{self, without_interrupts, PhysAddr, VirtAddr};
+
+/// Where the bootloader maps physical address 0. It's in the higher half,
+/// so it must be written sign extended to be canonical.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xffff_f000_0000_0000;
+const _: () = assert!(VirtAddr(PHYSICAL_MEMORY_OFFSET).is_canonical());
+
+pub const PAGE_SIZE: u64 = 4096;
+
+const ENTRY_COUNT: usize = 512;
+
+/// Address bits of an entry.
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+crate::bit_flags! {
+    pub struct PageTableFlags: u64 {
+        const PRESENT = 1;
+        const WRITABLE = 1 << 1;
+        const USER_ACCESSIBLE = 1 << 2;
+        const WRITE_THROUGH = 1 << 3;
+        const NO_CACHE = 1 << 4;
+        const ACCESSED = 1 << 5;
+        const DIRTY = 1 << 6;
+        /// Maps a 2MiB page in a level 2 entry, or a 1GiB one in level 3.
+        const HUGE_PAGE = 1 << 7;
+        const GLOBAL = 1 << 8;
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    pub const fn unused() -> Self {
+        Self(0)
+    }
+
+    pub fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn flags(&self) -> PageTableFlags {
+        PageTableFlags::from_bits_retain(self.0 & !ADDR_MASK)
+    }
+
+    pub fn addr(&self) -> PhysAddr {
+        PhysAddr(self.0 & ADDR_MASK)
+    }
+
+    /// # Panics
+    /// Panics if addr isn't page aligned.
+    pub fn set(&mut self, addr: PhysAddr, flags: PageTableFlags) {
+        assert!(addr.0 % PAGE_SIZE == 0, "unaligned frame {:#x}", addr);
+        self.0 = addr.0 | flags.bits();
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct PageTable {
+    pub entries: [PageTableEntry; ENTRY_COUNT],
+}
+
+/// Where the frame at `addr` can be accessed.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let virt = VirtAddr(addr.0 + PHYSICAL_MEMORY_OFFSET);
+    assert!(virt.is_canonical(), "{:#x} isn't canonical", virt);
+    virt
+}
+
+/// Index into the table of each level for `addr`, level 4 first.
+fn table_indices(addr: VirtAddr) -> [usize; 4] {
+    [
+        addr.0.get_bits(39..48) as usize,
+        addr.0.get_bits(30..39) as usize,
+        addr.0.get_bits(21..30) as usize,
+        addr.0.get_bits(12..21) as usize,
+    ]
+}
+
+/// The physical address `addr` maps to in the page tables in use, if any.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    let indices = table_indices(addr);
+    let mut frame = x86_64::read_cr3_frame();
+    for (level, &index) in indices.iter().enumerate() {
+        // Safety: Page tables are mapped at the offset, and are only
+        // changed with `MAPPER` locked, which never unmaps anything.
+        let table = unsafe { &*(phys_to_virt(frame).0 as *const PageTable) };
+        let entry = table.entries[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        // Level 4 entries can't be huge.
+        let huge = level > 0 && level < 3 && entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if huge || level == 3 {
+            let offset_bits = (12 + 9 * (3 - level)) as u8;
+            return Some(entry.addr() + addr.0.get_bits(0..offset_bits));
+        }
+        frame = entry.addr();
+    }
+    unreachable!()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The page is mapped already.
+    AlreadyMapped,
+    /// No frame left for an intermediate table.
+    FrameAllocationFailed,
+    /// A huge page covers the page already.
+    ParentHugePage,
+}
+
+/// Number of frames for intermediate tables.
+const POOL_FRAMES: usize = 16;
+
+/// Frames for intermediate tables, in the kernel image. There's no memory
+/// map to take them from yet.
+#[repr(C, align(4096))]
+struct FramePool(UnsafeCell<[[u8; PAGE_SIZE as usize]; POOL_FRAMES]>);
+
+// Safety: Frames are handed out once with `MAPPER` locked.
+unsafe impl Sync for FramePool {}
+
+static FRAME_POOL: FramePool = FramePool(UnsafeCell::new([[0; PAGE_SIZE as usize]; POOL_FRAMES]));
+
+/// Hands out the frames of `FRAME_POOL` in order, never to be freed.
+struct Mapper {
+    next_frame: usize,
+}
+
+impl Mapper {
+    fn allocate_frame(&mut self) -> Option<PhysAddr> {
+        if self.next_frame == POOL_FRAMES {
+            return None;
+        }
+        // Safety: Only the address is taken.
+        let frame = unsafe { (*FRAME_POOL.0.get())[self.next_frame].as_ptr() };
+        self.next_frame += 1;
+        translate(VirtAddr::from_ptr(frame))
+    }
+
+    /// The table the entry points to, allocating an empty one if unused.
+    fn next_table(&mut self, entry: &mut PageTableEntry) -> Result<&'static mut PageTable, MapError> {
+        if entry.is_unused() {
+            let frame = self.allocate_frame().ok_or(MapError::FrameAllocationFailed)?;
+            let table = phys_to_virt(frame).0 as *mut PageTable;
+            // Safety: The frame is fresh and mapped at the offset.
+            unsafe {
+                table.write(PageTable {
+                    entries: [PageTableEntry::unused(); ENTRY_COUNT],
+                })
+            };
+            // Leave it to the leaf entry to restrict the access.
+            let flags = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE;
+            entry.set(frame, flags);
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Err(MapError::ParentHugePage);
+        }
+        // Safety: Page tables are mapped at the offset.
+        Ok(unsafe { &mut *(phys_to_virt(entry.addr()).0 as *mut PageTable) })
+    }
+
+    fn map(&mut self, virt: VirtAddr, phys: PhysAddr, flags: PageTableFlags) -> Result<(), MapError> {
+        let [p4, p3, p2, p1] = table_indices(virt);
+        // Safety: The level 4 table is mapped at the offset.
+        let l4 = unsafe { &mut *(phys_to_virt(x86_64::read_cr3_frame()).0 as *mut PageTable) };
+        let l3 = self.next_table(&mut l4.entries[p4])?;
+        let l2 = self.next_table(&mut l3.entries[p3])?;
+        let l1 = self.next_table(&mut l2.entries[p2])?;
+        let entry = &mut l1.entries[p1];
+        if !entry.is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
+        entry.set(phys, flags | PageTableFlags::PRESENT);
+        // Safety: We are in ring 0.
+        unsafe { x86_64::invlpg(virt) };
+        Ok(())
+    }
+}
+
+static MAPPER: SpinLock<Mapper> = SpinLock::new(Mapper { next_frame: 0 });
+
+/// Map the 4KiB page at `virt` to the frame at `phys` in the page tables in
+/// use. Intermediate tables are taken from a small static pool.
+///
+/// Safety:
+/// * Nothing may rely on the frame's content staying private, e.g. it
+///   mustn't hold kernel data unless that's intended.
+///
+/// # Panics
+/// Panics if either address isn't page aligned.
+pub unsafe fn map(virt: VirtAddr, phys: PhysAddr, flags: PageTableFlags) -> Result<(), MapError> {
+    assert!(virt.0 % PAGE_SIZE == 0, "unaligned page {:#x}", virt);
+    without_interrupts(|| MAPPER.lock().map(virt, phys, flags))
+}
+
+/// Map the frame at the same virtual address, e.g. for MMIO.
+///
+/// Safety:
+/// * See `map`.
+pub unsafe fn map_identity(frame: PhysAddr, flags: PageTableFlags) -> Result<(), MapError> {
+    // Safety: Guaranteed by the caller.
+    unsafe { map(VirtAddr(frame.0), frame, flags) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_translate() {
+        // The VGA buffer is identity mapped by the bootloader.
+        assert_eq!(translate(VirtAddr(0xb8000)), Some(PhysAddr(0xb8000)));
+        let l4 = x86_64::read_cr3_frame();
+        assert_eq!(translate(phys_to_virt(l4) + 8u64), Some(l4 + 8));
+        assert!(phys_to_virt(PhysAddr(0)).is_canonical());
+    }
+
+    #[test_case]
+    fn test_map() {
+        let phys = without_interrupts(|| MAPPER.lock().allocate_frame()).unwrap();
+        // Nothing lives at the 136th level 4 entry.
+        let page = VirtAddr(0x4444_4444_0000);
+        assert_eq!(translate(page), None);
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        assert_eq!(unsafe { map(page, phys, flags) }, Ok(()));
+        assert_eq!(translate(page + 0x123u64), Some(phys + 0x123));
+        assert_eq!(
+            unsafe { map(page, phys, flags) },
+            Err(MapError::AlreadyMapped)
+        );
+
+        // Both addresses reach the same frame.
+        unsafe {
+            core::ptr::write_volatile((page.0 + 8) as *mut u64, 0xdead_beef);
+            let alias = (phys_to_virt(phys).0 + 8) as *const u64;
+            assert_eq!(core::ptr::read_volatile(alias), 0xdead_beef);
+        }
+    }
+}
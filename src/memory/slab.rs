@@ -0,0 +1,142 @@
+// A fixed-size object pool in a static array, for hot paths that shouldn't
+// go through the heap.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+
+use crate::spinlock::SpinLock;
+use crate::x86_64::without_interrupts;
+
+/// Bookkeeping of the free slots.
+struct FreeSlots<const N: usize> {
+    /// Stack of free slot indices. Only `free[..len]` is valid.
+    free: [usize; N],
+    len: usize,
+    /// To catch double frees.
+    in_use: [bool; N],
+}
+
+impl<const N: usize> FreeSlots<N> {
+    const fn new() -> Self {
+        let mut free = [0; N];
+        // Hand out the low slots first.
+        let mut i = 0;
+        while i < N {
+            free[i] = N - 1 - i;
+            i += 1;
+        }
+        Self {
+            free,
+            len: N,
+            in_use: [false; N],
+        }
+    }
+}
+
+/// Room for N values of T. Both `alloc` and `free` are O(1).
+pub struct Slab<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    // Taken with interrupts disabled, since handlers may allocate too.
+    free: SpinLock<FreeSlots<N>>,
+}
+
+// Safety: Each slot is handed out to one user at a time, and the free
+// list is behind a lock.
+unsafe impl<T: Send, const N: usize> Sync for Slab<T, N> {}
+
+impl<T, const N: usize> Slab<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            // Safety: An array of MaybeUninit needs no initialization.
+            slots: unsafe { MaybeUninit::uninit().assume_init() },
+            free: SpinLock::new(FreeSlots::new()),
+        }
+    }
+
+    /// Take a free slot, or None if all are in use. The value isn't
+    /// dropped on `free`, do it before if needed.
+    // Each slot is only handed out once until it's freed, so the returned
+    // references never alias.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc(&self) -> Option<&mut MaybeUninit<T>> {
+        let index = without_interrupts(|| {
+            let mut free = self.free.lock();
+            free.len = free.len.checked_sub(1)?;
+            let index = free.free[free.len];
+            free.in_use[index] = true;
+            Some(index)
+        })?;
+        // Safety: The slot is ours alone until it's freed.
+        Some(unsafe { &mut *self.slots[index].get() })
+    }
+
+    /// Give a slot back.
+    /// # Panics
+    /// Panics if the slot isn't from this slab or is already free.
+    ///
+    /// Safety:
+    /// * The slot must not be used afterward.
+    pub unsafe fn free(&self, slot: *mut MaybeUninit<T>) {
+        let index = self.index_of(slot).expect("free a slot of another slab");
+        without_interrupts(|| {
+            let mut free = self.free.lock();
+            assert!(free.in_use[index], "double free of slot {}", index);
+            free.in_use[index] = false;
+            let len = free.len;
+            free.free[len] = index;
+            free.len += 1;
+        });
+    }
+
+    /// Number of free slots.
+    pub fn available(&self) -> usize {
+        without_interrupts(|| self.free.lock().len)
+    }
+
+    fn index_of(&self, slot: *mut MaybeUninit<T>) -> Option<usize> {
+        let start = self.slots.as_ptr() as usize;
+        let offset = (slot as usize).checked_sub(start)?;
+        let size = core::mem::size_of::<UnsafeCell<MaybeUninit<T>>>().max(1);
+        let index = offset / size;
+        (offset % size == 0 && index < N).then_some(index)
+    }
+}
+
+impl<T, const N: usize> Default for Slab<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_slab() {
+        static SLAB: Slab<u64, 4> = Slab::new();
+
+        let mut slots = [core::ptr::null_mut(); 4];
+        for (i, slot) in slots.iter_mut().enumerate() {
+            let value = SLAB.alloc().unwrap();
+            value.write(i as u64);
+            *slot = value as *mut MaybeUninit<u64>;
+        }
+        assert!(SLAB.alloc().is_none());
+        assert_eq!(SLAB.available(), 0);
+
+        unsafe {
+            SLAB.free(slots[2]);
+            SLAB.free(slots[0]);
+        }
+        assert_eq!(SLAB.available(), 2);
+
+        // The freed slots are reused, and the others are left alone.
+        let a = SLAB.alloc().unwrap() as *mut MaybeUninit<u64>;
+        let b = SLAB.alloc().unwrap() as *mut MaybeUninit<u64>;
+        assert_eq!((a, b), (slots[0], slots[2]));
+        assert!(SLAB.alloc().is_none());
+        assert_eq!(unsafe { (*slots[1]).assume_init() }, 1);
+        assert_eq!(unsafe { (*slots[3]).assume_init() }, 3);
+    }
+}
@@ -0,0 +1,118 @@
+// Kernel stacks with a guard page below, e.g. for the IST.
+//
+// The pages come from a static pool in the kernel image, and are mapped
+// again in a region of their own with an unmapped page below each stack.
+// Overflowing a stack then page faults instead of running into whatever
+// lies below it.
+
+use core::cell::UnsafeCell;
+
+use super::paging::{self, PageTableFlags, PAGE_SIZE};
+use crate::spinlock::SpinLock;
+use crate::x86_64::{without_interrupts, VirtAddr};
+
+/// Number of pages for all stacks together.
+const POOL_PAGES: usize = 24;
+
+/// Where stacks are mapped. Nothing else lives in its level 4 entry.
+const STACK_REGION: u64 = 0x5555_0000_0000;
+
+#[repr(C, align(4096))]
+struct StackPool(UnsafeCell<[[u8; PAGE_SIZE as usize]; POOL_PAGES]>);
+
+// Safety: Pages are handed out once with `ALLOCATOR` locked.
+unsafe impl Sync for StackPool {}
+
+static POOL: StackPool = StackPool(UnsafeCell::new([[0; PAGE_SIZE as usize]; POOL_PAGES]));
+
+/// Hands out pool pages and stack addresses in order, never to be freed.
+struct Allocator {
+    next_page: usize,
+    /// The lowest page of the region not handed out yet.
+    next_virt: VirtAddr,
+}
+
+static ALLOCATOR: SpinLock<Allocator> = SpinLock::new(Allocator {
+    next_page: 0,
+    next_virt: VirtAddr(STACK_REGION),
+});
+
+/// A stack that lives as long as the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    /// Allocate a stack of `pages` pages, with an unmapped guard page below.
+    /// # Panics
+    /// Panics if the pool runs out of pages, or the stack can't be mapped.
+    pub fn new(pages: usize) -> Self {
+        assert!(pages > 0, "empty stack");
+        without_interrupts(|| {
+            let mut allocator = ALLOCATOR.lock();
+            assert!(
+                allocator.next_page + pages <= POOL_PAGES,
+                "out of stack pages"
+            );
+            let bottom = allocator.next_virt + PAGE_SIZE;
+            for i in 0..pages {
+                // Safety: Only the address is taken.
+                let page = unsafe { (*POOL.0.get())[allocator.next_page + i].as_ptr() };
+                let frame = paging::translate(VirtAddr::from_ptr(page))
+                    .expect("stack pool isn't mapped");
+                let virt = bottom + i as u64 * PAGE_SIZE;
+                let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+                // Safety: The frame is ours alone from now on.
+                unsafe { paging::map(virt, frame, flags) }.expect("failed to map stack");
+            }
+            allocator.next_page += pages;
+            let top = bottom + pages as u64 * PAGE_SIZE;
+            allocator.next_virt = top;
+            Self { bottom, top }
+        })
+    }
+
+    /// The initial stack pointer. Notice that the stack grows downward.
+    /// It's page aligned, so it satisfies the 16 bytes alignment of the ABI.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// The lowest address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// The unmapped page right below the stack.
+    pub fn guard_page(&self) -> VirtAddr {
+        VirtAddr(self.bottom.0 - PAGE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_kernel_stack() {
+        let stack = KernelStack::new(1);
+        let top = stack.top();
+        assert_ne!(top, VirtAddr::zero());
+        assert_eq!(top.0 % 16, 0);
+        assert_eq!(top.0 - stack.bottom().0, PAGE_SIZE);
+
+        assert!(paging::translate(stack.bottom()).is_some());
+        assert_eq!(paging::translate(stack.guard_page()), None);
+        let slot = (top.0 - 8) as *mut u64;
+        unsafe {
+            core::ptr::write_volatile(slot, 0x5a5a);
+            assert_eq!(core::ptr::read_volatile(slot), 0x5a5a);
+        }
+
+        // The next stack doesn't share the guard page.
+        let next = KernelStack::new(1);
+        assert_eq!(next.guard_page(), top);
+    }
+}
@@ -0,0 +1,71 @@
+// Memory-mapped registers, the sibling of `port`.
+
+use core::marker::PhantomData;
+
+use crate::port::access;
+
+pub type Mmio<T> = MmioGeneric<T, access::ReadWrite>;
+
+/// T is the type of the register we read from or write to.
+/// A is the access permission of the register.
+pub struct MmioGeneric<T, A> {
+    addr: *mut T,
+    _phantom: PhantomData<A>,
+}
+
+impl<T, A> MmioGeneric<T, A> {
+    /// Safety:
+    /// * addr is mapped, aligned for T and points to the register for as
+    ///   long as the value lives.
+    pub const unsafe fn new(addr: usize) -> Self {
+        Self {
+            addr: addr as *mut T,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr as usize
+    }
+}
+
+impl<T: Copy, A: access::Readable> MmioGeneric<T, A> {
+    pub fn read(&self) -> T {
+        // Safety: The address is valid, see `new`.
+        unsafe { core::ptr::read_volatile(self.addr) }
+    }
+}
+
+impl<T: Copy, A: access::Writable> MmioGeneric<T, A> {
+    pub fn write(&mut self, value: T) {
+        // Safety: The address is valid, see `new`.
+        unsafe { core::ptr::write_volatile(self.addr, value) }
+    }
+}
+
+// Safety:
+// It's only an address. Whoever holds it gets the access, just like a
+// Port, and the register is valid for its lifetime by `new`.
+unsafe impl<T: Send, A> Send for MmioGeneric<T, A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_mmio_read_write() {
+        let mut register: u32 = 0x1234;
+        let addr = &mut register as *mut u32 as usize;
+        {
+            let mut mmio: Mmio<u32> = unsafe { Mmio::new(addr) };
+            assert_eq!(mmio.read(), 0x1234);
+            mmio.write(0xdead_beef);
+            assert_eq!(mmio.read(), 0xdead_beef);
+
+            let read_only: MmioGeneric<u32, access::ReadOnly> =
+                unsafe { MmioGeneric::new(addr) };
+            assert_eq!(read_only.read(), 0xdead_beef);
+        }
+        assert_eq!(register, 0xdead_beef);
+    }
+}
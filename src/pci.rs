@@ -0,0 +1,192 @@
+// PCI configuration space through the legacy I/O ports.
+// See https://wiki.osdev.org/PCI#Configuration_Space_Access_Mechanism_.231
+
+use crate::bit_field::BitField;
+use crate::port::{Port, PortRead, PortWrite};
+use crate::spinlock::SpinLock;
+use crate::x86_64::without_interrupts;
+
+/// Vendor ID read back where there's no device.
+const NO_DEVICE: u16 = 0xffff;
+
+/// Offset of the register holding the header type.
+const HEADER_TYPE_OFFSET: u8 = 0x0c;
+/// Set in the header type if the device has functions other than 0.
+const MULTI_FUNCTION_BIT: u8 = 23;
+
+struct ConfigPorts {
+    address: Port<u32>,
+    data: Port<u32>,
+}
+
+// The address and the data access must not be interleaved with another's.
+static CONFIG: SpinLock<ConfigPorts> = SpinLock::new(ConfigPorts {
+    address: Port::new(0xcf8),
+    data: Port::new(0xcfc),
+});
+
+/// What's written to CONFIG_ADDRESS to reach a register.
+/// # Panics
+/// Panics if slot isn't below 32, func isn't below 8 or offset isn't 4
+/// bytes aligned.
+fn config_address(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+    assert!(slot < 32 && func < 8, "no PCI function {}:{}", slot, func);
+    assert!(offset % 4 == 0, "unaligned PCI register {:#x}", offset);
+    let mut address = 0u32;
+    address.set_bits(31, 1);
+    address.set_bits(16..24, bus as u32);
+    address.set_bits(11..16, slot as u32);
+    address.set_bits(8..11, func as u32);
+    address.set_bits(0..8, offset as u32);
+    address
+}
+
+/// Read the 32-bit register at `offset` of a function's configuration
+/// space. All ones if there's no such function.
+/// # Panics
+/// See `config_address`.
+pub fn config_read_u32(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+    let address = config_address(bus, slot, func, offset);
+    without_interrupts(|| {
+        let mut ports = CONFIG.lock();
+        // Safety: These are the configuration ports, reading a register
+        // has no side effect.
+        unsafe {
+            ports.address.write(address);
+            ports.data.read()
+        }
+    })
+}
+
+/// Write the 32-bit register at `offset` of a function's configuration
+/// space.
+///
+/// Safety:
+/// * It may reprogram the device, e.g. move its BARs over memory in use.
+///
+/// # Panics
+/// See `config_address`.
+pub unsafe fn config_write_u32(bus: u8, slot: u8, func: u8, offset: u8, value: u32) {
+    let address = config_address(bus, slot, func, offset);
+    without_interrupts(|| {
+        let mut ports = CONFIG.lock();
+        // Safety: Guaranteed by the caller.
+        unsafe {
+            ports.address.write(address);
+            ports.data.write(value);
+        }
+    })
+}
+
+/// A present PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+}
+
+impl Device {
+    fn probe(bus: u8, slot: u8, func: u8) -> Option<Self> {
+        let id = config_read_u32(bus, slot, func, 0);
+        let vendor_id = id.get_bits(0..16) as u16;
+        if vendor_id == NO_DEVICE {
+            return None;
+        }
+        Some(Self {
+            bus,
+            slot,
+            func,
+            vendor_id,
+            device_id: id.get_bits(16..32) as u16,
+        })
+    }
+
+    fn is_multi_function(&self) -> bool {
+        let header = config_read_u32(self.bus, self.slot, self.func, HEADER_TYPE_OFFSET);
+        header.get_bits(MULTI_FUNCTION_BIT) == 1
+    }
+}
+
+/// All present functions on all buses, by brute force.
+pub fn enumerate() -> Enumerate {
+    Enumerate {
+        bus: 0,
+        slot: 0,
+        func: 0,
+        multi_function: false,
+    }
+}
+
+/// See `enumerate`.
+pub struct Enumerate {
+    /// Wider than a bus number, to tell when it's past the last bus.
+    bus: u16,
+    slot: u8,
+    func: u8,
+    /// Whether the slot has functions other than 0.
+    multi_function: bool,
+}
+
+impl Enumerate {
+    /// Move on to the next function to look at.
+    fn advance(&mut self) {
+        if self.func + 1 < 8 && self.multi_function {
+            self.func += 1;
+            return;
+        }
+        self.func = 0;
+        self.multi_function = false;
+        self.slot += 1;
+        if self.slot == 32 {
+            self.slot = 0;
+            self.bus += 1;
+        }
+    }
+}
+
+impl Iterator for Enumerate {
+    type Item = Device;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.bus < 256 {
+            let (bus, slot, func) = (self.bus as u8, self.slot, self.func);
+            let device = Device::probe(bus, slot, func);
+            if let (Some(device), 0) = (device, func) {
+                self.multi_function = device.is_multi_function();
+            }
+            self.advance();
+            if device.is_some() {
+                return device;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_config_address() {
+        assert_eq!(config_address(0, 0, 0, 0), 0x8000_0000);
+        assert_eq!(config_address(0xab, 31, 7, 0xfc), 0x80ab_fffc);
+    }
+
+    #[test_case]
+    fn test_host_bridge() {
+        let id = config_read_u32(0, 0, 0, 0);
+        assert_ne!(id.get_bits(0..16), NO_DEVICE as u32);
+
+        // The host bridge comes first.
+        let mut devices = enumerate();
+        let bridge = devices.next().unwrap();
+        assert_eq!((bridge.bus, bridge.slot, bridge.func), (0, 0, 0));
+        assert_eq!(bridge.vendor_id as u32, id.get_bits(0..16));
+        // QEMU has a VGA card at least.
+        assert!(devices.next().is_some());
+    }
+}
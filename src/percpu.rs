@@ -0,0 +1,77 @@
+// Per-CPU data, reached through the GS segment base.
+//
+// There's only one CPU for now, so there's a single static instance.
+// Once there are more, each CPU gets its own and points its GS base at it.
+
+use crate::x86_64::{rdmsr, wrmsr, IA32_GS_BASE};
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, AtomicUsize};
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Points back to self, so `get` can find it with a single gs-relative
+    /// load. Must stay the first field.
+    this: *const PerCpu,
+    /// Id of the task running on this CPU.
+    pub current_task: AtomicUsize,
+    /// Top of the kernel stack of the current task.
+    pub kernel_stack: AtomicU64,
+}
+
+// Safety: All the mutable fields are atomic, and `this` never changes.
+unsafe impl Sync for PerCpu {}
+
+static BSP: PerCpu = PerCpu {
+    this: &BSP,
+    current_task: AtomicUsize::new(0),
+    kernel_stack: AtomicU64::new(0),
+};
+
+/// Point GS base at this CPU's data.
+pub fn init() {
+    // Safety: IA32_GS_BASE exists in long mode, and nothing else uses GS.
+    unsafe { wrmsr(IA32_GS_BASE, &BSP as *const PerCpu as u64) }
+}
+
+/// Whether GS base points at our per-CPU data.
+pub fn is_loaded() -> bool {
+    // Safety: IA32_GS_BASE exists in long mode.
+    let base = unsafe { rdmsr(IA32_GS_BASE) };
+    base == &BSP as *const PerCpu as u64
+}
+
+/// The data of the current CPU.
+/// # Panics
+/// Panics if `init` hasn't been called.
+pub fn get() -> &'static PerCpu {
+    assert!(is_loaded(), "per-CPU data isn't set up");
+    let this: *const PerCpu;
+    // Safety: GS base points at a PerCpu whose first field is `this`.
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) this,
+            options(readonly, nostack, preserves_flags)
+        );
+        &*this
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::Ordering;
+
+    #[test_case]
+    fn test_percpu_get() {
+        const SENTINEL: u64 = 0xdead_beef_cafe_f00d;
+
+        init();
+        assert!(is_loaded());
+        assert!(core::ptr::eq(get(), &BSP));
+
+        BSP.kernel_stack.store(SENTINEL, Ordering::Relaxed);
+        assert_eq!(get().kernel_stack.load(Ordering::Relaxed), SENTINEL);
+        BSP.kernel_stack.store(0, Ordering::Relaxed);
+    }
+}
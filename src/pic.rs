@@ -1,8 +1,6 @@
 /// See pic8259
 
-use crate::port::{
-    access, Port, PortWrite, PortRead,
-};
+use crate::port::{PortRead, PortWrite};
 
 /// Command sent to begin PIC initialization.
 const CMD_INIT: u8 = 0x11;
@@ -10,25 +8,43 @@ const CMD_INIT: u8 = 0x11;
 const CMD_END_OF_INTERRUPT: u8 = 0x20;
 /// The mode in which we want to run our PICSs.
 const MODE_8086: u8 = 0x01;
+/// OCW3 commands to select what the next read of the command port returns.
+const CMD_READ_IRR: u8 = 0x0a;
+const CMD_READ_ISR: u8 = 0x0b;
+
+crate::port_registers! {
+    struct PicRegs {
+        cmd: u8 @ 0,
+        data: u8 @ 1,
+    }
+}
 
 struct Pic {
     offset: u8,
-    cmd: Port<u8>,
-    data: Port<u8>,
+    regs: PicRegs,
 }
 
 impl Pic {
     unsafe fn end_of_interrupt(&mut self) {
-        unsafe {self.cmd.write(CMD_END_OF_INTERRUPT); }
+        unsafe {self.regs.cmd().write(CMD_END_OF_INTERRUPT); }
     }
 
     unsafe fn read_mask(&mut self) -> u8 {
         // TODO: why can we just read the data port for the mask?
-        unsafe { self.data.read() }
+        unsafe { self.regs.data().read() }
     }
 
     unsafe fn write_mask(&mut self, mask: u8) {
-        unsafe { self.data.write(mask) }
+        unsafe { self.regs.data().write(mask) }
+    }
+
+    /// Read the IRR or the ISR, as selected by an OCW3 command. Unlike the
+    /// mask, they're read from the command port.
+    unsafe fn read_register(&mut self, ocw3: u8) -> u8 {
+        unsafe {
+            self.regs.cmd().write(ocw3);
+            self.regs.cmd().read()
+        }
     }
 
     fn handles_interrupt(&self, interrupt_id: u8) -> bool {
@@ -49,13 +65,11 @@ impl ChainedPics {
             pics: [
                 Pic {
                     offset: offset1,
-                    cmd: Port::new(0x20),
-                    data: Port::new(0x21),
+                    regs: PicRegs::new(0x20),
                 },
                 Pic {
                     offset: offset2,
-                    cmd: Port::new(0xa0),
-                    data: Port::new(0xa1),
+                    regs: PicRegs::new(0xa0),
                 },
             ]
         }
@@ -75,40 +89,42 @@ impl ChainedPics {
     }
 
     pub unsafe fn initialize(&mut self) {
-        let mut wait_port: Port<u8> = Port::new(0x80);
-        let mut wait = || unsafe { wait_port.write(0) };
+        // Old 8259s need time to take each byte of the initialization
+        // sequence, and there's no status to poll for it. So we give them
+        // an I/O delay after every write.
+        let wait = crate::x86_64::io_wait;
 
         let saved_mask = self.read_masks();
 
         unsafe {
             // Tell each PIC that we're going to send it a three-byte
             // initialization sequence on its data port.
-            self.pics[0].cmd.write(CMD_INIT);
+            self.pics[0].regs.cmd().write(CMD_INIT);
             wait();
-            self.pics[1].cmd.write(CMD_INIT);
+            self.pics[1].regs.cmd().write(CMD_INIT);
             wait();
 
             // Byte 1: Set up base offset
-            self.pics[0].data.write(self.pics[0].offset);
+            self.pics[0].regs.data().write(self.pics[0].offset);
             wait();
-            self.pics[1].data.write(self.pics[1].offset);
+            self.pics[1].regs.data().write(self.pics[1].offset);
             wait();
 
             // Byte 2: Confiture chaining between PIC1 and PIC2
-            self.pics[0].data.write(4);
+            self.pics[0].regs.data().write(4);
             wait();
-            self.pics[1].data.write(2);
+            self.pics[1].regs.data().write(2);
             wait();
 
             // Byte 3: Set out mode
-            self.pics[0].data.write(MODE_8086);
+            self.pics[0].regs.data().write(MODE_8086);
             wait();
-            self.pics[1].data.write(MODE_8086);
+            self.pics[1].regs.data().write(MODE_8086);
             wait();
 
             self.write_masks(saved_mask[0], saved_mask[1]);
-            // There is no more waiting after write_masks in pic8259 crate.
-            // But why? I gonna add it anyway.
+            // pic8259 doesn't wait after restoring the masks, but it's
+            // cheap enough to be on the safe side.
             wait();
         }
     }
@@ -117,10 +133,109 @@ impl ChainedPics {
         self.pics.iter().any(|p| p.handles_interrupt(interrupt_id))
     }
 
+    /// Locate the PIC and the bit for an IRQ line.
+    /// # Panics
+    /// Panics if irq isn't in 0..16.
+    fn irq_bit(irq: u8) -> (usize, u8) {
+        assert!(irq < 16, "irq out of range");
+        ((irq / 8) as usize, 1 << (irq % 8))
+    }
+
+    /// Mask an IRQ line so that it's not delivered anymore.
+    /// # Panics
+    /// Panics if irq isn't in 0..16.
+    pub unsafe fn mask(&mut self, irq: u8) {
+        let (pic, bit) = Self::irq_bit(irq);
+        unsafe {
+            let mask = self.pics[pic].read_mask();
+            self.pics[pic].write_mask(mask | bit);
+        }
+    }
+
+    /// Unmask an IRQ line. Lines on the secondary PIC also need the
+    /// cascade line (IRQ 2) unmasked, so we do it as well.
+    /// # Panics
+    /// Panics if irq isn't in 0..16.
+    pub unsafe fn unmask(&mut self, irq: u8) {
+        let (pic, bit) = Self::irq_bit(irq);
+        unsafe {
+            let mask = self.pics[pic].read_mask();
+            self.pics[pic].write_mask(mask & !bit);
+            if pic == 1 {
+                self.unmask(2);
+            }
+        }
+    }
+
+    /// # Panics
+    /// Panics if irq isn't in 0..16.
+    pub fn is_masked(&mut self, irq: u8) -> bool {
+        let (pic, bit) = Self::irq_bit(irq);
+        self.read_masks()[pic] & bit != 0
+    }
+
+    /// The in-service register, with IRQ n at bit n: the IRQs whose
+    /// handlers are running and haven't sent an end of interrupt yet.
+    pub fn read_isr(&mut self) -> u16 {
+        self.read_registers(CMD_READ_ISR)
+    }
+
+    /// The interrupt request register, with IRQ n at bit n: the IRQs that
+    /// are raised but not delivered yet, e.g. while interrupts are
+    /// disabled.
+    pub fn read_irr(&mut self) -> u16 {
+        self.read_registers(CMD_READ_IRR)
+    }
+
+    fn read_registers(&mut self, ocw3: u8) -> u16 {
+        let [low, high] = unsafe {
+            [self.pics[0].read_register(ocw3), self.pics[1].read_register(ocw3)]
+        };
+        u16::from_le_bytes([low, high])
+    }
+
+    /// The masks of both PICs, for `restore_masks`.
+    pub fn save_masks(&mut self) -> [u8; 2] {
+        self.read_masks()
+    }
+
+    /// Put back masks from `save_masks`, e.g. to fall back to the PICs
+    /// after `disable`.
+    pub unsafe fn restore_masks(&mut self, masks: [u8; 2]) {
+        self.write_masks(masks[0], masks[1]);
+    }
+
+    /// Mask all IRQs, as needed before switching to the APIC. Save the
+    /// masks first to be able to come back.
+    ///
+    /// The PICs are remapped first, since they may still raise spurious
+    /// IRQs 7 and 15 with everything masked, which would otherwise land on
+    /// the exception vectors.
     pub unsafe fn disable(&mut self) {
+        unsafe { self.initialize() };
         self.write_masks(u8::MAX, u8::MAX);
     }
 
+    /// Whether the interrupt is a spurious IRQ 7 or 15, raised for a
+    /// request that went away before it was acknowledged. Its ISR bit is
+    /// clear then, and it must not get a normal end of interrupt.
+    pub fn is_spurious(&mut self, interrupt_id: u8) -> bool {
+        self.pics.iter_mut().any(|pic| {
+            // Safety: Reading the ISR has no side effect.
+            interrupt_id == pic.offset + 7
+                && unsafe { pic.read_register(CMD_READ_ISR) } & 1 << 7 == 0
+        })
+    }
+
+    /// Acknowledge a spurious interrupt, see `is_spurious`. Only the
+    /// primary PIC gets an end of interrupt, for the cascade line it
+    /// raised on behalf of the secondary.
+    pub unsafe fn notify_spurious_interrupt(&mut self, interrupt_id: u8) {
+        if self.pics[1].handles_interrupt(interrupt_id) {
+            unsafe { self.pics[0].end_of_interrupt(); }
+        }
+    }
+
     pub unsafe fn notify_end_of_interrupt(&mut self, interrupt_id: u8) {
         if self.handles_interrupt(interrupt_id) {
             if self.pics[1].handles_interrupt(interrupt_id) {
@@ -0,0 +1,86 @@
+// The programmable interval timer.
+// See https://wiki.osdev.org/Programmable_Interval_Timer
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::x86_64;
+
+/// Frequency of the PIT's input clock in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// We leave channel 0 at its power-on divisor, which gives us ~18.2 Hz.
+const TIMER_DIVISOR: u64 = 65536;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer interrupts since the timer is enabled.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of ticks that covers at least `ms` milliseconds.
+pub(crate) const fn ms_to_ticks(ms: u64) -> u64 {
+    let ticks = ms as u128 * BASE_FREQUENCY as u128;
+    let per_tick = TIMER_DIVISOR as u128 * 1000;
+    ticks.div_ceil(per_tick) as u64
+}
+
+/// Number of whole milliseconds in `ticks` ticks.
+pub(crate) const fn ticks_to_ms(ticks: u64) -> u64 {
+    let ms = ticks as u128 * TIMER_DIVISOR as u128 * 1000;
+    (ms / BASE_FREQUENCY as u128) as u64
+}
+
+/// Halt until at least `ms` milliseconds have passed. The resolution is
+/// one tick, i.e. ~55ms.
+/// # Panics
+/// Panics if interrupts are disabled, since we'd never wake up.
+pub fn sleep_ms(ms: u64) {
+    assert!(x86_64::is_interrupt_enabled(), "sleep with interrupts disabled");
+    let deadline = ticks() + ms_to_ticks(ms);
+    loop {
+        // Check with interrupts off, so the tick can't arrive after the
+        // check but before the halt.
+        x86_64::disable_interrupt();
+        if ticks() >= deadline {
+            x86_64::enable_interrupt();
+            break;
+        }
+        x86_64::enable_and_hlt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_ms_to_ticks() {
+        assert_eq!(ms_to_ticks(0), 0);
+        assert_eq!(ms_to_ticks(1), 1);
+        assert_eq!(ms_to_ticks(54), 1);
+        assert_eq!(ms_to_ticks(55), 2);
+        assert_eq!(ms_to_ticks(1000), 19);
+    }
+
+    #[test_case]
+    fn test_ticks_to_ms() {
+        assert_eq!(ticks_to_ms(0), 0);
+        assert_eq!(ticks_to_ms(1), 54);
+        assert_eq!(ticks_to_ms(18), 988);
+        assert_eq!(ticks_to_ms(ms_to_ticks(1000)), 1043);
+    }
+
+    #[test_case]
+    fn test_sleep_ms() {
+        crate::init();
+        let start = ticks();
+        sleep_ms(100);
+        assert!(ticks() - start >= 2);
+    }
+}
@@ -34,6 +34,55 @@ impl<T, A> PortGeneric<T, A> {
 
 }
 
+/// Declare the registers of a device as named ports at offsets from its
+/// base port, so that the offsets are written down in one place:
+///
+/// ```ignore
+/// port_registers! {
+///     struct Uart {
+///         data: u8 @ 0,
+///         line_status: u8 @ 5,
+///     }
+/// }
+/// let lsr: u8 = unsafe { Uart::new(0x3f8).line_status().read() };
+/// ```
+#[macro_export]
+macro_rules! port_registers {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$reg_attr:meta])*
+                $reg:ident: $ty:ident @ $offset:literal
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        $vis struct $name {
+            base: u16,
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            pub const fn new(base: u16) -> Self {
+                Self { base }
+            }
+
+            pub const fn base(self) -> u16 {
+                self.base
+            }
+
+            $(
+                $(#[$reg_attr])*
+                pub const fn $reg(self) -> $crate::port::Port<$ty> {
+                    $crate::port::Port::new(self.base + $offset)
+                }
+            )*
+        }
+    };
+}
+
 pub trait PortWrite<T> {
     unsafe fn write(&mut self, value: T);
 }
@@ -129,3 +178,29 @@ impl<A: access::Readable> PortRead<u32> for PortGeneric<u32, A> {
         value
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::port_registers! {
+        struct Mock {
+            data: u8 @ 0,
+            /// Holds whatever is written to it.
+            scratch: u8 @ 7,
+        }
+    }
+
+    #[test_case]
+    fn test_port_registers() {
+        let regs = Mock::new(0x3f8);
+        assert_eq!(regs.base(), 0x3f8);
+        assert_eq!(regs.data().port, 0x3f8);
+        assert_eq!(regs.scratch().port, 0x3ff);
+
+        let original: u8 = unsafe { regs.scratch().read() };
+        unsafe { regs.scratch().write(0x5a) };
+        assert_eq!(unsafe { regs.scratch().read() }, 0x5a);
+        unsafe { regs.scratch().write(original) };
+    }
+}
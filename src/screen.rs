@@ -1,22 +1,29 @@
+pub mod cp437;
+mod volatile;
+pub mod vt;
+pub mod widgets;
+
+use crate::console::Console;
 use crate::lazy_static;
 use crate::spinlock::SpinLock;
+use volatile::Volatile;
 
 const BRIGHT_BIT: u8 = 1 << 3;
 #[allow(dead_code)]
 const BLINK_BIT: u8 = 1 << 7;
 
-const VGA_BUFFER_ROWS: usize = 25;
-const VGA_BUFFER_COLUMNS: usize = 80;
+pub const VGA_BUFFER_ROWS: usize = 25;
+pub const VGA_BUFFER_COLUMNS: usize = 80;
 #[allow(dead_code)]
 const VGA_BUFFER_SIZE: usize = VGA_BUFFER_COLUMNS * VGA_BUFFER_ROWS * 2;
-const VGA_BUFFER_ADDR: *mut () = 0xb8000 as *mut ();
+const VGA_BUFFER_ADDR: usize = 0xb8000;
 
 /// Depending on the setup, the bright bit of background color may be
 /// used as the blink bit.
 /// See https://en.wikipedia.org/wiki/VGA_text_mode#endnote_text_buffer_1
 #[allow(dead_code)]
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Color {
     Black = 0,
     Blue = 1,
@@ -38,19 +45,79 @@ pub enum Color {
     White = Self::LightGray as u8 | BRIGHT_BIT,
 }
 
+impl Color {
+    /// Get the color from the low 4 bits of v.
+    const fn from_nibble(v: u8) -> Self {
+        const COLORS: [Color; 16] = [
+            Color::Black,
+            Color::Blue,
+            Color::Green,
+            Color::Cyan,
+            Color::Red,
+            Color::Magenta,
+            Color::Brown,
+            Color::LightGray,
+            Color::DarkGray,
+            Color::LightBlue,
+            Color::LightGreen,
+            Color::LightCyan,
+            Color::LightRed,
+            Color::Pink,
+            Color::Yellow,
+            Color::White,
+        ];
+        COLORS[(v & 0xf) as usize]
+    }
+}
+
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
     pub const fn new(foreground: Color, background: Color) -> Self {
         Self(foreground as u8 | (background as u8) << 4)
     }
 
+    pub const fn foreground(self) -> Color {
+        Color::from_nibble(self.0)
+    }
+
+    /// The blink bit is ignored.
+    pub const fn background(self) -> Color {
+        Color::from_nibble((self.0 >> 4) & !BRIGHT_BIT)
+    }
+
     #[allow(dead_code)]
     pub fn blink(self) -> Self {
         Self(self.0 | BLINK_BIT)
     }
+
+    /// Swap foreground and background, i.e. reverse video. Only the three
+    /// color bits are swapped: the bright and blink bits stay, otherwise a
+    /// bright foreground would start blinking.
+    pub const fn reverse(self) -> Self {
+        const ATTRS: u8 = 0x88;
+        let colors = self.0 & !ATTRS;
+        Self(self.0 & ATTRS | colors.rotate_left(4))
+    }
+}
+
+/// A code point of the VGA font, i.e. CP437.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VgaChar(pub u8);
+
+/// The char has no glyph in the VGA font.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnmappableChar(pub char);
+
+impl TryFrom<char> for VgaChar {
+    type Error = UnmappableChar;
+
+    fn try_from(ch: char) -> Result<Self, Self::Error> {
+        cp437::from_char(ch).map(VgaChar).ok_or(UnmappableChar(ch))
+    }
 }
 
 #[repr(C)]
@@ -72,75 +139,261 @@ impl ScreenChar {
     }
 }
 
-// TODO:
-// It's not good. It's protected by convention, not the type system.
-/// Wrapper that indicates inner should not be written directly
-/// without using volatile.
-#[repr(transparent)]
-struct Volatile<T>(T);
-
 /// Type alias for non-volatile buffer row.
 /// It's easier to use for the users of VgaBuffer.
 type VgaBufferRow = [ScreenChar; VGA_BUFFER_COLUMNS];
 
-// I prefer not to depends on an outside crate unless absolutely
-// neccessary, so I don't use `volatile` crate here. Instead, I
-// wrap them by myself.
-#[repr(transparent)]
-struct VgaBuffer([[Volatile<ScreenChar>; VGA_BUFFER_COLUMNS]; VGA_BUFFER_ROWS]);
+type VgaBufferCells = [Volatile<VgaBufferRow>; VGA_BUFFER_ROWS];
+
+struct VgaBuffer {
+    base: *mut VgaBufferCells,
+}
+
+// Safety:
+// VgaBuffer owns the memory it points to, see `with_base`.
+unsafe impl Send for VgaBuffer {}
 
 impl VgaBuffer {
+    /// Use the memory at base as the VGA buffer.
+    ///
+    /// Safety:
+    /// - base must point to VGA_BUFFER_SIZE bytes of valid memory, and
+    /// - nothing else accesses that memory while the VgaBuffer is alive.
+    pub unsafe fn with_base(base: usize) -> Self {
+        Self {
+            base: base as *mut VgaBufferCells,
+        }
+    }
+
+    fn cells(&self) -> &VgaBufferCells {
+        // Safety: See `with_base`.
+        unsafe { &*self.base }
+    }
+
+    fn cells_mut(&mut self) -> &mut VgaBufferCells {
+        // Safety: See `with_base`.
+        unsafe { &mut *self.base }
+    }
+
     /// Read a ScreenChar to the VGA buffer.
     /// # Panics
     /// Panics if row or col goes outside of the screen.
-    #[allow(dead_code)]
     pub fn read_char(&self, row: usize, col: usize) -> ScreenChar {
-        // Safety: self.0[row][col] will panics otherwise.
-        unsafe { core::ptr::read_volatile(&self.0[row][col]).0 }
+        self.cells()[row].at(col).read()
     }
 
     /// Write a ScreenChar to the VGA buffer.
     /// # Panics
     /// Panics if row or col goes outside of the screen.
     pub fn write_char(&mut self, row: usize, col: usize, ch: ScreenChar) {
-        // Safety: self.0[row][col] will panics otherwise.
-        unsafe {
-            core::ptr::write_volatile(&mut self.0[row][col], Volatile(ch));
-        }
+        self.cells_mut()[row].at_mut(col).write(ch);
     }
 
     /// Read a row at idx.
     /// # Panics
     /// Panics if idx goes outside of the screen
     pub fn read_row(&self, idx: usize) -> VgaBufferRow {
-        // Safety: self.0[idx] will panics otherwise.
-        unsafe { core::ptr::read_volatile(&self.0[idx] as *const _ as *const VgaBufferRow) }
+        self.cells()[idx].read()
     }
 
     /// Write a row at idx.
     /// # Panics
     /// Panics if idx goes outside of the screen
     pub fn write_row(&mut self, idx: usize, row: VgaBufferRow) {
-        // Safety: self.0[idx] will panics otherwise.
-        unsafe {
-            core::ptr::write_volatile(&mut self.0[idx] as *mut _ as *mut VgaBufferRow, row);
+        self.cells_mut()[idx].write(row);
+    }
+}
+
+/// All rows of a screen, in memory.
+type ScreenRows = [VgaBufferRow; VGA_BUFFER_ROWS];
+
+/// Screen chars that `Screen` draws on, either the VGA buffer or a
+/// copy in memory.
+trait Cells {
+    fn read_char(&self, row: usize, col: usize) -> ScreenChar;
+    fn write_char(&mut self, row: usize, col: usize, ch: ScreenChar);
+    fn read_row(&self, idx: usize) -> VgaBufferRow;
+    fn write_row(&mut self, idx: usize, row: VgaBufferRow);
+}
+
+impl Cells for VgaBuffer {
+    fn read_char(&self, row: usize, col: usize) -> ScreenChar {
+        VgaBuffer::read_char(self, row, col)
+    }
+
+    fn write_char(&mut self, row: usize, col: usize, ch: ScreenChar) {
+        VgaBuffer::write_char(self, row, col, ch)
+    }
+
+    fn read_row(&self, idx: usize) -> VgaBufferRow {
+        VgaBuffer::read_row(self, idx)
+    }
+
+    fn write_row(&mut self, idx: usize, row: VgaBufferRow) {
+        VgaBuffer::write_row(self, idx, row)
+    }
+}
+
+impl Cells for ScreenRows {
+    fn read_char(&self, row: usize, col: usize) -> ScreenChar {
+        self[row][col]
+    }
+
+    fn write_char(&mut self, row: usize, col: usize, ch: ScreenChar) {
+        self[row][col] = ch;
+    }
+
+    fn read_row(&self, idx: usize) -> VgaBufferRow {
+        self[idx]
+    }
+
+    fn write_row(&mut self, idx: usize, row: VgaBufferRow) {
+        self[idx] = row;
+    }
+}
+
+/// Write `s` starting at the given position, clipped at the right boundary.
+fn write_str_at(cells: &mut dyn Cells, row: usize, col: usize, s: &str, color: ColorCode) {
+    for (c, ch) in (col..VGA_BUFFER_COLUMNS).zip(s.chars()) {
+        cells.write_char(row, c, ScreenChar::new(glyph(ch), color));
+    }
+}
+
+/// The most rows the history can hold.
+pub const SCROLLBACK_ROWS: usize = 100;
+
+/// Rows that have scrolled off the top of the screen.
+struct History {
+    rows: [VgaBufferRow; SCROLLBACK_ROWS],
+    /// Rows in use, at most SCROLLBACK_ROWS.
+    capacity: usize,
+    /// Index of the oldest row.
+    start: usize,
+    len: usize,
+}
+
+impl History {
+    fn new() -> Self {
+        Self {
+            rows: [[ScreenChar::Blank; VGA_BUFFER_COLUMNS]; SCROLLBACK_ROWS],
+            capacity: SCROLLBACK_ROWS,
+            start: 0,
+            len: 0,
         }
     }
+
+    /// Add the newest row, dropping the oldest one if it's full.
+    fn push(&mut self, row: VgaBufferRow) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.len < self.capacity {
+            self.rows[(self.start + self.len) % self.capacity] = row;
+            self.len += 1;
+        } else {
+            self.rows[self.start] = row;
+            self.start = (self.start + 1) % self.capacity;
+        }
+    }
+
+    /// Keep at most `capacity` rows, dropping the oldest ones.
+    /// # Panics
+    /// Panics if capacity > SCROLLBACK_ROWS.
+    fn set_capacity(&mut self, capacity: usize) {
+        assert!(capacity <= SCROLLBACK_ROWS, "scrollback capacity too large");
+        // Move the oldest row to the front, then drop what doesn't fit.
+        self.rows[..self.capacity].rotate_left(self.start);
+        let dropped = self.len.saturating_sub(capacity);
+        self.rows.copy_within(dropped..self.len, 0);
+        self.len -= dropped;
+        self.start = 0;
+        self.capacity = capacity;
+    }
+
+    /// Get a row, 0 being the oldest.
+    /// # Panics
+    /// Panics if idx >= len.
+    fn get(&self, idx: usize) -> &VgaBufferRow {
+        assert!(idx < self.len);
+        &self.rows[(self.start + idx) % self.capacity]
+    }
+}
+
+/// How `puts` breaks lines that don't fit on the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapMode {
+    /// Break at the right boundary, even in the middle of a word.
+    Char,
+    /// Break before a word that would overflow the line. Words longer
+    /// than a whole line still fall back to char wrap.
+    Word,
 }
 
 pub struct Screen {
     row: usize,
     col: usize,
-    buffer: &'static mut VgaBuffer,
+    buffer: VgaBuffer,
 
     color_code: ColorCode,
+    wrap: WrapMode,
+
+    history: History,
+    /// How many rows the view is scrolled up into the history. 0 means
+    /// the live screen is shown.
+    scroll: usize,
+    /// The live screen while the history is shown. Output goes here
+    /// meanwhile.
+    live: ScreenRows,
+    /// Rows scrolled off the top so far, wrapping around. Things drawn at
+    /// a fixed place compare it to tell that they've moved.
+    scrolled: usize,
+
+    /// None if the software cursor is disabled.
+    soft_cursor: Option<SoftCursor>,
+}
+
+/// Everything shown on a screen, to be put back later by `Screen::restore`.
+#[derive(Clone)]
+pub struct ScreenSnapshot {
+    rows: ScreenRows,
+    row: usize,
+    col: usize,
+    color_code: ColorCode,
+}
+
+/// Clears the screen when printed.
+const FORM_FEED: u8 = 0x0c;
+
+/// Colors a new screen starts with, until changed by `with_color`.
+pub const DEFAULT_FOREGROUND: Color = Color::Yellow;
+pub const DEFAULT_BACKGROUND: Color = Color::Black;
+
+const CURSOR_BLINK_TICKS: u64 = crate::pit::ms_to_ticks(500);
+
+/// A cursor drawn by inverting the colors of the cell under it, for when
+/// the hardware cursor isn't available.
+struct SoftCursor {
+    /// Where the cursor is drawn and the cell it covers.
+    shown: Option<(usize, usize, ScreenChar)>,
+    /// Tick count of the next toggle.
+    next_toggle: u64,
 }
 
 impl Screen {
     fn new() -> Self {
         // Safety:
         // This is the vga buffer and we are the only user.
-        let buffer = unsafe { &mut *(VGA_BUFFER_ADDR as *mut VgaBuffer) };
+        unsafe { Self::with_base(VGA_BUFFER_ADDR) }
+    }
+
+    /// Create a screen on the VGA buffer at base, e.g. after the
+    /// framebuffer gets mapped elsewhere.
+    ///
+    /// Safety:
+    /// - See `VgaBuffer::with_base`.
+    pub unsafe fn with_base(base: usize) -> Self {
+        // Safety: Guaranteed by the caller.
+        let buffer = unsafe { VgaBuffer::with_base(base) };
 
         Self {
             // This has a benefit that we know it will print to the last line,
@@ -148,10 +401,332 @@ impl Screen {
             row: VGA_BUFFER_ROWS - 1,
             col: 0,
             buffer,
-            color_code: ColorCode::new(Color::Yellow, Color::Black),
+            color_code: ColorCode::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+            wrap: WrapMode::Char,
+            history: History::new(),
+            scroll: 0,
+            live: [[ScreenChar::Blank; VGA_BUFFER_COLUMNS]; VGA_BUFFER_ROWS],
+            scrolled: 0,
+            soft_cursor: None,
+        }
+    }
+
+    /// Start with the given colors instead of the default ones.
+    pub fn with_color(mut self, foreground: Color, background: Color) -> Self {
+        self.color_code = ColorCode::new(foreground, background);
+        self
+    }
+
+    /// Start writing at row and col instead of the start of the last row.
+    /// # Panics
+    /// Panics if the position is off the screen.
+    pub fn with_position(mut self, row: usize, col: usize) -> Self {
+        assert!(row < VGA_BUFFER_ROWS && col < VGA_BUFFER_COLUMNS);
+        self.row = row;
+        self.col = col;
+        self
+    }
+
+    /// Where the live screen is.
+    fn cells(&self) -> &dyn Cells {
+        if self.scroll > 0 {
+            &self.live
+        } else {
+            &self.buffer
+        }
+    }
+
+    /// Where the live screen is, with the software cursor taken off first
+    /// so that it never gets mixed into the cells.
+    fn cells_mut(&mut self) -> &mut dyn Cells {
+        self.hide_cursor();
+        self.live_cells_mut()
+    }
+
+    fn live_cells_mut(&mut self) -> &mut dyn Cells {
+        if self.scroll > 0 {
+            &mut self.live
+        } else {
+            &mut self.buffer
+        }
+    }
+
+    /// Rows the view is scrolled up from the live screen.
+    pub fn scroll_offset(&self) -> usize {
+        self.scroll
+    }
+
+    /// Scroll the view up into the history, stopping at the oldest row.
+    pub fn scroll_up(&mut self, rows: usize) {
+        let scroll = (self.scroll + rows).min(self.history.len);
+        self.set_scroll(scroll);
+    }
+
+    /// Scroll the view down towards the live screen.
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.set_scroll(self.scroll.saturating_sub(rows));
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.set_scroll(0);
+    }
+
+    /// Keep at most `rows` rows of history, dropping the oldest ones. The
+    /// view goes back to the live screen.
+    /// # Panics
+    /// Panics if rows > SCROLLBACK_ROWS.
+    pub fn set_scrollback_capacity(&mut self, rows: usize) {
+        self.scroll_to_bottom();
+        self.history.set_capacity(rows);
+    }
+
+    /// Write the whole history and then the live screen as text, one line
+    /// per row with trailing spaces trimmed.
+    pub fn write_scrollback(&self, w: &mut impl core::fmt::Write) -> core::fmt::Result {
+        let live = self.cells();
+        let history = (0..self.history.len).map(|idx| *self.history.get(idx));
+        for row in history.chain((0..VGA_BUFFER_ROWS).map(|r| live.read_row(r))) {
+            let len = row
+                .iter()
+                .rposition(|ch| !matches!(ch.ascii_char, b' ' | 0))
+                .map_or(0, |i| i + 1);
+            for ch in &row[..len] {
+                w.write_char(cp437::to_char(ch.ascii_char))?;
+            }
+            w.write_char('\n')?;
+        }
+        Ok(())
+    }
+
+    fn set_scroll(&mut self, scroll: usize) {
+        if scroll == self.scroll {
+            return;
+        }
+        self.hide_cursor();
+        if self.scroll == 0 {
+            // Put the live screen aside before the history covers it.
+            for r in 0..VGA_BUFFER_ROWS {
+                self.live[r] = self.buffer.read_row(r);
+            }
+        }
+        self.scroll = scroll;
+        if scroll == 0 {
+            for r in 0..VGA_BUFFER_ROWS {
+                self.buffer.write_row(r, self.live[r]);
+            }
+        } else {
+            self.render_history();
+        }
+    }
+
+    /// Show the view at the current scroll offset, with the position in
+    /// the top right corner.
+    fn render_history(&mut self) {
+        let top = self.history.len - self.scroll;
+        for r in 0..VGA_BUFFER_ROWS {
+            let idx = top + r;
+            let row = if idx < self.history.len {
+                *self.history.get(idx)
+            } else {
+                self.live[idx - self.history.len]
+            };
+            self.buffer.write_row(r, row);
+        }
+
+        // "[45%]", written right to left so the width needn't be known
+        // first.
+        let mut percent = top * 100 / self.history.len;
+        let color = ColorCode::new(Color::Black, Color::LightGray);
+        let mut col = VGA_BUFFER_COLUMNS;
+        let mut put = |byte| {
+            col -= 1;
+            self.buffer.write_char(0, col, ScreenChar::new(byte, color));
+        };
+        put(b']');
+        put(b'%');
+        loop {
+            put(b'0' + (percent % 10) as u8);
+            percent /= 10;
+            if percent == 0 {
+                break;
+            }
+        }
+        put(b'[');
+    }
+
+    /// Blink a cursor at the current position in software, driven by the
+    /// timer. For when the hardware cursor is off or unavailable.
+    pub fn enable_software_cursor(&mut self) {
+        if self.soft_cursor.is_none() {
+            self.soft_cursor = Some(SoftCursor {
+                shown: None,
+                next_toggle: 0,
+            });
+        }
+    }
+
+    pub fn disable_software_cursor(&mut self) {
+        self.hide_cursor();
+        self.soft_cursor = None;
+    }
+
+    /// Toggle the software cursor if it's time to. `now` is in ticks.
+    fn tick_cursor(&mut self, now: u64) {
+        match &mut self.soft_cursor {
+            Some(cursor) if now >= cursor.next_toggle => {
+                cursor.next_toggle = now + CURSOR_BLINK_TICKS;
+            }
+            _ => return,
+        }
+        self.toggle_cursor();
+    }
+
+    /// Draw the software cursor at the current position, or take it off
+    /// if it's drawn.
+    fn toggle_cursor(&mut self) {
+        let is_shown = match &self.soft_cursor {
+            Some(cursor) => cursor.shown.is_some(),
+            None => return,
+        };
+        if is_shown {
+            self.hide_cursor();
+            return;
+        }
+        // Nothing to draw on past the right boundary or while scrolled.
+        let (row, col) = (self.row, self.col);
+        if col >= VGA_BUFFER_COLUMNS || self.scroll > 0 {
+            return;
+        }
+        let cell = self.buffer.read_char(row, col);
+        let inverted = ScreenChar::new(cell.ascii_char, cell.color_code.reverse());
+        self.buffer.write_char(row, col, inverted);
+        if let Some(cursor) = &mut self.soft_cursor {
+            cursor.shown = Some((row, col, cell));
+        }
+    }
+
+    /// Put back the cell under the software cursor, if it's drawn.
+    fn hide_cursor(&mut self) {
+        let shown = self.soft_cursor.as_mut().and_then(|cursor| cursor.shown.take());
+        if let Some((row, col, cell)) = shown {
+            self.live_cells_mut().write_char(row, col, cell);
+        }
+    }
+
+    /// Copy the live screen, the cursor position and the color, e.g.
+    /// before an overlay takes over the screen.
+    pub fn snapshot(&self) -> ScreenSnapshot {
+        let cells = self.cells();
+        let mut rows = [[ScreenChar::Blank; VGA_BUFFER_COLUMNS]; VGA_BUFFER_ROWS];
+        for (idx, row) in rows.iter_mut().enumerate() {
+            *row = cells.read_row(idx);
+        }
+        // Not the inverted cell under the software cursor.
+        if let Some((row, col, cell)) = self.soft_cursor.as_ref().and_then(|cursor| cursor.shown) {
+            rows[row][col] = cell;
+        }
+        ScreenSnapshot {
+            rows,
+            row: self.row,
+            col: self.col,
+            color_code: self.color_code,
+        }
+    }
+
+    /// Put back what `snapshot` took. The history is left as is.
+    pub fn restore(&mut self, snapshot: &ScreenSnapshot) {
+        let cells = self.cells_mut();
+        for (idx, row) in snapshot.rows.iter().enumerate() {
+            cells.write_row(idx, *row);
+        }
+        self.row = snapshot.row;
+        self.col = snapshot.col;
+        self.color_code = snapshot.color_code;
+    }
+
+    pub fn set_wrap(&mut self, wrap: WrapMode) {
+        self.wrap = wrap;
+    }
+
+    /// Read the char and its foreground color at the position.
+    /// # Panics
+    /// Panics if row or col goes outside of the screen.
+    pub fn read_char_at(&self, row: usize, col: usize) -> (u8, Color) {
+        let shown = self.soft_cursor.as_ref().and_then(|cursor| cursor.shown);
+        let ch = match shown {
+            Some((r, c, cell)) if (r, c) == (row, col) => cell,
+            _ => self.cells().read_char(row, col),
+        };
+        (ch.ascii_char, ch.color_code.foreground())
+    }
+
+    /// Write a char in color on the current background at the position.
+    /// The cursor doesn't move.
+    /// # Panics
+    /// Panics if row or col goes outside of the screen.
+    pub fn set_char_at(&mut self, row: usize, col: usize, ch: u8, color: Color) {
+        let color_code = ColorCode::new(color, self.color_code.background());
+        self.cells_mut()
+            .write_char(row, col, ScreenChar::new(ch, color_code));
+    }
+
+    /// Blank the whole row with the current color. The cursor doesn't move.
+    /// # Panics
+    /// Panics if row goes outside of the screen.
+    pub fn clear_line(&mut self, row: usize) {
+        let blank = ScreenChar::new(b' ', self.color_code);
+        self.cells_mut().write_row(row, [blank; VGA_BUFFER_COLUMNS]);
+    }
+
+    /// Open a blank line at row, moving it and the rows below down by one,
+    /// like ANSI `IL`. The bottom row is discarded and the cursor doesn't
+    /// move.
+    /// # Panics
+    /// Panics if row goes outside of the screen.
+    pub fn insert_line(&mut self, row: usize) {
+        assert!(row < VGA_BUFFER_ROWS);
+        let cells = self.cells_mut();
+        for r in (row..VGA_BUFFER_ROWS - 1).rev() {
+            let upper_row = cells.read_row(r);
+            cells.write_row(r + 1, upper_row);
+        }
+        self.clear_line(row);
+    }
+
+    /// Remove row, moving the rows below up by one and blanking the bottom
+    /// row, like ANSI `DL`. The cursor doesn't move.
+    /// # Panics
+    /// Panics if row goes outside of the screen.
+    pub fn delete_line(&mut self, row: usize) {
+        assert!(row < VGA_BUFFER_ROWS);
+        let cells = self.cells_mut();
+        for r in row..VGA_BUFFER_ROWS - 1 {
+            let lower_row = cells.read_row(r + 1);
+            cells.write_row(r, lower_row);
+        }
+        self.clear_line(VGA_BUFFER_ROWS - 1);
+    }
+
+    /// Blank the cursor row from the cursor column to the end with the
+    /// current color, like ANSI `EL`. The cursor doesn't move.
+    pub fn clear_to_end_of_line(&mut self) {
+        let blank = ScreenChar::new(b' ', self.color_code);
+        for col in self.col..VGA_BUFFER_COLUMNS {
+            let row = self.row;
+            self.cells_mut().write_char(row, col, blank);
         }
     }
 
+    /// Blank the whole screen with the current color and move the cursor
+    /// to the top left. The history is kept.
+    pub fn clear(&mut self) {
+        for row in 0..VGA_BUFFER_ROWS {
+            self.clear_line(row);
+        }
+        self.row = 0;
+        self.col = 0;
+    }
+
     /// Print a char on the current position. Add a new line if
     /// we hit the right boundary. Move all lines up if we are
     /// already at the bottom.
@@ -160,24 +735,35 @@ impl Screen {
     ///
     /// Caveat:
     /// - We treat '\r' as '\r' and '\n' as '\r\n'.
+    /// - Form feed '\x0c' clears the screen, see [`clear`].
     pub fn put_char(&mut self, ch: u8) {
+        match ch {
+            FORM_FEED => self.clear(),
+            b'\n' | b'\r' => self.put_byte(ch),
+            byte => self.put_byte(printable(byte)),
+        }
+    }
+
+    /// Like [`put_char`], but any byte other than '\n' and '\r' is
+    /// written as is.
+    fn put_byte(&mut self, ch: u8) {
         // Sanity check.
         assert!(self.col <= VGA_BUFFER_COLUMNS);
         assert!(self.row <= VGA_BUFFER_ROWS);
 
-        if self.col == VGA_BUFFER_COLUMNS {
-            self.new_line();
-        }
         match ch {
             b'\n' => self.new_line(),
             b'\r' => self.col = 0,
-            mut byte => {
-                // Unprintable char
-                if !(b' '..=b'~').contains(&byte) {
-                    byte = 0xfe;
+            byte => {
+                // Wrap only when there's something to put on the next
+                // line, so that a '\n' or '\r' right at the boundary
+                // doesn't add a line.
+                if self.col == VGA_BUFFER_COLUMNS {
+                    self.new_line();
                 }
                 let ch = ScreenChar::new(byte, self.color_code);
-                self.buffer.write_char(self.row, self.col, ch);
+                let (row, col) = (self.row, self.col);
+                self.cells_mut().write_char(row, col, ch);
                 self.col += 1;
             }
         };
@@ -185,36 +771,152 @@ impl Screen {
 
     /// Print each char in `s`.
     /// See [`put_char`] for details
+    ///
+    /// Chars the VGA font has a glyph for are printed as such, others as
+    /// 0xfe, so it never fails on any text.
     pub fn puts(&mut self, s: &str) {
-        for ch in s.bytes() {
-            self.put_char(ch);
+        let bytes = s.as_bytes();
+        let mut chars = s.char_indices().peekable();
+        while let Some((i, ch)) = chars.next() {
+            if self.wrap == WrapMode::Word && is_word_start(bytes, i) {
+                let len = s[i..].chars().take_while(|&c| is_word_char(c)).count();
+                if len <= VGA_BUFFER_COLUMNS && self.col + len > VGA_BUFFER_COLUMNS {
+                    self.new_line();
+                }
+            }
+            match ch {
+                '\x0c' => self.clear(),
+                '\n' => {
+                    let mut n = 1;
+                    while chars.next_if(|&(_, c)| c == '\n').is_some() {
+                        n += 1;
+                    }
+                    self.new_lines(n);
+                }
+                '\r' => self.put_byte(ch as u8),
+                ch => self.put_byte(glyph(ch)),
+            }
+        }
+    }
+
+    /// Print raw CP437 code points, e.g. box drawing glyphs that `puts`
+    /// would filter out. '\n' and '\r' still move the cursor as in
+    /// [`put_char`], and there's no word wrapping.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.put_byte(byte);
         }
     }
 
+    /// Write `s` starting at the given position without moving the cursor.
+    /// Chars beyond the right boundary are clipped.
+    /// # Panics
+    /// Panics if row or col goes outside of the screen.
+    pub fn write_at(&mut self, row: usize, col: usize, s: &str, color: ColorCode) {
+        assert!(row < VGA_BUFFER_ROWS && col < VGA_BUFFER_COLUMNS);
+        write_str_at(self.cells_mut(), row, col, s, color);
+    }
+
+    /// Write `s` in reverse video of the current color, e.g. to highlight
+    /// a selection. See [`write_at`] for details.
+    pub fn write_reverse(&mut self, row: usize, col: usize, s: &str) {
+        let color = self.color_code.reverse();
+        self.write_at(row, col, s, color);
+    }
+
+    /// Write `s` centered on the given row. See [`write_at`] for details.
+    pub fn write_centered(&mut self, row: usize, s: &str, color: ColorCode) {
+        let len = s.chars().count().min(VGA_BUFFER_COLUMNS);
+        self.write_at(row, (VGA_BUFFER_COLUMNS - len) / 2, s, color);
+    }
+
+    /// Write `s` aligned to the right boundary of the given row.
+    /// Over-long strings are clipped at the right, like [`write_at`].
+    pub fn write_right(&mut self, row: usize, s: &str, color: ColorCode) {
+        let len = s.chars().count().min(VGA_BUFFER_COLUMNS);
+        self.write_at(row, VGA_BUFFER_COLUMNS - len, s, color);
+    }
+
     /// Add a new line below the current position. If we are
     /// already at the bottom, move all rows up and discard
     /// the first row.
     pub fn new_line(&mut self) {
-        if self.row + 1 < VGA_BUFFER_ROWS {
-            self.row += 1;
-            self.col = 0;
+        self.new_lines(1);
+    }
+
+    /// Add `n` new lines, scrolling the ones past the bottom in one go.
+    fn new_lines(&mut self, n: usize) {
+        self.hide_cursor();
+        let down = n.min(VGA_BUFFER_ROWS - 1 - self.row);
+        self.row += down;
+        self.scroll_lines(n - down);
+        self.col = 0;
+    }
+
+    /// Move the screen content up by `n` rows, as `n` new lines at the
+    /// bottom would, but copying each row only once. The rows moved out
+    /// go to the scrollback. The cursor stays where it is.
+    pub fn scroll_lines(&mut self, n: usize) {
+        if n == 0 {
             return;
         }
-        // Move all rows up.
-        // TODO: add the discarded line to history.
-        for r in 0..(VGA_BUFFER_ROWS - 1) {
-            let lower_row = self.buffer.read_row(r + 1);
-            self.buffer.write_row(r, lower_row);
+        // Otherwise the cursor cell would move up with the rows.
+        self.hide_cursor();
+        self.scrolled = self.scrolled.wrapping_add(n);
+        let moved = n.min(VGA_BUFFER_ROWS);
+        for r in 0..moved {
+            let row = self.cells().read_row(r);
+            self.history.push(row);
+        }
+        // Past a full screen, blank rows are scrolled out.
+        let blank = [ScreenChar::Blank; VGA_BUFFER_COLUMNS];
+        for _ in 0..(n - moved).min(self.history.capacity) {
+            self.history.push(blank);
         }
-        // Clear the last row.
-        self.buffer
-            .write_row(VGA_BUFFER_ROWS - 1, [ScreenChar::Blank; VGA_BUFFER_COLUMNS]);
 
-        // self.row remains unchanged.
-        self.col = 0;
+        let cells = self.cells_mut();
+        for r in 0..(VGA_BUFFER_ROWS - moved) {
+            let lower_row = cells.read_row(r + moved);
+            cells.write_row(r, lower_row);
+        }
+        for r in (VGA_BUFFER_ROWS - moved)..VGA_BUFFER_ROWS {
+            cells.write_row(r, blank);
+        }
+
+        if self.scroll > 0 {
+            // Keep the view on the same rows.
+            self.scroll = (self.scroll + n).min(self.history.len);
+            self.render_history();
+        }
+    }
+}
+
+/// Replace unprintable chars with 0xfe.
+fn printable(byte: u8) -> u8 {
+    if (b' '..=b'~').contains(&byte) {
+        byte
+    } else {
+        0xfe
     }
 }
 
+/// The code point of ch in the VGA font, or 0xfe if it has none.
+fn glyph(ch: char) -> u8 {
+    cp437::from_char(ch).unwrap_or(0xfe)
+}
+
+fn is_word_byte(byte: u8) -> bool {
+    !matches!(byte, b' ' | b'\n' | b'\r')
+}
+
+fn is_word_char(ch: char) -> bool {
+    !matches!(ch, ' ' | '\n' | '\r')
+}
+
+fn is_word_start(bytes: &[u8], i: usize) -> bool {
+    is_word_byte(bytes[i]) && (i == 0 || !is_word_byte(bytes[i - 1]))
+}
+
 impl core::fmt::Write for Screen {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         self.puts(s);
@@ -226,10 +928,45 @@ lazy_static! {
     pub static ref SCREEN: SpinLock<Screen> = SpinLock::new(Screen::new());
 }
 
+/// Dump the scrollback over serial, e.g. to see what scrolled away before
+/// a crash.
+pub fn dump_scrollback() {
+    let _ = SCREEN.lock().write_scrollback(&mut crate::serial::SerialWriter);
+}
+
+/// Run `f` on the screen, locked once with interrupts disabled once, e.g.
+/// to redraw a whole TUI frame instead of going through `print!` for
+/// every piece of it. Don't `print!` in `f`, it would deadlock.
+pub fn frame<R>(f: impl FnOnce(&mut Screen) -> R) -> R {
+    crate::x86_64::without_interrupts(|| SCREEN.with(f))
+}
+
+/// See `Screen::set_scrollback_capacity`.
+pub fn set_scrollback_capacity(rows: usize) {
+    SCREEN.lock().set_scrollback_capacity(rows);
+}
+
+/// Called by the timer interrupt handler to blink the software cursor.
+pub(crate) fn tick_cursor() {
+    // Skip this tick if the screen is busy.
+    if let Some(mut screen) = SCREEN.try_lock() {
+        screen.tick_cursor(crate::pit::ticks());
+    }
+}
+
+/// Console that prints to `SCREEN`.
+pub struct ScreenConsole;
+
+impl Console for ScreenConsole {
+    fn write_str(&self, s: &str) {
+        SCREEN.lock().puts(s);
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
-        $crate::screen::_print(::core::format_args!($($arg)*))
+        $crate::console::_print(::core::format_args!($($arg)*))
     };
 }
 
@@ -243,14 +980,6 @@ macro_rules! println {
     };
 }
 
-#[doc(hidden)]
-pub fn _print(args: core::fmt::Arguments) {
-    use core::fmt::Write;
-    crate::x86_64::without_interrupts(
-        || SCREEN.lock().write_fmt(args).unwrap()
-    );
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -288,4 +1017,454 @@ mod tests {
             assert_eq!(char::from(screen_char.ascii_char), ch);
         }
     }
+
+    #[test_case]
+    fn test_carriage_return_at_line_end() {
+        let full = core::str::from_utf8(&[b'x'; VGA_BUFFER_COLUMNS]).unwrap();
+        let mut screen = SCREEN.lock();
+        screen.clear();
+        screen.puts(full);
+        assert_eq!((screen.row, screen.col), (0, VGA_BUFFER_COLUMNS));
+        // Back to the start of the same line.
+        screen.puts("\ry");
+        assert_eq!((screen.row, screen.col), (0, 1));
+        assert_eq!(screen.buffer.read_char(0, 0).ascii_char, b'y');
+        assert_eq!(screen.buffer.read_char(0, 1).ascii_char, b'x');
+    }
+
+    #[test_case]
+    fn test_new_line_at_line_end() {
+        let full = core::str::from_utf8(&[b'x'; VGA_BUFFER_COLUMNS]).unwrap();
+        let mut screen = SCREEN.lock();
+        screen.clear();
+        screen.puts(full);
+        // Only one new line, not one for the wrap and another for '\n'.
+        screen.puts("\nz");
+        assert_eq!((screen.row, screen.col), (1, 1));
+        assert_eq!(screen.buffer.read_char(1, 0).ascii_char, b'z');
+        // Printable chars still wrap.
+        screen.puts(&full[1..]);
+        screen.puts("w");
+        assert_eq!((screen.row, screen.col), (2, 1));
+        assert_eq!(screen.buffer.read_char(2, 0).ascii_char, b'w');
+    }
+
+    #[test_case]
+    fn test_scroll_lines() {
+        let mut fake = [[0u8; VGA_BUFFER_SIZE]; 2];
+        let [one_by_one, at_once] = &mut fake;
+        {
+            // Safety: fake outlives the screens and is only accessed
+            // through them.
+            let mut screens = unsafe {
+                [
+                    Screen::with_base(one_by_one.as_mut_ptr() as usize),
+                    Screen::with_base(at_once.as_mut_ptr() as usize),
+                ]
+            };
+            for screen in &mut screens {
+                let color = screen.color_code;
+                for row in 0..VGA_BUFFER_ROWS {
+                    let ch = [b'a' + row as u8];
+                    screen.write_at(row, 0, core::str::from_utf8(&ch).unwrap(), color);
+                }
+            }
+            let [a, b] = &mut screens;
+            for _ in 0..5 {
+                a.scroll_lines(1);
+            }
+            b.scroll_lines(5);
+
+            assert_eq!(a.history.len, 5);
+            assert_eq!(b.history.len, 5);
+            for i in 0..5 {
+                assert_eq!(a.history.get(i), b.history.get(i));
+            }
+            assert_eq!(b.history.get(4)[0].ascii_char, b'e');
+        }
+        assert!(one_by_one == at_once);
+        assert_eq!(at_once[0], b'f');
+    }
+
+    #[test_case]
+    fn test_scroll_lines_with_cursor() {
+        let mut fake = [0u8; VGA_BUFFER_SIZE];
+        // Safety: fake outlives the screen and is only accessed through it.
+        let mut screen = unsafe { Screen::with_base(fake.as_mut_ptr() as usize) }
+            .with_position(1, 0);
+        screen.puts("ab");
+        screen.col = 0;
+        screen.enable_software_cursor();
+        screen.toggle_cursor();
+        let color = screen.color_code;
+        let below = screen.buffer.read_char(2, 0);
+
+        screen.scroll_lines(1);
+        // The cell moves up as it was, and nothing is put back at the old
+        // position.
+        assert_eq!(screen.buffer.read_char(0, 0), ScreenChar::new(b'a', color));
+        assert_eq!(screen.buffer.read_char(1, 0), below);
+    }
+
+    #[test_case]
+    fn test_word_wrap() {
+        let s = "The quick brown fox jumps over the lazy dog while the \
+                 slow purple turtle watches from a comfortable distance";
+        assert!(s.len() > VGA_BUFFER_COLUMNS);
+
+        SCREEN.lock().set_wrap(WrapMode::Word);
+        // Force a new line.
+        println!();
+        println!("{}", s);
+        let mut screen = SCREEN.lock();
+        screen.set_wrap(WrapMode::Char);
+
+        for r in (VGA_BUFFER_ROWS - 3)..(VGA_BUFFER_ROWS - 1) {
+            let mut line = [0u8; VGA_BUFFER_COLUMNS];
+            for (byte, screen_char) in line.iter_mut().zip(screen.buffer.read_row(r)) {
+                *byte = screen_char.ascii_char;
+            }
+            let line = core::str::from_utf8(&line).unwrap();
+            for word in line.split_whitespace() {
+                assert!(s.split(' ').any(|w| w == word), "word `{}` is split", word);
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_vga_char_from_char() {
+        assert_eq!(VgaChar::try_from('A'), Ok(VgaChar(b'A')));
+        assert_eq!(VgaChar::try_from('░'), Ok(VgaChar(0xb0)));
+        assert_eq!(VgaChar::try_from('☺'), Ok(VgaChar(0x01)));
+        assert_eq!(VgaChar::try_from('😀'), Err(UnmappableChar('😀')));
+        assert_eq!(VgaChar::try_from('\n'), Err(UnmappableChar('\n')));
+    }
+
+    #[test_case]
+    fn test_write_aligned() {
+        let color = ColorCode::new(Color::White, Color::Blue);
+        let s = "0123456789";
+        let mut screen = SCREEN.lock();
+
+        screen.write_centered(0, s, color);
+        for (col, ch) in (35..).zip(s.bytes()) {
+            assert_eq!(screen.buffer.read_char(0, col), ScreenChar::new(ch, color));
+        }
+
+        screen.write_right(1, s, color);
+        for (col, ch) in (70..).zip(s.bytes()) {
+            assert_eq!(screen.buffer.read_char(1, col), ScreenChar::new(ch, color));
+        }
+
+        // Clipped at the right boundary.
+        screen.write_at(2, 75, s, color);
+        assert_eq!(screen.buffer.read_char(2, 79).ascii_char, b'4');
+    }
+
+    #[test_case]
+    fn test_vga_buffer_with_base() {
+        let mut fake = [0u8; VGA_BUFFER_SIZE];
+        let color = ColorCode::new(Color::White, Color::Blue);
+        {
+            // Safety: fake outlives the buffer and is only accessed through
+            // it.
+            let mut buffer = unsafe { VgaBuffer::with_base(fake.as_mut_ptr() as usize) };
+            buffer.write_char(0, 1, ScreenChar::new(b'A', color));
+            buffer.write_char(1, 0, ScreenChar::new(b'B', color));
+            assert_eq!(buffer.read_char(0, 1), ScreenChar::new(b'A', color));
+        }
+
+        assert_eq!(fake[2..4], [b'A', 0x1f]);
+        assert_eq!(fake[VGA_BUFFER_COLUMNS * 2..][..2], [b'B', 0x1f]);
+        assert!(fake[..2].iter().all(|&b| b == 0));
+    }
+
+    #[test_case]
+    fn test_with_color() {
+        let mut fake = [0u8; VGA_BUFFER_SIZE];
+        {
+            // Safety: fake outlives the screen and is only accessed through
+            // it.
+            let mut screen = unsafe { Screen::with_base(fake.as_mut_ptr() as usize) }
+                .with_color(Color::White, Color::Blue)
+                .with_position(1, 2);
+            screen.puts("A");
+            assert_eq!((screen.row, screen.col), (1, 3));
+        }
+
+        let offset = (VGA_BUFFER_COLUMNS + 2) * 2;
+        assert_eq!(fake[offset..][..2], [b'A', 0x1f]);
+    }
+
+    #[test_case]
+    fn test_snapshot_restore() {
+        let mut fake = [0u8; VGA_BUFFER_SIZE];
+        // Safety: fake outlives the screen and is only accessed through it.
+        let mut screen = unsafe { Screen::with_base(fake.as_mut_ptr() as usize) }
+            .with_position(0, 0);
+        screen.puts("dialog\nbehind");
+        let snapshot = screen.snapshot();
+
+        screen.color_code = ColorCode::new(Color::White, Color::Red);
+        for row in 0..VGA_BUFFER_ROWS {
+            screen.clear_line(row);
+        }
+        screen.puts("\n\noverlay");
+        screen.restore(&snapshot);
+
+        assert_eq!((screen.row, screen.col), (1, 6));
+        assert_eq!(screen.color_code, ColorCode::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND));
+        assert_eq!(screen.read_char_at(0, 0).0, b'd');
+        assert_eq!(screen.read_char_at(1, 5).0, b'd');
+        assert_eq!(screen.read_char_at(2, 0).0, 0);
+        screen.puts("!");
+        assert_eq!(screen.read_char_at(1, 6).0, b'!');
+    }
+
+    #[test_case]
+    fn test_form_feed() {
+        let mut fake = [0u8; VGA_BUFFER_SIZE];
+        // Safety: fake outlives the screen and is only accessed through it.
+        let mut screen = unsafe { Screen::with_base(fake.as_mut_ptr() as usize) };
+        screen.puts("foo\nfoo\x0cbar");
+        assert_eq!((screen.row, screen.col), (0, 3));
+        let text = |screen: &Screen, row| screen.cells().read_row(row).map(|ch| ch.ascii_char);
+        assert_eq!(text(&screen, 0)[..4], *b"bar ");
+        for row in 1..VGA_BUFFER_ROWS {
+            assert!(text(&screen, row).iter().all(|&ch| ch == b' '));
+        }
+
+        screen.put_char(FORM_FEED);
+        assert_eq!((screen.row, screen.col), (0, 0));
+        assert_eq!(text(&screen, 0)[0], b' ');
+    }
+
+    #[test_case]
+    fn test_clear_to_end_of_line() {
+        let line = core::str::from_utf8(&[b'x'; VGA_BUFFER_COLUMNS]).unwrap();
+        let mut screen = SCREEN.lock();
+        screen.puts("\n");
+        screen.puts(line);
+        screen.puts("\r");
+        screen.puts(&line[..40]);
+        let row = screen.row;
+
+        screen.clear_to_end_of_line();
+        assert_eq!((screen.row, screen.col), (row, 40));
+        for col in 0..VGA_BUFFER_COLUMNS {
+            let expected = if col < 40 { b'x' } else { b' ' };
+            assert_eq!(screen.buffer.read_char(row, col).ascii_char, expected);
+        }
+
+        screen.clear_line(row);
+        assert!(screen.buffer.read_row(row).iter().all(|ch| ch.ascii_char == b' '));
+        assert_eq!(screen.col, 40);
+    }
+
+    #[test_case]
+    fn test_write_bytes_box() {
+        let mut screen = SCREEN.lock();
+        screen.write_bytes(b"\n\xda\xc4\xc4\xbf\n\xb3~\x01\xb3\n\xc0\xc4\xc4\xd9");
+        let top = screen.row - 2;
+
+        let expected: [&[u8; 4]; 3] = [
+            b"\xda\xc4\xc4\xbf",
+            b"\xb3~\x01\xb3",
+            b"\xc0\xc4\xc4\xd9",
+        ];
+        for (row, bytes) in (top..).zip(expected) {
+            for (col, &byte) in bytes.iter().enumerate() {
+                assert_eq!(screen.buffer.read_char(row, col).ascii_char, byte);
+            }
+        }
+        // `puts` still filters.
+        screen.puts("\r\x01");
+        assert_eq!(screen.buffer.read_char(screen.row, 0).ascii_char, 0xfe);
+    }
+
+    #[test_case]
+    fn test_frame() {
+        let color = ColorCode::new(Color::Green, Color::Black);
+        let drawn = frame(|screen| {
+            for row in 0..VGA_BUFFER_ROWS {
+                for col in 0..VGA_BUFFER_COLUMNS {
+                    let ch = b'a' + ((row + col) % 26) as u8;
+                    screen.cells_mut().write_char(row, col, ScreenChar::new(ch, color));
+                }
+            }
+            // Check before the lock is released, a timer tick may redraw
+            // the cursor cell right after.
+            for row in 0..VGA_BUFFER_ROWS {
+                for col in 0..VGA_BUFFER_COLUMNS {
+                    let ch = b'a' + ((row + col) % 26) as u8;
+                    assert_eq!(screen.buffer.read_char(row, col), ScreenChar::new(ch, color));
+                }
+            }
+            VGA_BUFFER_ROWS * VGA_BUFFER_COLUMNS
+        });
+        assert_eq!(drawn, 2000);
+    }
+
+    #[test_case]
+    fn test_write_reverse() {
+        let mut screen = SCREEN.lock();
+        let normal = ColorCode::new(Color::Yellow, Color::Blue);
+        let prev = screen.color_code;
+        screen.color_code = normal;
+        screen.write_reverse(3, 10, "sel");
+        screen.color_code = prev;
+
+        for (col, ch) in (10..).zip(b"sel") {
+            let cell = screen.buffer.read_char(3, col);
+            assert_eq!(cell.ascii_char, *ch);
+            // Yellow is bright brown, and stays bright in front.
+            let color = ColorCode::new(Color::LightBlue, Color::Brown);
+            assert_eq!(cell.color_code, color);
+        }
+        assert_eq!(normal.reverse().reverse(), normal);
+        // Nothing starts blinking.
+        assert_eq!(normal.reverse().0 & 0x80, 0);
+    }
+
+    #[test_case]
+    fn test_software_cursor() {
+        let mut screen = SCREEN.lock();
+        screen.puts("\nab\r");
+        let row = screen.row;
+        let a = screen.buffer.read_char(row, 0);
+        screen.enable_software_cursor();
+
+        screen.toggle_cursor();
+        let drawn = screen.buffer.read_char(row, 0);
+        assert_eq!(drawn.ascii_char, b'a');
+        assert_eq!(drawn.color_code.foreground(), a.color_code.background());
+        assert_eq!(screen.read_char_at(row, 0), (b'a', a.color_code.foreground()));
+        screen.toggle_cursor();
+        assert_eq!(screen.buffer.read_char(row, 0), a);
+
+        // Moving the cursor leaves the cell it was drawn on intact.
+        screen.toggle_cursor();
+        screen.puts("x");
+        assert_eq!(screen.buffer.read_char(row, 0), ScreenChar::new(b'x', a.color_code));
+        screen.toggle_cursor();
+        screen.puts("\r");
+        screen.toggle_cursor();
+        let b = screen.buffer.read_char(row, 1);
+        assert_eq!(b.ascii_char, b'b');
+        assert_eq!(b.color_code, a.color_code);
+
+        screen.disable_software_cursor();
+        assert_eq!(screen.buffer.read_char(row, 0), ScreenChar::new(b'x', a.color_code));
+    }
+
+    #[test_case]
+    fn test_write_scrollback() {
+        use crate::test_util::StrBuf;
+        use core::fmt::Write;
+
+        let mut screen = SCREEN.lock();
+        for i in 0..60 {
+            writeln!(screen, "scrollback {}", i).unwrap();
+        }
+        let mut out = StrBuf::<{ 81 * (SCROLLBACK_ROWS + VGA_BUFFER_ROWS) }>::new();
+        let has_line = |out: &StrBuf<_>, i| {
+            let mut line = StrBuf::<16>::new();
+            write!(line, "scrollback {}", i).unwrap();
+            out.as_str().lines().any(|l| l == line.as_str())
+        };
+        screen.write_scrollback(&mut out).unwrap();
+        assert!(has_line(&out, 0));
+        assert!(has_line(&out, 59));
+
+        // Only the last rows of history are kept. The screen shows 36..=59.
+        screen.set_scrollback_capacity(10);
+        out.clear();
+        screen.write_scrollback(&mut out).unwrap();
+        assert!(!has_line(&out, 25));
+        assert!(has_line(&out, 26));
+        assert!(has_line(&out, 59));
+        screen.set_scrollback_capacity(SCROLLBACK_ROWS);
+    }
+
+    #[test_case]
+    fn test_non_ascii_fallback() {
+        // Under one lock, so that no timer tick prints in between.
+        let mut screen = SCREEN.lock();
+        screen.puts("\né→\u{1f600}\t");
+        let row = screen.row;
+        let cells: [u8; 4] =
+            core::array::from_fn(|col| screen.buffer.read_char(row, col).ascii_char);
+        // Glyphs from the VGA font where it has them, 0xfe otherwise.
+        assert_eq!(cells, [0x82, 0x1a, 0xfe, 0xfe]);
+        assert_eq!(screen.col, 4);
+    }
+
+    #[test_case]
+    fn test_utf8() {
+        // Under one lock, so that no timer tick prints in between.
+        let mut screen = SCREEN.lock();
+        // 'é' is two bytes in UTF-8 but one glyph.
+        screen.puts("\ncafé");
+        let row = screen.row;
+        let cells: [u8; 5] =
+            core::array::from_fn(|col| screen.buffer.read_char(row, col).ascii_char);
+        assert_eq!(cells, [b'c', b'a', b'f', 0x82, b' ']);
+        assert_eq!(screen.col, 4);
+
+        // Aligned by chars, not bytes.
+        let color = screen.color_code;
+        screen.write_right(row, "é", color);
+        let last = screen.buffer.read_char(row, VGA_BUFFER_COLUMNS - 1);
+        assert_eq!(last, ScreenChar::new(0x82, color));
+    }
+
+    #[test_case]
+    fn test_insert_delete_line() {
+        let mut screen = SCREEN.lock();
+        let color = screen.color_code;
+        screen.write_at(5, 0, "row 5", color);
+        screen.write_at(6, 0, "row 6", color);
+        let bottom = screen.buffer.read_row(VGA_BUFFER_ROWS - 2);
+
+        screen.insert_line(5);
+        assert!(screen.buffer.read_row(5).iter().all(|ch| ch.ascii_char == b' '));
+        assert_eq!(screen.buffer.read_char(6, 4).ascii_char, b'5');
+        assert_eq!(screen.buffer.read_char(7, 4).ascii_char, b'6');
+        assert_eq!(screen.buffer.read_row(VGA_BUFFER_ROWS - 1), bottom);
+
+        screen.delete_line(5);
+        assert_eq!(screen.buffer.read_char(5, 4).ascii_char, b'5');
+        assert_eq!(screen.buffer.read_char(6, 4).ascii_char, b'6');
+        let last = screen.buffer.read_row(VGA_BUFFER_ROWS - 1);
+        assert!(last.iter().all(|ch| ch.ascii_char == b' '));
+    }
+
+    #[test_case]
+    fn test_scroll_indicator() {
+        let mut screen = SCREEN.lock();
+        // Fill up the history.
+        for _ in 0..SCROLLBACK_ROWS {
+            screen.puts("history\n");
+        }
+        screen.puts("live");
+        let live_top = screen.buffer.read_row(0);
+
+        screen.scroll_up(55);
+        assert_eq!(screen.scroll_offset(), 55);
+        let mut indicator = [0u8; 5];
+        for (col, byte) in (VGA_BUFFER_COLUMNS - 5..).zip(indicator.iter_mut()) {
+            *byte = screen.buffer.read_char(0, col).ascii_char;
+        }
+        assert_eq!(&indicator, b"[45%]");
+
+        // Output while scrolled goes to the live screen.
+        screen.puts("!");
+        let (ch, _) = screen.read_char_at(VGA_BUFFER_ROWS - 1, 4);
+        assert_eq!(ch, b'!');
+
+        screen.scroll_to_bottom();
+        assert_eq!(screen.scroll_offset(), 0);
+        assert!(screen.buffer.read_row(0) == live_top);
+        assert_eq!(screen.buffer.read_char(VGA_BUFFER_ROWS - 1, 4).ascii_char, b'!');
+    }
 }
@@ -0,0 +1,50 @@
+// Code page 437, the character set of the VGA text mode font.
+// See https://en.wikipedia.org/wiki/Code_page_437
+
+/// Glyphs of 0x01..=0x1f. 0x00 has no glyph.
+const LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►',
+    '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// Glyph of 0x7f.
+const DEL: char = '⌂';
+
+/// Glyphs of 0x80..=0xff.
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// The glyph of a code point. 0x00 has none and shows as a space.
+pub fn to_char(byte: u8) -> char {
+    match byte {
+        0x00 => ' ',
+        0x01..=0x1f => LOW[byte as usize - 0x01],
+        0x7f => DEL,
+        0x80..=0xff => HIGH[byte as usize - 0x80],
+        _ => byte as char,
+    }
+}
+
+/// Map a char to its code point in CP437.
+/// Returns None if the font has no glyph for it. ASCII control chars
+/// are not mapped to the glyphs sharing their code points.
+pub fn from_char(ch: char) -> Option<u8> {
+    if (' '..='~').contains(&ch) {
+        return Some(ch as u8);
+    }
+    if ch == DEL {
+        return Some(0x7f);
+    }
+    if let Some(i) = LOW.iter().position(|&c| c == ch) {
+        return Some(i as u8 + 0x01);
+    }
+    HIGH.iter().position(|&c| c == ch).map(|i| i as u8 + 0x80)
+}
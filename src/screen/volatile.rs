@@ -0,0 +1,55 @@
+// I prefer not to depends on an outside crate unless absolutely
+// neccessary, so I don't use `volatile` crate here. Instead, I
+// wrap them by myself.
+
+/// Wrapper whose inner value can only be accessed with volatile reads
+/// and writes. The field is private to this module, so it's enforced by
+/// the type system rather than by convention.
+#[repr(transparent)]
+pub struct Volatile<T>(T);
+
+impl<T: Copy> Volatile<T> {
+    pub fn read(&self) -> T {
+        // Safety: self is a valid reference.
+        unsafe { core::ptr::read_volatile(&self.0) }
+    }
+
+    pub fn write(&mut self, value: T) {
+        // Safety: self is a valid reference.
+        unsafe { core::ptr::write_volatile(&mut self.0, value) }
+    }
+}
+
+impl<T, const N: usize> Volatile<[T; N]> {
+    /// A single element of the array, still behind volatile access.
+    /// # Panics
+    /// Panics if idx is out of bounds.
+    pub fn at(&self, idx: usize) -> &Volatile<T> {
+        // Safety: Volatile<T> is repr(transparent) over T.
+        unsafe { &*(&self.0[idx] as *const T as *const Volatile<T>) }
+    }
+
+    /// See [`Self::at`].
+    pub fn at_mut(&mut self, idx: usize) -> &mut Volatile<T> {
+        // Safety: Volatile<T> is repr(transparent) over T.
+        unsafe { &mut *(&mut self.0[idx] as *mut T as *mut Volatile<T>) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_volatile_round_trip() {
+        let mut value = [0u16; 4];
+        // Safety: Volatile<T> is repr(transparent) over T.
+        let volatile = unsafe { &mut *(&mut value as *mut [u16; 4] as *mut Volatile<[u16; 4]>) };
+        volatile.write([1, 2, 3, 4]);
+        assert_eq!(volatile.read(), [1, 2, 3, 4]);
+        volatile.at_mut(2).write(42);
+        assert_eq!(volatile.at(2).read(), 42);
+        assert_eq!(volatile.read(), [1, 2, 42, 4]);
+        assert_eq!(value, [1, 2, 42, 4]);
+    }
+}
@@ -0,0 +1,124 @@
+// Virtual consoles: several screens that take turns on the VGA buffer.
+//
+// The active one is `SCREEN` itself, so `print!` goes there. The others
+// are kept as snapshots until switched to. The history is shared.
+
+use super::{Screen, ScreenSnapshot, SCREEN};
+use crate::keyboard::{KeyCode, Modifiers};
+use crate::spinlock::SpinLock;
+use crate::x86_64::without_interrupts;
+
+/// Number of virtual consoles, one for each of Alt+F1..F4.
+pub const VT_COUNT: usize = 4;
+
+struct Vts {
+    active: usize,
+    /// The inactive consoles. None if one hasn't been shown yet.
+    saved: [Option<ScreenSnapshot>; VT_COUNT],
+}
+
+impl Vts {
+    /// Put the active console away and bring up console n.
+    fn switch(&mut self, screen: &mut Screen, n: usize) {
+        if n == self.active {
+            return;
+        }
+        // The snapshot is of the live screen, and so is `restore`'s target.
+        screen.scroll_to_bottom();
+        self.saved[self.active] = Some(screen.snapshot());
+        match self.saved[n].take() {
+            Some(snapshot) => screen.restore(&snapshot),
+            None => screen.clear(),
+        }
+        self.active = n;
+    }
+}
+
+const NOT_SHOWN: Option<ScreenSnapshot> = None;
+
+static VTS: SpinLock<Vts> = SpinLock::new(Vts {
+    active: 0,
+    saved: [NOT_SHOWN; VT_COUNT],
+});
+
+/// Index of the console on the screen.
+pub fn active() -> usize {
+    VTS.lock().active
+}
+
+/// Show console n, and send `print!` there from now on.
+/// # Panics
+/// Panics if n isn't below `VT_COUNT`.
+pub fn switch(n: usize) {
+    assert!(n < VT_COUNT, "no virtual console {}", n);
+    without_interrupts(|| VTS.lock().switch(&mut SCREEN.lock(), n));
+}
+
+/// Like `switch`, but gives up if the screen is busy, e.g. in the
+/// keyboard interrupt handler. Returns whether it switched.
+/// # Panics
+/// Panics if n isn't below `VT_COUNT`.
+pub fn try_switch(n: usize) -> bool {
+    assert!(n < VT_COUNT, "no virtual console {}", n);
+    let (Some(mut vts), Some(mut screen)) = (VTS.try_lock(), SCREEN.try_lock()) else {
+        return false;
+    };
+    vts.switch(&mut screen, n);
+    true
+}
+
+/// The console Alt+F1..F4 switches to.
+pub fn switch_key(code: KeyCode, modifiers: Modifiers) -> Option<usize> {
+    if !modifiers.alt {
+        return None;
+    }
+    match code {
+        KeyCode::F1 => Some(0),
+        KeyCode::F2 => Some(1),
+        KeyCode::F3 => Some(2),
+        KeyCode::F4 => Some(3),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen::VGA_BUFFER_COLUMNS;
+
+    fn visible_row(row: usize) -> [u8; VGA_BUFFER_COLUMNS] {
+        SCREEN.lock().buffer.read_row(row).map(|ch| ch.ascii_char)
+    }
+
+    #[test_case]
+    fn test_switch() {
+        // No timer tick may print between the writes and the checks.
+        crate::x86_64::without_interrupts(|| {
+            switch(0);
+            SCREEN.lock().puts("\nvt0");
+            let row = SCREEN.lock().row;
+
+            switch(1);
+            assert_eq!(active(), 1);
+            // A fresh console starts blank.
+            assert!(visible_row(row).iter().all(|&ch| ch == b' '));
+            SCREEN.lock().puts("vt1");
+            assert_eq!(visible_row(0)[..3], *b"vt1");
+
+            switch(0);
+            assert_eq!(visible_row(row)[..3], *b"vt0");
+            assert_eq!(SCREEN.lock().col, 3);
+            switch(1);
+            assert_eq!(visible_row(0)[..3], *b"vt1");
+            let screen = SCREEN.lock();
+            assert_eq!((screen.row, screen.col), (0, 3));
+            drop(screen);
+            switch(0);
+        });
+
+        let alt = Modifiers { alt: true, ..Modifiers::default() };
+        assert_eq!(switch_key(KeyCode::F2, alt), Some(1));
+        assert_eq!(switch_key(KeyCode::F2, Modifiers::default()), None);
+        assert_eq!(switch_key(KeyCode::F5, alt), None);
+    }
+}
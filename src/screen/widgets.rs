@@ -0,0 +1,176 @@
+// Progress indicators drawn at a fixed place on the screen, for long
+// operations like a memory test.
+//
+// They draw in the current color and don't move the cursor, so `print!`
+// can go on around them. If it scrolls the screen, they're drawn whole
+// again at the same place on the next update.
+
+use super::{frame, Screen, VGA_BUFFER_COLUMNS, VGA_BUFFER_ROWS};
+
+/// `[####----] 50%` on one row. Only the cells that change are redrawn.
+pub struct ProgressBar {
+    row: usize,
+    col: usize,
+    /// Number of cells between the brackets.
+    width: usize,
+    /// The filled cells and the percentage on the screen, None before the
+    /// first draw.
+    drawn: Option<(usize, u8)>,
+    /// `Screen::scrolled` at the last draw.
+    drawn_scrolled: usize,
+}
+
+impl ProgressBar {
+    /// A bar with `width` cells between the brackets, starting at the
+    /// given position. It's drawn at the first `set_progress`.
+    /// # Panics
+    /// Panics if it doesn't fit on the row.
+    pub fn new(row: usize, col: usize, width: usize) -> Self {
+        // The brackets and " 100%".
+        assert!(
+            row < VGA_BUFFER_ROWS && col + width + 7 <= VGA_BUFFER_COLUMNS,
+            "progress bar out of screen"
+        );
+        Self {
+            row,
+            col,
+            width,
+            drawn: None,
+            drawn_scrolled: 0,
+        }
+    }
+
+    /// Show `frac` of the work as done. It's clamped to 0.0..=1.0.
+    pub fn set_progress(&mut self, frac: f32) {
+        let frac = frac.clamp(0.0, 1.0);
+        // Rounded down, so that it's only full when done.
+        let filled = (frac * self.width as f32) as usize;
+        let percent = (frac * 100.0) as u8;
+        frame(|screen| self.draw(screen, filled, percent));
+    }
+
+    fn draw(&mut self, screen: &mut Screen, filled: usize, percent: u8) {
+        let color = screen.color_code;
+        let (row, bar) = (self.row, self.col + 1);
+        // What was drawn has moved up, or off the screen.
+        if screen.scrolled != self.drawn_scrolled {
+            self.drawn = None;
+        }
+        let (old_filled, old_percent) = match self.drawn {
+            Some(drawn) => drawn,
+            None => {
+                screen.write_at(row, self.col, "[", color);
+                screen.write_at(row, bar + self.width, "] ", color);
+                for col in bar..bar + self.width {
+                    screen.write_at(row, col, "-", color);
+                }
+                (0, u8::MAX)
+            }
+        };
+        let changed = old_filled.min(filled)..old_filled.max(filled);
+        let fill = if filled > old_filled { "#" } else { "-" };
+        for i in changed {
+            screen.write_at(row, bar + i, fill, color);
+        }
+        if percent != old_percent {
+            let text = percent_text(percent);
+            // Safety: It's all ASCII.
+            let text = unsafe { core::str::from_utf8_unchecked(&text) };
+            screen.write_at(row, bar + self.width + 2, text, color);
+        }
+        self.drawn = Some((filled, percent));
+        self.drawn_scrolled = screen.scrolled;
+    }
+}
+
+/// `percent` right aligned in 3 columns, then '%'.
+fn percent_text(percent: u8) -> [u8; 4] {
+    let mut text = *b"   %";
+    let mut n = percent;
+    for i in (0..3).rev() {
+        text[i] = b'0' + n % 10;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    text
+}
+
+/// Ticks between two frames of a spinner, ~0.2s.
+const SPIN_TICKS: u64 = crate::pit::ms_to_ticks(200);
+
+/// A `|/-\` spinner in one cell, for work of unknown length.
+pub struct Spinner {
+    row: usize,
+    col: usize,
+    frame: usize,
+    next_tick: u64,
+}
+
+impl Spinner {
+    const FRAMES: [&'static str; 4] = ["|", "/", "-", "\\"];
+
+    /// # Panics
+    /// Panics if row or col goes outside of the screen.
+    pub fn new(row: usize, col: usize) -> Self {
+        assert!(row < VGA_BUFFER_ROWS && col < VGA_BUFFER_COLUMNS);
+        Self {
+            row,
+            col,
+            frame: 0,
+            next_tick: 0,
+        }
+    }
+
+    /// Draw the next frame if it's time to. Call it as often as
+    /// convenient, it turns at the same pace either way.
+    pub fn tick(&mut self) {
+        let now = crate::pit::ticks();
+        if now < self.next_tick {
+            return;
+        }
+        self.next_tick = now + SPIN_TICKS;
+        let ch = Self::FRAMES[self.frame];
+        self.frame = (self.frame + 1) % Self::FRAMES.len();
+        frame(|screen| {
+            let color = screen.color_code;
+            screen.write_at(self.row, self.col, ch, color);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen::SCREEN;
+
+    fn row_text(row: usize) -> [u8; 15] {
+        let screen = SCREEN.lock();
+        core::array::from_fn(|col| screen.buffer.read_char(row, col).ascii_char)
+    }
+
+    #[test_case]
+    fn test_progress_bar() {
+        let row = VGA_BUFFER_ROWS - 3;
+        let mut bar = ProgressBar::new(row, 0, 8);
+        for i in 0..=8 {
+            bar.set_progress(i as f32 / 8.0);
+            let text = row_text(row);
+            let filled = text[1..9].iter().filter(|&&ch| ch == b'#').count();
+            assert_eq!(filled, i);
+        }
+        assert_eq!(&row_text(row), b"[########] 100%");
+
+        // Going back redraws the cells as empty.
+        bar.set_progress(0.5);
+        assert_eq!(&row_text(row), b"[####----]  50%");
+        bar.set_progress(2.0);
+        assert_eq!(&row_text(row), b"[########] 100%");
+
+        // Drawn whole again after a scroll moved it.
+        frame(|screen| screen.scroll_lines(1));
+        bar.set_progress(1.0);
+        assert_eq!(&row_text(row), b"[########] 100%");
+    }
+}
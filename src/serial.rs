@@ -1,22 +1,232 @@
+pub mod line;
+#[cfg(test)]
+mod script;
+pub mod xmodem;
+
+pub use line::SerialMode;
+pub use xmodem::XmodemError;
+
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::Ordering;
 use uart_16550::SerialPort;
 
+use crate::console::Console;
 use crate::lazy_static;
+use crate::port::{PortRead, PortWrite};
 use crate::spinlock::SpinLock;
 
+const COM1: u16 = 0x3f8;
+
+crate::port_registers! {
+    /// The registers of a 16550 UART we use directly.
+    struct UartRegs {
+        data: u8 @ 0,
+        line_status: u8 @ 5,
+        /// It has no effect on the UART and just holds whatever byte is
+        /// written to it.
+        scratch: u8 @ 7,
+    }
+}
+
+/// Set in the line status register when a byte has been received.
+const LINE_DATA_READY: u8 = 1;
+/// Set in the line status register when the UART can take another byte.
+const LINE_THR_EMPTY: u8 = 1 << 5;
+/// Set in the line status register when the last byte has been shifted
+/// out on the line, too.
+const LINE_TRANSMITTER_EMPTY: u8 = 1 << 6;
+/// Number of line status polls before `read_byte` gives up.
+const READ_POLL_LIMIT: usize = 1_000_000;
+/// Number of line status polls per byte before `emergency_write` sends it
+/// anyway.
+const WRITE_POLL_LIMIT: usize = 100_000;
+/// Number of line status polls before `flush` gives up, enough for a full
+/// FIFO.
+const FLUSH_POLL_LIMIT: usize = 16 * WRITE_POLL_LIMIT;
+
+static IS_PRESENT: AtomicBool = AtomicBool::new(false);
+/// The last byte `emergency_write` sent, or `NO_BYTE`.
+static LAST_EMERGENCY_BYTE: AtomicU16 = AtomicU16::new(NO_BYTE);
+const NO_BYTE: u16 = u16::MAX;
+static IS_CANONICAL: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
     pub static ref SERIAL1: SpinLock<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3f8) };
-        serial_port.init();
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
+        // Safety: COM1 is the standard port of the first UART.
+        let is_present = unsafe { probe(COM1) };
+        if is_present {
+            serial_port.init();
+        }
+        IS_PRESENT.store(is_present, Ordering::Release);
         SpinLock::new(serial_port)
     };
 }
 
+/// Detect a UART by writing to its scratch register and reading it back.
+/// Reading an absent port gives 0xff, so both patterns can't pass.
+/// Safety:
+/// * base must not belong to another device.
+unsafe fn probe(base: u16) -> bool {
+    let mut scratch = UartRegs::new(base).scratch();
+    [0x55, 0xaa].into_iter().all(|pattern| unsafe {
+        scratch.write(pattern);
+        scratch.read() == pattern
+    })
+}
+
+/// Whether a UART is present at COM1.
+pub fn is_present() -> bool {
+    // Make sure the probe has run.
+    let _ = &*SERIAL1;
+    IS_PRESENT.load(Ordering::Acquire)
+}
+
+/// Read a byte from COM1, or None if nothing arrives for a while or
+/// there's no UART.
+pub fn read_byte() -> Option<u8> {
+    if !is_present() {
+        return None;
+    }
+    let mut line_status = UartRegs::new(COM1).line_status();
+    // Poll without the lock, so printing isn't blocked in the meantime.
+    // Safety: It's the line status register of COM1.
+    let is_ready = (0..READ_POLL_LIMIT)
+        .any(|_| unsafe { line_status.read() } & LINE_DATA_READY != 0);
+    if !is_ready {
+        return None;
+    }
+    Some(crate::x86_64::without_interrupts(|| SERIAL1.lock().receive()))
+}
+
+/// Write a raw byte to COM1, without the backspace translation of
+/// printing. Nothing happens if there's no UART.
+pub fn write_byte(byte: u8) {
+    if !is_present() {
+        return;
+    }
+    crate::x86_64::without_interrupts(|| SERIAL1.lock().send_raw(byte));
+}
+
+/// Write bytes to COM1 with nothing but port accesses: no lock, no lazy
+/// static, no formatting. For a panic handler when `SERIAL1` may be held
+/// or half initialized. It relies on the UART being set up already, and
+/// never hangs if it isn't there.
+pub fn emergency_write(bytes: &[u8]) {
+    let regs = UartRegs::new(COM1);
+    let (mut line_status, mut data) = (regs.line_status(), regs.data());
+    for &byte in bytes {
+        // Safety: These are the line status and data registers of COM1.
+        unsafe {
+            let _ = (0..WRITE_POLL_LIMIT).any(|_| line_status.read() & LINE_THR_EMPTY != 0);
+            data.write(byte);
+        }
+        LAST_EMERGENCY_BYTE.store(byte as u16, Ordering::Relaxed);
+    }
+}
+
+/// The last byte `emergency_write` sent, e.g. for a test to tell that a
+/// handler got as far as its diagnostics.
+pub fn last_emergency_byte() -> Option<u8> {
+    match LAST_EMERGENCY_BYTE.load(Ordering::Relaxed) {
+        NO_BYTE => None,
+        byte => Some(byte as u8),
+    }
+}
+
+/// Wait until every byte written has left the UART, e.g. before QEMU
+/// exits and would cut the output short. Like `emergency_write`, it only
+/// touches the ports, and gives up after a while.
+pub fn flush() {
+    const DRAINED: u8 = LINE_THR_EMPTY | LINE_TRANSMITTER_EMPTY;
+    let mut line_status = UartRegs::new(COM1).line_status();
+    // Safety: It's the line status register of COM1. An absent UART reads
+    // all ones, so it returns at once.
+    let _ = (0..FLUSH_POLL_LIMIT).any(|_| unsafe { line_status.read() } & DRAINED == DRAINED);
+}
+
+/// Byte-level access to a serial line, so protocols can be tested with
+/// a scripted peer.
+pub trait SerialIo {
+    /// Read a byte, or None if nothing arrives in time.
+    fn read_byte(&mut self) -> Option<u8>;
+    fn write_byte(&mut self, byte: u8);
+}
+
+/// The serial line at COM1.
+pub struct Com1;
+
+impl SerialIo for Com1 {
+    fn read_byte(&mut self) -> Option<u8> {
+        read_byte()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        write_byte(byte)
+    }
+}
+
+/// Choose how `read` treats input. It's raw by default.
+pub fn set_mode(mode: SerialMode) {
+    IS_CANONICAL.store(mode == SerialMode::Canonical, Ordering::Relaxed);
+}
+
+pub fn mode() -> SerialMode {
+    if IS_CANONICAL.load(Ordering::Relaxed) {
+        SerialMode::Canonical
+    } else {
+        SerialMode::Raw
+    }
+}
+
+/// Read input from COM1 into buf according to the current mode. See
+/// `line::read_with` for details.
+pub fn read(buf: &mut [u8]) -> usize {
+    line::read_with(&mut Com1, mode(), buf)
+}
+
+/// Receive a file over COM1 with XMODEM, e.g. `sx` on the host side.
+/// See `xmodem::xmodem_recv_with` for details. Anything else printed
+/// over serial during the transfer confuses the sender.
+pub fn xmodem_recv(buf: &mut [u8]) -> Result<usize, XmodemError> {
+    xmodem::xmodem_recv_with(&mut Com1, buf)
+}
+
+/// Console that prints to `SERIAL1`.
+pub struct SerialConsole;
+
+impl Console for SerialConsole {
+    fn write_str(&self, s: &str) {
+        use core::fmt::Write;
+        if !is_present() {
+            return;
+        }
+        // SerialPort never fails to write.
+        let _ = SERIAL1.lock().write_str(s);
+    }
+}
+
+/// `fmt::Write` adapter over `serial_print!`, for code that takes a writer.
+pub struct SerialWriter;
+
+impl core::fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        crate::serial_print!("{}", s);
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    crate::x86_64::without_interrupts(
-        || SERIAL1.lock().write_fmt(args).unwrap()
-    );
+    if !is_present() {
+        return;
+    }
+    // Only a `Display` impl can fail here, see `console::_print`.
+    crate::x86_64::without_interrupts(|| {
+        let _ = SERIAL1.lock().write_fmt(args);
+    });
 }
 
 #[macro_export]
@@ -35,3 +245,49 @@ macro_rules! serial_println {
         $crate::serial_print!("{}\n", ::core::format_args!($($arg)*))
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_probe() {
+        assert!(is_present());
+        assert!(unsafe { probe(COM1) });
+    }
+
+    #[test_case]
+    fn test_emergency_write() {
+        // It would hang if it took the lock.
+        let guard = SERIAL1.lock();
+        emergency_write(b"emergency ");
+        drop(guard);
+        assert_eq!(last_emergency_byte(), Some(b' '));
+        // The UART took all the bytes.
+        let mut line_status = UartRegs::new(COM1).line_status();
+        let drained = (0..WRITE_POLL_LIMIT)
+            .any(|_| unsafe { line_status.read() } & LINE_THR_EMPTY != 0);
+        assert!(drained);
+    }
+
+    #[test_case]
+    fn test_flush() {
+        for _ in 0..8 {
+            crate::serial_print!("flush me please, ");
+        }
+        crate::serial_println!();
+        flush();
+        let status = unsafe { UartRegs::new(COM1).line_status().read() };
+        assert_eq!(status & LINE_TRANSMITTER_EMPTY, LINE_TRANSMITTER_EMPTY);
+        assert_eq!(status & LINE_THR_EMPTY, LINE_THR_EMPTY);
+    }
+
+    #[test_case]
+    fn test_print_without_uart() {
+        // Pretend the probe failed.
+        IS_PRESENT.store(false, Ordering::Release);
+        serial_println!("this goes nowhere");
+        SerialConsole.write_str("neither does this");
+        IS_PRESENT.store(true, Ordering::Release);
+    }
+}
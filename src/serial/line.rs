@@ -0,0 +1,133 @@
+// Line discipline for serial input, like a TTY's raw and canonical modes.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::SerialIo;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const BELL: u8 = 0x07;
+
+/// Whether the last line read ended with '\r', so that the '\n' of a
+/// CRLF right after it isn't taken for another, empty line. COM1 is the
+/// only line read from.
+static AFTER_CR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialMode {
+    /// Every byte is passed on as is, without echo.
+    Raw,
+    /// Input is collected into lines, with local echo and backspace
+    /// editing. '\r', '\n' and "\r\n" all end a line.
+    Canonical,
+}
+
+/// Read input from `io` into buf according to mode. Returns the number of
+/// bytes delivered.
+///
+/// In raw mode, that's at most one byte, and 0 if nothing arrives in
+/// time. In canonical mode, it blocks until a line ends, and delivers the
+/// line without the line ending. Input beyond the size of buf is rejected
+/// with a bell.
+pub fn read_with<S: SerialIo>(io: &mut S, mode: SerialMode, buf: &mut [u8]) -> usize {
+    match mode {
+        SerialMode::Raw => match (buf.first_mut(), io.read_byte()) {
+            (Some(slot), Some(byte)) => {
+                AFTER_CR.store(false, Ordering::Relaxed);
+                *slot = byte;
+                1
+            }
+            _ => 0,
+        },
+        SerialMode::Canonical => read_line(io, buf),
+    }
+}
+
+fn read_line<S: SerialIo>(io: &mut S, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        let Some(byte) = io.read_byte() else {
+            continue;
+        };
+        if AFTER_CR.swap(false, Ordering::Relaxed) && byte == b'\n' {
+            continue;
+        }
+        match byte {
+            b'\r' | b'\n' => {
+                AFTER_CR.store(byte == b'\r', Ordering::Relaxed);
+                io.write_byte(b'\r');
+                io.write_byte(b'\n');
+                return len;
+            }
+            BACKSPACE | DELETE => {
+                if len > 0 {
+                    len -= 1;
+                    // Move back, blank it out, and move back again.
+                    io.write_byte(BACKSPACE);
+                    io.write_byte(b' ');
+                    io.write_byte(BACKSPACE);
+                }
+            }
+            byte if len < buf.len() => {
+                buf[len] = byte;
+                len += 1;
+                io.write_byte(byte);
+            }
+            _ => io.write_byte(BELL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::script::Script;
+
+    #[test_case]
+    fn test_canonical() {
+        let mut io = Script::new(b"ab\x7fc\rd");
+        let mut buf = [0; 8];
+        let len = read_with(&mut io, SerialMode::Canonical, &mut buf);
+        assert_eq!(&buf[..len], b"ac");
+        assert_eq!(io.output(), b"ab\x08 \x08c\r\n");
+        // The rest is left for the next read.
+        assert_eq!(io.input, b"d");
+    }
+
+    #[test_case]
+    fn test_canonical_crlf() {
+        let mut io = Script::new(b"ab\r\ncd\n\nef\r");
+        let mut buf = [0; 8];
+        let len = read_with(&mut io, SerialMode::Canonical, &mut buf);
+        assert_eq!(&buf[..len], b"ab");
+        // The '\n' of the CRLF doesn't end another line.
+        let len = read_with(&mut io, SerialMode::Canonical, &mut buf);
+        assert_eq!(&buf[..len], b"cd");
+        // But an '\n' after an '\n' does.
+        assert_eq!(read_with(&mut io, SerialMode::Canonical, &mut buf), 0);
+        let len = read_with(&mut io, SerialMode::Canonical, &mut buf);
+        assert_eq!(&buf[..len], b"ef");
+        assert_eq!(io.input, b"");
+    }
+
+    #[test_case]
+    fn test_canonical_full() {
+        let mut io = Script::new(b"\x08abc\n");
+        let mut buf = [0; 2];
+        let len = read_with(&mut io, SerialMode::Canonical, &mut buf);
+        assert_eq!(&buf[..len], b"ab");
+        assert_eq!(io.output(), b"ab\x07\r\n");
+    }
+
+    #[test_case]
+    fn test_raw() {
+        let mut io = Script::new(b"\x7f\r");
+        let mut buf = [0; 8];
+        assert_eq!(read_with(&mut io, SerialMode::Raw, &mut buf), 1);
+        assert_eq!(buf[0], 0x7f);
+        assert_eq!(read_with(&mut io, SerialMode::Raw, &mut buf), 1);
+        assert_eq!(buf[0], b'\r');
+        assert_eq!(read_with(&mut io, SerialMode::Raw, &mut buf), 0);
+        assert_eq!(io.output(), b"");
+    }
+}
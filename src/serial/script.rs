@@ -0,0 +1,42 @@
+// A scripted serial line for testing protocols on top of `SerialIo`.
+
+use super::SerialIo;
+
+/// Plays back a script of incoming bytes, and records what's written
+/// back. Output past the first 32 bytes is counted but not kept.
+pub(super) struct Script<'a> {
+    /// What's left to be read.
+    pub(super) input: &'a [u8],
+    output: [u8; 32],
+    /// Number of bytes written, including those that weren't kept.
+    pub(super) output_len: usize,
+}
+
+impl<'a> Script<'a> {
+    pub(super) fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            output: [0; 32],
+            output_len: 0,
+        }
+    }
+
+    pub(super) fn output(&self) -> &[u8] {
+        &self.output[..self.output_len.min(self.output.len())]
+    }
+}
+
+impl SerialIo for Script<'_> {
+    fn read_byte(&mut self) -> Option<u8> {
+        let (&first, rest) = self.input.split_first()?;
+        self.input = rest;
+        Some(first)
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if let Some(slot) = self.output.get_mut(self.output_len) {
+            *slot = byte;
+        }
+        self.output_len += 1;
+    }
+}
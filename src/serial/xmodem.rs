@@ -0,0 +1,172 @@
+// XMODEM receiver, the original checksum variant.
+// See http://wiki.synchro.net/ref:xmodem
+
+use super::SerialIo;
+
+const SOH: u8 = 0x01;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+
+/// XMODEM always sends 128 bytes of data per block.
+pub const BLOCK_SIZE: usize = 128;
+/// Consecutive errors before we give up.
+const MAX_ERRORS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmodemError {
+    /// Nothing valid came from the sender for too long.
+    Timeout,
+    /// The sender cancelled the transfer.
+    Cancelled,
+    /// The sender skipped a block, which can't be recovered.
+    OutOfSync,
+    /// The next block doesn't fit into the buffer.
+    BufferTooSmall,
+}
+
+/// Receive a file into buf over `io`. Returns the length received.
+///
+/// The data always comes in whole 128-byte blocks, so the length is a
+/// multiple of 128 and the last block is usually padded by the sender
+/// (often with 0x1a). buf must have room for the padding as well.
+pub fn xmodem_recv_with<S: SerialIo>(io: &mut S, buf: &mut [u8]) -> Result<usize, XmodemError> {
+    let mut expected: u8 = 1;
+    let mut len = 0;
+    let mut errors = 0;
+    let mut block = [0u8; BLOCK_SIZE];
+
+    // NAK asks the sender to start, or to send the last block again.
+    io.write_byte(NAK);
+    loop {
+        if errors > MAX_ERRORS {
+            io.write_byte(CAN);
+            return Err(XmodemError::Timeout);
+        }
+        match io.read_byte() {
+            Some(SOH) => (),
+            Some(EOT) => {
+                io.write_byte(ACK);
+                return Ok(len);
+            }
+            Some(CAN) => return Err(XmodemError::Cancelled),
+            // Timeout or garbage.
+            _ => {
+                errors += 1;
+                io.write_byte(NAK);
+                continue;
+            }
+        }
+
+        let Some(number) = io.read_byte() else {
+            errors += 1;
+            io.write_byte(NAK);
+            continue;
+        };
+        let Some(complement) = io.read_byte() else {
+            errors += 1;
+            io.write_byte(NAK);
+            continue;
+        };
+        let mut is_complete = true;
+        for byte in block.iter_mut() {
+            match io.read_byte() {
+                Some(b) => *byte = b,
+                None => {
+                    is_complete = false;
+                    break;
+                }
+            }
+        }
+        let checksum = block.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        if !is_complete || number != !complement || io.read_byte() != Some(checksum) {
+            errors += 1;
+            io.write_byte(NAK);
+            continue;
+        }
+
+        if number == expected.wrapping_sub(1) {
+            // Our ACK got lost and the sender repeats the last block.
+            io.write_byte(ACK);
+            continue;
+        }
+        if number != expected {
+            io.write_byte(CAN);
+            return Err(XmodemError::OutOfSync);
+        }
+        let Some(dest) = buf.get_mut(len..len + BLOCK_SIZE) else {
+            io.write_byte(CAN);
+            return Err(XmodemError::BufferTooSmall);
+        };
+        dest.copy_from_slice(&block);
+        len += BLOCK_SIZE;
+        expected = expected.wrapping_add(1);
+        errors = 0;
+        io.write_byte(ACK);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::script::Script;
+
+    const FRAME_SIZE: usize = BLOCK_SIZE + 4;
+
+    fn frame(number: u8, fill: u8, out: &mut [u8]) {
+        out[0] = SOH;
+        out[1] = number;
+        out[2] = !number;
+        out[3..3 + BLOCK_SIZE].fill(fill);
+        out[3 + BLOCK_SIZE] = fill.wrapping_mul(BLOCK_SIZE as u8);
+    }
+
+    #[test_case]
+    fn test_xmodem_recv() {
+        let mut input = [0u8; FRAME_SIZE * 4 + 1];
+        frame(1, b'a', &mut input[..FRAME_SIZE]);
+        // A corrupted block, then the resent one.
+        frame(2, b'b', &mut input[FRAME_SIZE..][..FRAME_SIZE]);
+        input[FRAME_SIZE * 2 - 1] ^= 1;
+        frame(2, b'b', &mut input[FRAME_SIZE * 2..][..FRAME_SIZE]);
+        // A duplicate of the block we've just acked.
+        frame(2, b'b', &mut input[FRAME_SIZE * 3..][..FRAME_SIZE]);
+        input[FRAME_SIZE * 4] = EOT;
+
+        let mut script = Script::new(&input);
+        let mut buf = [0u8; BLOCK_SIZE * 3];
+        assert_eq!(xmodem_recv_with(&mut script, &mut buf), Ok(BLOCK_SIZE * 2));
+        assert_eq!(script.output(), [NAK, ACK, NAK, ACK, ACK, ACK]);
+        assert!(buf[..BLOCK_SIZE].iter().all(|&b| b == b'a'));
+        assert!(buf[BLOCK_SIZE..BLOCK_SIZE * 2].iter().all(|&b| b == b'b'));
+        assert!(buf[BLOCK_SIZE * 2..].iter().all(|&b| b == 0));
+    }
+
+    #[test_case]
+    fn test_xmodem_recv_errors() {
+        let mut script = Script::new(&[]);
+        let mut buf = [0u8; BLOCK_SIZE];
+        assert_eq!(
+            xmodem_recv_with(&mut script, &mut buf),
+            Err(XmodemError::Timeout)
+        );
+        assert_eq!(script.output_len, MAX_ERRORS + 3);
+
+        let mut input = [0u8; FRAME_SIZE * 2];
+        frame(1, b'a', &mut input[..FRAME_SIZE]);
+        frame(2, b'b', &mut input[FRAME_SIZE..]);
+        let mut script = Script::new(&input);
+        assert_eq!(
+            xmodem_recv_with(&mut script, &mut buf),
+            Err(XmodemError::BufferTooSmall)
+        );
+        assert_eq!(script.output(), [NAK, ACK, CAN]);
+
+        let mut script = Script::new(&[CAN]);
+        assert_eq!(
+            xmodem_recv_with(&mut script, &mut buf),
+            Err(XmodemError::Cancelled)
+        );
+    }
+}
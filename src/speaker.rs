@@ -0,0 +1,63 @@
+// The PC speaker, driven by PIT channel 2.
+// See https://wiki.osdev.org/PC_Speaker
+
+use crate::pit;
+use crate::port::{Port, PortRead, PortWrite};
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2, lobyte/hibyte access, square wave generator.
+const CMD_CHANNEL2_SQUARE_WAVE: u8 = 0xb6;
+
+const SPEAKER_CONTROL: u16 = 0x61;
+/// Bit 0 gates PIT channel 2, and bit 1 connects it to the speaker.
+const SPEAKER_GATE: u8 = 0b11;
+
+/// Play a tone of `hz` for `duration_ms` milliseconds. It blocks and uses
+/// the timer to wait.
+/// # Panics
+/// Panics if hz is 0 or interrupts are disabled.
+pub fn beep(hz: u32, duration_ms: u64) {
+    assert!(hz > 0, "beep with 0 hz");
+    let divisor = (pit::BASE_FREQUENCY / hz).clamp(1, u16::MAX as u32) as u16;
+
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel2: Port<u8> = Port::new(PIT_CHANNEL2);
+    let mut control: Port<u8> = Port::new(SPEAKER_CONTROL);
+
+    // Safety:
+    // These are the standard PIT and speaker ports, and nobody else
+    // uses channel 2.
+    let saved = unsafe {
+        command.write(CMD_CHANNEL2_SQUARE_WAVE);
+        channel2.write(divisor as u8);
+        channel2.write((divisor >> 8) as u8);
+
+        let saved: u8 = control.read();
+        control.write(saved | SPEAKER_GATE);
+        saved
+    };
+
+    pit::sleep_ms(duration_ms);
+
+    // Safety: Same as above.
+    unsafe {
+        let current: u8 = control.read();
+        control.write((current & !SPEAKER_GATE) | (saved & SPEAKER_GATE));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_beep_restores_gate() {
+        crate::init();
+        let mut control: Port<u8> = Port::new(SPEAKER_CONTROL);
+        let before: u8 = unsafe { control.read() };
+        beep(440, 10);
+        let after: u8 = unsafe { control.read() };
+        assert_eq!(after & SPEAKER_GATE, before & SPEAKER_GATE);
+    }
+}
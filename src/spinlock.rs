@@ -5,6 +5,12 @@ use core::ops::DerefMut;
 use core::sync::atomic::AtomicBool;
 use core::sync::atomic::Ordering;
 
+/// A lock that busy-waits until it's free.
+///
+/// The value can be unsized. Since we don't have an allocator, a lock of
+/// an unsized value can't be constructed directly. Instead, construct a
+/// sized one and let the reference coerce, e.g. `&SpinLock<Console>` into
+/// `&SpinLock<dyn Write>`. This works because the value is the last field.
 pub struct SpinLock<T: ?Sized> {
     is_locked: AtomicBool,
     value: UnsafeCell<T>,
@@ -12,6 +18,10 @@ pub struct SpinLock<T: ?Sized> {
 
 pub struct SpinLockGuard<'a, T: ?Sized>(&'a SpinLock<T>);
 
+/// The lock is still held by someone else after the given spins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
 impl<T> SpinLock<T> {
     pub const fn new(value: T) -> Self {
         Self {
@@ -21,6 +31,18 @@ impl<T> SpinLock<T> {
     }
 }
 
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for SpinLock<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
 impl<T: ?Sized> SpinLock<T> {
     pub fn lock(&self) -> SpinLockGuard<T> {
         // TODO: Not quite sure about the Ordering, check these later.
@@ -34,6 +56,47 @@ impl<T: ?Sized> SpinLock<T> {
 
         SpinLockGuard(self)
     }
+
+    /// Try to take the lock once, without spinning.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        self.is_locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Acquire)
+            .ok()
+            .map(|_| SpinLockGuard(self))
+    }
+
+    /// Spin up to `max_spins` times for the lock. Useful where waiting
+    /// forever is worse than failing, e.g. in a panic handler.
+    pub fn lock_timeout(&self, max_spins: u32) -> Result<SpinLockGuard<T>, Timeout> {
+        for _ in 0..max_spins {
+            if let Some(guard) = self.try_lock() {
+                return Ok(guard);
+            }
+            core::hint::spin_loop();
+        }
+        // One last try, so that 0 spins still means a single attempt.
+        self.try_lock().ok_or(Timeout)
+    }
+
+    /// Run `f` on the value with the lock held, releasing it as soon as
+    /// `f` returns.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    /// Release the lock no matter who holds it. Meant for a panic handler
+    /// to reclaim e.g. the screen from code that panicked while holding
+    /// it: with `panic=abort`, the guard is never dropped.
+    ///
+    /// Safety:
+    /// * The holder must never touch the value or drop its guard again,
+    ///   e.g. because it will never run again. Dropping the guard later
+    ///   panics, or worse, releases the lock under a new holder.
+    /// * The holder may have been in the middle of changing the value, so
+    ///   the value may be inconsistent. Use it as little as possible.
+    pub unsafe fn force_unlock(&self) {
+        self.is_locked.store(false, Ordering::Release);
+    }
 }
 
 impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
@@ -69,3 +132,82 @@ impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
 // or Sync even in Mutex.
 unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
 unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    struct Counter(usize);
+
+    impl Write for Counter {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            self.0 += s.len();
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn test_force_unlock() {
+        let lock = SpinLock::new(1);
+        let guard = lock.lock();
+        // Like a holder that panicked: its guard is never dropped.
+        core::mem::forget(guard);
+        assert!(lock.try_lock().is_none());
+
+        unsafe { lock.force_unlock() };
+        let mut guard = lock.try_lock().unwrap();
+        *guard += 1;
+        drop(guard);
+        assert_eq!(*lock.lock(), 2);
+    }
+
+    #[test_case]
+    fn test_unsized_value() {
+        static COUNTER: SpinLock<Counter> = SpinLock::new(Counter(0));
+
+        let writer: &SpinLock<dyn Write + Send> = &COUNTER;
+        write!(writer.lock(), "hello").unwrap();
+        writer.lock().write_str(", world").unwrap();
+        assert_eq!((*COUNTER.lock()).0, 12);
+    }
+
+    #[test_case]
+    fn test_dyn_write_ref() {
+        let mut counter = Counter(0);
+        {
+            let writer = SpinLock::new(&mut counter as &mut dyn Write);
+            write!(writer.lock(), "{}", 42).unwrap();
+        }
+        assert_eq!(counter.0, 2);
+    }
+
+    #[test_case]
+    fn test_with() {
+        let lock = SpinLock::new(1);
+        let old = lock.with(|value| core::mem::replace(value, 2));
+        assert_eq!(old, 1);
+        // Released on return.
+        assert_eq!(lock.try_lock().map(|guard| *guard), Some(2));
+    }
+
+    #[test_case]
+    fn test_default_and_from() {
+        let lock: SpinLock<u32> = Default::default();
+        assert_eq!(*lock.lock(), 0);
+        let lock = SpinLock::from(7u32);
+        assert_eq!(*lock.lock(), 7);
+    }
+
+    #[test_case]
+    fn test_lock_timeout() {
+        let lock = SpinLock::new(0);
+        {
+            let _guard = lock.lock();
+            assert!(matches!(lock.lock_timeout(100), Err(Timeout)));
+            assert!(lock.try_lock().is_none());
+        }
+        *lock.lock_timeout(100).unwrap() += 1;
+        assert_eq!(*lock.try_lock().unwrap(), 1);
+    }
+}
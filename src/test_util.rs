@@ -0,0 +1,155 @@
+use crate::console::{self, Console};
+use crate::spinlock::SpinLock;
+use core::fmt;
+
+/// A fixed-capacity string to collect formatted output in tests.
+/// Writing past the capacity fails with `fmt::Error`.
+#[derive(Clone)]
+pub struct StrBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> StrBuf<N> {
+    pub const fn new() -> Self {
+        Self { buf: [0; N], len: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Safety: Only whole `&str`s are copied in.
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for StrBuf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for StrBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len.checked_add(s.len()).filter(|&end| end <= N).ok_or(fmt::Error)?;
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// A console that records everything written to it, so tests can assert
+/// exact output. Output past the capacity is dropped.
+///
+/// It stands in for `print!`'s console, not for `SCREEN`, which renders
+/// to cells rather than a stream. Screen tests render into a fake buffer
+/// with `Screen::with_base` instead.
+pub struct CaptureConsole<const N: usize> {
+    buf: SpinLock<StrBuf<N>>,
+}
+
+impl<const N: usize> CaptureConsole<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: SpinLock::new(StrBuf::new()),
+        }
+    }
+
+    /// A copy of what's been captured so far.
+    pub fn contents(&self) -> StrBuf<N> {
+        self.buf.lock().clone()
+    }
+
+    pub fn clear(&self) {
+        self.buf.lock().clear();
+    }
+
+    /// Run `f` with `print!` redirected here, then restore the previous
+    /// console.
+    pub fn capture<R>(&'static self, f: impl FnOnce() -> R) -> R {
+        let prev = console::set_output(self);
+        let ret = f();
+        console::set_output(prev);
+        ret
+    }
+}
+
+impl<const N: usize> Default for CaptureConsole<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Console for CaptureConsole<N> {
+    fn write_str(&self, s: &str) {
+        use core::fmt::Write;
+        let _ = self.buf.lock().write_str(s);
+    }
+}
+
+/// Like `assert!`, but reports over serial and exits QEMU with
+/// `QemuExitCode::Failed` instead of panicking, so a failure shows up
+/// the same way no matter what the test's panic handler does.
+#[macro_export]
+macro_rules! assert_serial {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::serial_println!("[Failed]");
+            $crate::serial_println!(
+                "assertion failed: {} at {}:{}",
+                ::core::stringify!($cond),
+                ::core::file!(),
+                ::core::line!(),
+            );
+            $crate::exit_qemu($crate::QemuExitCode::Failed);
+        }
+    };
+}
+
+/// Like `assert_eq!`, but reports over serial and exits QEMU with
+/// `QemuExitCode::Failed` instead of panicking.
+#[macro_export]
+macro_rules! assert_serial_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::serial_println!("[Failed]");
+                    $crate::serial_println!(
+                        "assertion failed: {} == {} at {}:{}",
+                        ::core::stringify!($left),
+                        ::core::stringify!($right),
+                        ::core::file!(),
+                        ::core::line!(),
+                    );
+                    $crate::serial_println!("  left: {:?}", left);
+                    $crate::serial_println!(" right: {:?}", right);
+                    $crate::exit_qemu($crate::QemuExitCode::Failed);
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::print;
+
+    #[test_case]
+    fn test_capture_console() {
+        static CAPTURE: CaptureConsole<64> = CaptureConsole::new();
+
+        // Nothing else may print into it meanwhile.
+        crate::x86_64::without_interrupts(|| {
+            CAPTURE.capture(|| print!("hello\nworld"));
+            print!("not captured");
+        });
+        assert_eq!(CAPTURE.contents().as_str(), "hello\nworld");
+
+        CAPTURE.clear();
+        assert_eq!(CAPTURE.contents().as_str(), "");
+    }
+}
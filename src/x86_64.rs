@@ -4,6 +4,7 @@ use core::arch::asm;
 use core::fmt;
 use core::ops;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum PrivilegeLevel {
     Ring0 = 0,
@@ -46,7 +47,44 @@ impl CS {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Define a data segment register, which is loaded with a plain `mov`
+/// unlike CS.
+macro_rules! data_segment_register {
+    ($name:ident, $reg:literal) => {
+        pub struct $name;
+
+        impl $name {
+            /// Safety:
+            #[doc = concat!("* input must be valid for ", $reg, ".")]
+            pub unsafe fn set_reg(sel: SegmentSelector) {
+                unsafe {
+                    asm!(
+                        concat!("mov ", $reg, ", {:x}"),
+                        in(reg) sel.0,
+                        options(nostack, preserves_flags)
+                    );
+                }
+            }
+
+            pub fn get_reg() -> SegmentSelector {
+                let sel: u16;
+                unsafe {
+                    asm!(
+                        concat!("mov {:x}, ", $reg),
+                        out(reg) sel,
+                        options(nomem, nostack, preserves_flags)
+                    );
+                }
+                SegmentSelector(sel)
+            }
+        }
+    };
+}
+
+data_segment_register!(DS, "ds");
+data_segment_register!(SS, "ss");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct SegmentSelector(u16);
 
@@ -57,6 +95,20 @@ impl SegmentSelector {
     pub const fn new(index: u16, rpl: PrivilegeLevel) -> Self {
         SegmentSelector(index << 3 | (rpl as u16))
     }
+
+    /// Index of the descriptor in the table.
+    pub const fn index(self) -> u16 {
+        self.0 >> 3
+    }
+
+    pub const fn rpl(self) -> PrivilegeLevel {
+        match self.0 & 0b11 {
+            0 => PrivilegeLevel::Ring0,
+            1 => PrivilegeLevel::Ring1,
+            2 => PrivilegeLevel::Ring2,
+            _ => PrivilegeLevel::Ring3,
+        }
+    }
 }
 
 // TODO: impl Debug
@@ -72,6 +124,12 @@ impl VirtAddr {
     pub fn from_ptr<T>(ptr: *const T) -> Self {
         VirtAddr(ptr as u64)
     }
+
+    /// Whether bits 48..64 are copies of bit 47. Using any other address
+    /// faults, with #GP or #SS.
+    pub const fn is_canonical(self) -> bool {
+        ((self.0 << 16) as i64 >> 16) as u64 == self.0
+    }
 }
 
 // TODO: consider those ops impls.
@@ -103,6 +161,30 @@ impl fmt::UpperHex for VirtAddr {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PhysAddr(pub u64);
+
+impl PhysAddr {
+    pub const fn zero() -> Self {
+        PhysAddr(0)
+    }
+}
+
+impl ops::Add<u64> for PhysAddr {
+    type Output = Self;
+
+    fn add(self, rhs: u64) -> Self::Output {
+        PhysAddr(self.0.checked_add(rhs).unwrap())
+    }
+}
+
+impl fmt::LowerHex for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed(2))]
 pub struct DescriptorTablePointer {
@@ -140,6 +222,246 @@ pub unsafe fn lgdt(gdt: &DescriptorTablePointer) {
     }
 }
 
+/// Store the current interrupt descriptor table register.
+#[inline]
+pub fn sidt() -> DescriptorTablePointer {
+    let mut idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        asm!(
+            "sidt [{}]",
+            in(reg) &mut idt,
+            options(nostack, preserves_flags)
+        );
+    }
+    idt
+}
+
+/// Store the current global descriptor table register.
+#[inline]
+pub fn sgdt() -> DescriptorTablePointer {
+    let mut gdt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        asm!(
+            "sgdt [{}]",
+            in(reg) &mut gdt,
+            options(nostack, preserves_flags)
+        );
+    }
+    gdt
+}
+
+/// Wait a tiny while, about 1µs, for slow legacy devices like the 8259
+/// PIC to catch up between port writes.
+///
+/// It writes a dummy byte to port 0x80, which the BIOS uses for POST
+/// codes and is otherwise unused after boot. The write goes out on the
+/// ISA bus, which is slow enough to act as the delay.
+#[inline]
+pub fn io_wait() {
+    // Safety: Nothing listens on port 0x80 after boot.
+    unsafe {
+        asm!(
+            "out 0x80, al",
+            in("al") 0u8,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
+
+/// Write back and invalidate all caches of this CPU.
+///
+/// It's extremely expensive: it flushes every dirty line to memory and
+/// may take millions of cycles, during which interrupts aren't served.
+/// Prefer `clflush` when the affected range is known.
+/// Safety:
+/// * Only meant for ring 0, and nothing relies on the cache contents.
+#[inline]
+pub unsafe fn wbinvd() {
+    unsafe {
+        asm!("wbinvd", options(nostack, preserves_flags));
+    }
+}
+
+/// Write back and invalidate the cache line holding `addr`.
+/// Safety:
+/// * addr must be mapped, otherwise it's a page fault.
+#[inline]
+pub unsafe fn clflush(addr: *const u8) {
+    unsafe {
+        asm!(
+            "clflush [{}]",
+            in(reg) addr,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// The page table base register. Bits 12..52 hold the frame of the level
+/// 4 table and bits 0..12 the flags, or the PCID if enabled.
+pub struct Cr3;
+
+impl Cr3 {
+    pub fn read_raw() -> u64 {
+        let value: u64;
+        // Safety: Reading CR3 has no side effect.
+        unsafe {
+            asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags));
+        }
+        value
+    }
+
+    /// The frame of the level 4 table and the flags.
+    pub fn read() -> (PhysAddr, u16) {
+        use crate::bit_field::BitField;
+
+        let value = Self::read_raw();
+        (PhysAddr(value.get_bits(12..52) << 12), value.get_bits(0..12) as u16)
+    }
+
+    /// Switch to the level 4 table at frame. Pass the flags from `read`
+    /// to keep them.
+    ///
+    /// Safety:
+    /// * The table must map the running code and data the same.
+    ///
+    /// # Panics
+    /// Panics if frame isn't page aligned or flags don't fit in 12 bits.
+    pub unsafe fn write(frame: PhysAddr, flags: u16) {
+        let value = Self::compose(frame, flags);
+        // Safety: Guaranteed by the caller.
+        unsafe {
+            asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+        }
+    }
+
+    fn compose(frame: PhysAddr, flags: u16) -> u64 {
+        use crate::bit_field::BitField;
+
+        assert!(frame.0.get_bits(0..12) == 0, "unaligned frame {:#x}", frame);
+        let mut value = 0;
+        value.set_bits(12..52, frame.0 >> 12);
+        value.set_bits(0..12, flags as u64);
+        value
+    }
+}
+
+/// Physical address of the level 4 page table, without the flags.
+#[inline]
+pub fn read_cr3_frame() -> PhysAddr {
+    Cr3::read().0
+}
+
+/// Drop the TLB entry of the page holding `addr`.
+/// Safety:
+/// * Only meant for ring 0.
+#[inline]
+pub unsafe fn invlpg(addr: VirtAddr) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) addr.0, options(nostack, preserves_flags));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Query the CPU about leaf and subleaf.
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u64, u32, u32);
+    // Safety: CPUID is always there in 64-bit mode. LLVM keeps rbx for
+    // itself, so it's swapped out and back.
+    unsafe {
+        asm!(
+            "mov {tmp}, rbx",
+            "cpuid",
+            "xchg {tmp}, rbx",
+            tmp = out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    CpuidResult {
+        eax,
+        ebx: ebx as u32,
+        ecx,
+        edx,
+    }
+}
+
+/// Tries before giving up on an exhausted RNG, as recommended by Intel
+/// for RDRAND. RDSEED runs out faster, so it gets more.
+const RDRAND_RETRIES: usize = 10;
+const RDSEED_RETRIES: usize = 100;
+
+fn has_rdrand() -> bool {
+    use crate::bit_field::BitField;
+    cpuid(1, 0).ecx.get_bits(30) == 1
+}
+
+fn has_rdseed() -> bool {
+    use crate::bit_field::BitField;
+    cpuid(0, 0).eax >= 7 && cpuid(7, 0).ebx.get_bits(18) == 1
+}
+
+/// A random number from the CPU's RNG, or None if there's no RDRAND or
+/// it keeps failing.
+pub fn rdrand64() -> Option<u64> {
+    if !has_rdrand() {
+        return None;
+    }
+    (0..RDRAND_RETRIES).find_map(|_| {
+        let (value, ok): (u64, u8);
+        // Safety: The CPU supports it.
+        unsafe {
+            asm!(
+                "rdrand {}",
+                "setc {}",
+                out(reg) value,
+                out(reg_byte) ok,
+                options(nomem, nostack)
+            );
+        }
+        (ok == 1).then_some(value)
+    })
+}
+
+/// A random number straight from the CPU's entropy source, for seeding,
+/// or None if there's no RDSEED or it keeps failing.
+pub fn rdseed64() -> Option<u64> {
+    if !has_rdseed() {
+        return None;
+    }
+    (0..RDSEED_RETRIES).find_map(|_| {
+        let (value, ok): (u64, u8);
+        // Safety: The CPU supports it.
+        unsafe {
+            asm!(
+                "rdseed {}",
+                "setc {}",
+                out(reg) value,
+                out(reg_byte) ok,
+                options(nomem, nostack)
+            );
+        }
+        if ok == 0 {
+            core::hint::spin_loop();
+        }
+        (ok == 1).then_some(value)
+    })
+}
+
 #[inline]
 pub fn int3() {
     unsafe {
@@ -177,10 +499,28 @@ pub fn hlt() {
     }
 }
 
+/// Enable interrupts and halt until the next one.
+///
+/// `sti` only takes effect after the following instruction, so nothing
+/// can be serviced between `sti` and `hlt` here. Had an interrupt slipped
+/// in between separate `enable_interrupt(); hlt();`, e.g. the one we
+/// checked for before going to sleep, the `hlt` would wait for another
+/// one that may never come.
+#[inline]
+pub fn enable_and_hlt() {
+    unsafe {
+        asm!(
+            "sti",
+            "hlt",
+            options(nomem, nostack)
+        )
+    }
+}
+
 #[inline]
 pub fn hlt_loop() -> ! {
     loop {
-        hlt();
+        enable_and_hlt();
     }
 }
 
@@ -204,8 +544,7 @@ pub fn disable_interrupt() {
     }
 }
 
-pub fn is_interrupt_enabled() -> bool {
-    const INTERRUPT_FLAG: u64 = 1 << 9;
+pub fn read_rflags() -> u64 {
     let rflags: u64;
     unsafe {
         asm!(
@@ -215,21 +554,112 @@ pub fn is_interrupt_enabled() -> bool {
             options(nomem, preserves_flags)
         );
     }
-    rflags & INTERRUPT_FLAG != 0
+    rflags
 }
 
+pub fn is_interrupt_enabled() -> bool {
+    const INTERRUPT_FLAG: u64 = 1 << 9;
+    read_rflags() & INTERRUPT_FLAG != 0
+}
+
+/// Run `f` with interrupts disabled and pass its return value through.
+/// Interrupts are restored to their previous state afterward.
+///
+/// `FnOnce` accepts any closure, and `&mut F` works for an `FnMut` that
+/// is needed again later.
+///
+/// If interrupts are already disabled, e.g. inside an interrupt handler,
+/// `f` is run directly and IF is left alone.
 pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    // Fast path: nothing to disable or restore.
+    if !is_interrupt_enabled() {
+        return f();
+    }
     // TODO: will it cause a race condition where interrupt state changed
     // during the process?
-    let is_enabled = is_interrupt_enabled();
-    if is_enabled {
-        disable_interrupt();
-        let ret = f();
-        enable_interrupt();
-        ret
-    } else {
-        f()
+    disable_interrupt();
+    let ret = f();
+    enable_interrupt();
+    ret
+}
+
+/// Read the stack pointer.
+///
+/// It's inlined, so the value is the stack pointer inside the caller's
+/// frame, and is only meaningful relative to that frame.
+#[inline(always)]
+pub fn read_rsp() -> u64 {
+    let rsp: u64;
+    unsafe {
+        asm!(
+            "mov {}, rsp",
+            out(reg) rsp,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    rsp
+}
+
+/// Read the frame pointer. It's only a frame chain if the code is
+/// compiled with frame pointers.
+#[inline(always)]
+pub fn read_rbp() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!(
+            "mov {}, rbp",
+            out(reg) rbp,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    rbp
+}
+
+/// Model specific register holding the GS segment base.
+pub const IA32_GS_BASE: u32 = 0xc000_0101;
+
+/// Read a model specific register.
+/// Safety:
+/// * msr exists on this CPU, otherwise it's a #GP.
+#[inline]
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!(
+            "rdmsr",
+            in("ecx") msr,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags)
+        );
     }
+    ((high as u64) << 32) | low as u64
+}
+
+/// Write a model specific register.
+/// Safety:
+/// * msr exists on this CPU, otherwise it's a #GP.
+/// * The value doesn't break anything relying on the register.
+#[inline]
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// The selector of the TSS in use, NULL if none is loaded yet.
+pub fn read_tr() -> SegmentSelector {
+    let tr: u16;
+    unsafe {
+        asm!("str {:x}", out(reg) tr, options(nomem, nostack, preserves_flags));
+    }
+    SegmentSelector(tr)
 }
 
 /// Safety:
@@ -241,3 +671,150 @@ pub unsafe fn load_tss(tss: SegmentSelector) {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_without_interrupts_returns_value() {
+        let values = [1, 2, 3, 4];
+        let mut calls = 0;
+        let sum: u32 = without_interrupts(|| {
+            calls += 1;
+            values.iter().sum()
+        });
+        assert_eq!(sum, 10);
+        assert_eq!(calls, 1);
+
+        let mut total = 0;
+        let mut add_sum = || {
+            total += sum;
+            total
+        };
+        assert_eq!(without_interrupts(&mut add_sum), 10);
+        assert_eq!(without_interrupts(&mut add_sum), 20);
+    }
+
+    #[test_case]
+    fn test_without_interrupts_already_disabled() {
+        let was_enabled = is_interrupt_enabled();
+        disable_interrupt();
+
+        let inner = without_interrupts(is_interrupt_enabled);
+        assert!(!inner);
+        assert!(!is_interrupt_enabled());
+
+        if was_enabled {
+            enable_interrupt();
+        }
+    }
+
+    #[test_case]
+    fn test_enable_and_hlt() {
+        crate::init();
+        disable_interrupt();
+        let start = crate::pit::ticks();
+        // The timer wakes us up.
+        while crate::pit::ticks() == start {
+            enable_and_hlt();
+            assert!(is_interrupt_enabled());
+            disable_interrupt();
+        }
+        enable_interrupt();
+    }
+
+    #[test_case]
+    fn test_cr3() {
+        let raw = Cr3::read_raw();
+        let (frame, flags) = Cr3::read();
+        assert_eq!(frame, read_cr3_frame());
+        assert_eq!(Cr3::compose(frame, flags), raw);
+        // Writing it back changes nothing but flushes the TLB.
+        unsafe { Cr3::write(frame, flags) };
+        assert_eq!(Cr3::read_raw(), raw);
+    }
+
+    #[test_case]
+    fn test_sidt_sgdt() {
+        // Never used to handle anything, interrupts are off while loaded.
+        static TABLE: [u64; 4] = [0; 4];
+        let table = DescriptorTablePointer {
+            limit: (core::mem::size_of_val(&TABLE) - 1) as u16,
+            base: VirtAddr(TABLE.as_ptr() as u64),
+        };
+        let (limit, base) = without_interrupts(|| {
+            let previous = sidt();
+            unsafe { lidt(&table) };
+            let loaded = sidt();
+            unsafe { lidt(&previous) };
+            (loaded.limit, loaded.base)
+        });
+        assert_eq!(limit, 31);
+        assert_eq!(base, VirtAddr(TABLE.as_ptr() as u64));
+
+        let gdt = sgdt();
+        unsafe { lgdt(&gdt) };
+        let (limit, base) = (gdt.limit, gdt.base);
+        let reloaded = sgdt();
+        assert_eq!((reloaded.limit, reloaded.base), (limit, base));
+    }
+
+    #[test_case]
+    fn test_rdrand_rdseed() {
+        // The vendor string, e.g. "GenuineIntel", is never all zeros.
+        let vendor = cpuid(0, 0);
+        assert_ne!([vendor.ebx, vendor.edx, vendor.ecx], [0; 3]);
+
+        // QEMU's default CPU may lack both, then there's nothing to check.
+        for rng in [rdrand64, rdseed64] {
+            if let (Some(a), Some(b)) = (rng(), rng()) {
+                // Equal with a chance of 2^-64.
+                assert_ne!(a, b);
+            }
+        }
+        assert_eq!(rdrand64().is_some(), has_rdrand());
+    }
+
+    #[test_case]
+    fn test_io_wait() {
+        use crate::port::{Port, PortRead};
+
+        // The PIC masks.
+        let mut ports: [Port<u8>; 2] = [Port::new(0x21), Port::new(0xa1)];
+        let mut before = [0u8; 2];
+        for (value, port) in before.iter_mut().zip(ports.iter_mut()) {
+            *value = unsafe { port.read() };
+        }
+        for _ in 0..16 {
+            io_wait();
+        }
+        for (&value, port) in before.iter().zip(ports.iter_mut()) {
+            let after: u8 = unsafe { port.read() };
+            assert_eq!(after, value);
+        }
+    }
+
+    #[test_case]
+    fn test_clflush() {
+        let mut buf = [0u8; 256];
+        buf[100] = 42;
+        // Every cache line the buffer spans.
+        for offset in (0..buf.len()).step_by(64) {
+            unsafe { clflush(buf.as_ptr().add(offset)) };
+        }
+        // The data survives the flush.
+        assert_eq!(core::hint::black_box(&buf)[100], 42);
+    }
+
+    #[test_case]
+    fn test_read_rsp() {
+        let local = 0u64;
+        let rsp = read_rsp();
+        assert_ne!(rsp, 0);
+        assert_eq!(rsp % 8, 0);
+        // The local lives in our frame, right above rsp.
+        let addr = &local as *const u64 as u64;
+        assert!(rsp <= addr && addr - rsp < 4096);
+    }
+}
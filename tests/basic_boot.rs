@@ -5,6 +5,8 @@
 #![reexport_test_harness_main = "test_main"]
 
 use fyos::println;
+use fyos::{assert_serial, assert_serial_eq};
+use fyos::screen::{Color, SCREEN, VGA_BUFFER_ROWS};
 use fyos::x86_64;
 
 #[no_mangle]
@@ -22,3 +24,33 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 fn test_println() {
     println!("test_println ok");
 }
+
+#[test_case]
+fn test_assert_serial() {
+    assert_serial!(x86_64::VirtAddr::zero().0 == 0);
+    assert_serial_eq!(2 + 2, 4);
+}
+
+#[test_case]
+fn test_read_char_at() {
+    let s = "read_char_at";
+    println!();
+    println!("{}", s);
+    let mut screen = SCREEN.lock();
+    for (col, ch) in s.bytes().enumerate() {
+        assert_eq!(screen.read_char_at(VGA_BUFFER_ROWS - 2, col), (ch, Color::Yellow));
+    }
+
+    screen.set_char_at(VGA_BUFFER_ROWS - 2, 0, b'R', Color::LightGreen);
+    assert_eq!(screen.read_char_at(VGA_BUFFER_ROWS - 2, 0), (b'R', Color::LightGreen));
+}
+
+#[test_case]
+fn test_init() {
+    fyos::init();
+    assert!(x86_64::is_interrupt_enabled());
+    assert!(fyos::gdt::is_loaded());
+    assert!(fyos::interrupts::is_loaded());
+    // Returns only if the breakpoint is handled.
+    x86_64::int3();
+}
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(type_name_of_val)]
+
+use fyos::{
+    exit_qemu,
+    gdt::{Descriptor, GlobalDescriptorTable, TaskStateSegment, DOUBLE_FAULT_IST_INDEX},
+    interrupts::{self, InterruptDescriptorTable},
+    lazy_static, serial, serial_print, serial_println,
+    test_util::CaptureConsole,
+    x86_64::{load_tss, SegmentSelector, VirtAddr, CS},
+    QemuExitCode,
+};
+
+/// Much smaller than the kernel's, but enough for the first steps of the
+/// handler.
+const STACK_SIZE: usize = 4096;
+
+/// Where the handler dumps the overflowed stack.
+static STACK_DUMP: CaptureConsole<256> = CaptureConsole::new();
+
+lazy_static! {
+    static ref TSS: TaskStateSegment = {
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        // Only the double fault gets a stack. The kernel's page fault
+        // handler has none here, so the overflow escalates into a double
+        // fault.
+        let mut tss = TaskStateSegment::new();
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_start + STACK_SIZE;
+        tss
+    };
+
+    static ref GDT: (GlobalDescriptorTable, SegmentSelector, SegmentSelector) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code_selector = gdt.add_entry(Descriptor::kernel_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        (gdt, code_selector, tss_selector)
+    };
+
+    // The kernel's handlers, on the tiny stack.
+    static ref IDT: InterruptDescriptorTable = interrupts::build_idt();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    // The handler panics after the marker. Getting here at all means it
+    // wasn't a triple fault, and the marker must have gone out before.
+    let marked = serial::last_emergency_byte() == Some(interrupts::DOUBLE_FAULT_MARKER);
+    // The stack pointer is in the guard page, so there's only the header.
+    let dumped = STACK_DUMP.contents().as_str().starts_with("Interrupted stack at");
+    if interrupts::double_faulted() && marked && dumped {
+        serial_println!("[OK]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    serial_println!("[Failed]");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_double_fault_marker();
+    serial_println!("[Test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+fn test_double_fault_marker() {
+    GDT.0.load();
+    // Safety: The selectors are valid in the loaded GDT.
+    unsafe {
+        CS::set_reg(GDT.1);
+        load_tss(GDT.2);
+    }
+    IDT.load();
+    interrupts::set_double_fault_stack_dump(Some(&STACK_DUMP));
+
+    #[allow(unconditional_recursion)]
+    fn overflow() {
+        let i = 42;
+        overflow();
+        // Prevent tail recursion optimization
+        unsafe {
+            (&i as *const i32).read_volatile();
+        }
+    }
+    serial_print!("{}...\t", core::any::type_name_of_val(&test_double_fault_marker));
+    overflow();
+}
@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(type_name_of_val)]
+
+use fyos::interrupts::{InterruptDescriptorTable, InterruptStackFrame};
+use fyos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    serial_println!("[OK]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_stack_index_out_of_range();
+    serial_println!("[Test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+extern "x86-interrupt" fn handler(_stack_frame: InterruptStackFrame) {}
+
+fn test_stack_index_out_of_range() {
+    serial_print!(
+        "{}...\t",
+        core::any::type_name_of_val(&test_stack_index_out_of_range)
+    );
+    let mut idt = InterruptDescriptorTable::new();
+    // The TSS has only 7 IST slots.
+    unsafe {
+        idt.divide_error.set_handler(handler).set_stack_index(7);
+    }
+}
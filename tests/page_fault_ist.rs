@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(type_name_of_val)]
+#![feature(naked_functions)]
+#![feature(asm_sym)]
+
+use fyos::{
+    exit_qemu,
+    gdt::{init as init_gdt, DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX},
+    interrupts::{idt::InterruptDescriptorTable, ErrorCode, InterruptStackFrame, PageFaultErrorCode},
+    lazy_static, raw_handler_with_error_code, raw_page_fault_handler, serial_print,
+    serial_println,
+    x86_64::{read_rsp, VirtAddr},
+    QemuExitCode,
+};
+
+/// Unmapped and misaligned, as a stack corrupted beyond repair. It's
+/// canonical though, otherwise the push raises #SS instead. Nothing lives
+/// at its level 4 entry.
+const BAD_RSP: u64 = 0x0000_6666_0000_0003;
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.page_fault
+                .set_raw_handler(raw_page_fault_handler!(raw_page_fault_handler))
+                .set_stack_index(PAGE_FAULT_IST_INDEX);
+            idt.double_fault
+                .set_raw_handler(raw_handler_with_error_code!(raw_double_fault_handler -> !))
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+extern "C" fn raw_page_fault_handler(stack_frame: &InterruptStackFrame, _: PageFaultErrorCode) {
+    // The frame could only be pushed on a good stack, not on the bad one.
+    let rsp = read_rsp();
+    if stack_frame.stack_pointer == VirtAddr(BAD_RSP) && rsp.abs_diff(BAD_RSP) > 0x10000 {
+        serial_println!("[OK]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    serial_println!("[Failed]");
+    serial_println!("rsp {:#x} in the handler, frame {:#?}", rsp, stack_frame);
+    exit_qemu(QemuExitCode::Failed);
+}
+
+extern "C" fn raw_double_fault_handler(_: &InterruptStackFrame, _: ErrorCode) -> ! {
+    serial_println!("[Failed]");
+    serial_println!("The page fault escalated into a double fault");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    serial_println!("[Failed]");
+    serial_println!("{}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_page_fault_on_bad_stack();
+}
+
+fn test_page_fault_on_bad_stack() -> ! {
+    init_gdt();
+    TEST_IDT.load();
+    serial_print!("{}...\t", core::any::type_name_of_val(&test_page_fault_on_bad_stack));
+    // Safety: We never come back to the bad stack.
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {}",
+            "push rax",
+            in(reg) BAD_RSP,
+            options(noreturn)
+        );
+    }
+}